@@ -0,0 +1,36 @@
+//! Status indicators drawn on top of the current effect.
+
+use smart_leds::RGB8;
+
+use crate::{battery, host_leds, layout::Led};
+
+/// Key whose LED shows the Caps Lock state of the host.
+pub const CAPS_LOCK_KEY: (u8, u8) = (2, 0);
+
+const CAPS_LOCK_COLOR: RGB8 = RGB8::new(255, 255, 255);
+
+/// Index of the LED in the chain of each half which blinks when the battery of the half is low.
+pub const LOW_BATTERY_LED: usize = 0;
+
+const LOW_BATTERY_COLOR: RGB8 = RGB8::new(255, 0, 0);
+const LOW_BATTERY_BLINK_MS: u32 = 500;
+
+pub fn apply(leds: &[Led], frame: &mut [RGB8], now: u32) {
+    if host_leds::get().caps_lock() {
+        set_key(leds, frame, CAPS_LOCK_KEY, CAPS_LOCK_COLOR);
+    }
+    if battery::is_low() {
+        frame[LOW_BATTERY_LED] = if (now / LOW_BATTERY_BLINK_MS) % 2 == 0 {
+            LOW_BATTERY_COLOR
+        } else {
+            RGB8::default()
+        };
+    }
+}
+
+/// Sets the color of the LED under `key`. Does nothing if the key is on the other half.
+fn set_key(leds: &[Led], frame: &mut [RGB8], key: (u8, u8), color: RGB8) {
+    if let Some(i) = leds.iter().position(|l| *l == Led::Key(key.0, key.1)) {
+        frame[i] = color;
+    }
+}
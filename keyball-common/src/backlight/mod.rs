@@ -0,0 +1,78 @@
+//! Backlight of the Keyball.
+//!
+//! Keyball drives the LED chain itself instead of leaving it to rktk, so that effects can react to
+//! key presses of both halves. rktk is given no RGB driver, so its own backlight modes and RGB
+//! keycodes have no effect. The effect is selected with [`RGB_MOD`](crate::keycode::RGB_MOD)
+//! instead.
+
+use embassy_futures::select::{select, Either};
+use embassy_sync::{
+    blocking_mutex::raw::CriticalSectionRawMutex, channel::Channel, signal::Signal,
+};
+use embassy_time::{Duration, Instant, Ticker};
+use rktk::drivers::interface::rgb::RgbDriver;
+use smart_leds::RGB8;
+
+use crate::{
+    hooks::HAND,
+    layout::{self, MAX_LED_COUNT},
+};
+
+pub mod indicator;
+pub mod reactive;
+
+use reactive::{ReactiveEngine, ReactiveMode};
+
+pub const FRAME_INTERVAL: Duration = Duration::from_millis(20);
+
+static KEY_PRESSES: Channel<CriticalSectionRawMutex, (u8, u8), 16> = Channel::new();
+static MODE: Signal<CriticalSectionRawMutex, ReactiveMode> = Signal::new();
+static TURN_OFF: Signal<CriticalSectionRawMutex, ()> = Signal::new();
+static TURNED_OFF: Signal<CriticalSectionRawMutex, ()> = Signal::new();
+
+/// Notifies the backlight that a key was pressed. Presses beyond the queue size are dropped.
+pub fn key_pressed(row: u8, col: u8) {
+    let _ = KEY_PRESSES.try_send((row, col));
+}
+
+pub fn set_mode(mode: ReactiveMode) {
+    MODE.signal(mode);
+}
+
+/// Turns all LEDs off and stops the backlight for good. Returns once the chain has been written.
+///
+/// Does not return if [`run`] is not running.
+pub async fn turn_off() {
+    TURN_OFF.signal(());
+    TURNED_OFF.wait().await;
+}
+
+/// Drives the LED chain. Waits until rktk has detected the hand of this half before starting.
+pub async fn run<R: RgbDriver>(mut rgb: R) -> ! {
+    let leds = layout::leds(HAND.wait().await);
+    let mut engine = ReactiveEngine::new(ReactiveMode::Ripple);
+    let mut frame = [RGB8::default(); MAX_LED_COUNT];
+    let frame = &mut frame[..leds.len()];
+
+    let mut ticker = Ticker::every(FRAME_INTERVAL);
+    loop {
+        if let Either::Second(()) = select(ticker.next(), TURN_OFF.wait()).await {
+            frame.fill(RGB8::default());
+            let _ = rgb.write(frame.iter().copied()).await;
+            TURNED_OFF.signal(());
+            core::future::pending::<()>().await;
+        }
+        let now = Instant::now().as_millis() as u32;
+
+        if let Some(mode) = MODE.try_take() {
+            engine.set_mode(mode);
+        }
+        while let Ok((row, col)) = KEY_PRESSES.try_receive() {
+            engine.key_pressed(row, col, now);
+        }
+
+        engine.render(now, leds, frame);
+        indicator::apply(leds, frame, now);
+        let _ = rgb.write(frame.iter().copied()).await;
+    }
+}
@@ -0,0 +1,349 @@
+//! Reactive effects which respond to key presses.
+//!
+//! The engine is pure: it gets the current time in milliseconds from the caller and renders into a
+//! plain slice, so the same sequence of presses and timestamps always produces the same frames.
+//! Work per frame is bounded by `MAX_LED_COUNT * MAX_WAVES`.
+
+use smart_leds::{
+    hsv::{hsv2rgb, Hsv},
+    RGB8,
+};
+
+use crate::layout::{key_position, Led, Point, COLS, ROWS};
+
+/// Maximum number of ripples/splashes animated at the same time. The oldest one is replaced when
+/// this is exceeded.
+pub const MAX_WAVES: usize = 8;
+
+const RIPPLE_SPEED: u32 = 2; // layout units per ms
+const RIPPLE_WIDTH: u32 = 60;
+const RIPPLE_LIFETIME: u32 = 600;
+
+const SPLASH_SPEED: u32 = 2;
+const SPLASH_WIDTH: u32 = 180;
+const SPLASH_LIFETIME: u32 = 1000;
+
+const HEAT_STEP: u8 = 48;
+const HEAT_DECAY_MS: u32 = 40;
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum ReactiveMode {
+    /// Ring expanding from the pressed key.
+    Ripple,
+    /// Keys light up according to how often they are pressed and cool down over time.
+    Heatmap,
+    /// Wide, colorful wave which travels far enough to reach the other half.
+    Splash,
+}
+
+impl ReactiveMode {
+    pub fn to_u8(self) -> u8 {
+        match self {
+            ReactiveMode::Ripple => 0,
+            ReactiveMode::Heatmap => 1,
+            ReactiveMode::Splash => 2,
+        }
+    }
+
+    pub fn from_u8(value: u8) -> Option<Self> {
+        match value {
+            0 => Some(ReactiveMode::Ripple),
+            1 => Some(ReactiveMode::Heatmap),
+            2 => Some(ReactiveMode::Splash),
+            _ => None,
+        }
+    }
+
+    pub fn next(self) -> Self {
+        match self {
+            ReactiveMode::Ripple => ReactiveMode::Heatmap,
+            ReactiveMode::Heatmap => ReactiveMode::Splash,
+            ReactiveMode::Splash => ReactiveMode::Ripple,
+        }
+    }
+}
+
+#[derive(Clone, Copy)]
+struct Wave {
+    origin: Point,
+    started_at: u32,
+}
+
+pub struct ReactiveEngine {
+    mode: ReactiveMode,
+    waves: [Option<Wave>; MAX_WAVES],
+    next_wave: usize,
+    heat: [[u8; COLS]; ROWS],
+    last_decay: Option<u32>,
+}
+
+impl ReactiveEngine {
+    pub const fn new(mode: ReactiveMode) -> Self {
+        Self {
+            mode,
+            waves: [None; MAX_WAVES],
+            next_wave: 0,
+            heat: [[0; COLS]; ROWS],
+            last_decay: None,
+        }
+    }
+
+    pub fn mode(&self) -> ReactiveMode {
+        self.mode
+    }
+
+    pub fn set_mode(&mut self, mode: ReactiveMode) {
+        *self = Self::new(mode);
+    }
+
+    /// Registers a key press. `row` and `col` are positions in the whole keyboard, not in one half.
+    pub fn key_pressed(&mut self, row: u8, col: u8, now: u32) {
+        let Some(origin) = key_position(row as usize, col as usize) else {
+            return;
+        };
+
+        match self.mode {
+            ReactiveMode::Ripple | ReactiveMode::Splash => {
+                self.waves[self.next_wave] = Some(Wave {
+                    origin,
+                    started_at: now,
+                });
+                self.next_wave = (self.next_wave + 1) % MAX_WAVES;
+            }
+            ReactiveMode::Heatmap => {
+                let heat = &mut self.heat[row as usize][col as usize];
+                *heat = heat.saturating_add(HEAT_STEP);
+            }
+        }
+    }
+
+    /// Renders one frame. `leds` and `frame` must have the same length.
+    pub fn render(&mut self, now: u32, leds: &[Led], frame: &mut [RGB8]) {
+        match self.mode {
+            ReactiveMode::Ripple => {
+                self.expire_waves(now, RIPPLE_LIFETIME);
+                for (led, out) in leds.iter().zip(frame.iter_mut()) {
+                    let pos = led.position();
+                    let val = self
+                        .active_waves()
+                        .map(|w| ripple_intensity(w, pos, now))
+                        .max()
+                        .unwrap_or(0);
+                    *out = hsv2rgb(Hsv {
+                        hue: 128,
+                        sat: 255,
+                        val,
+                    });
+                }
+            }
+            ReactiveMode::Splash => {
+                self.expire_waves(now, SPLASH_LIFETIME);
+                for (led, out) in leds.iter().zip(frame.iter_mut()) {
+                    let pos = led.position();
+                    *out = self
+                        .active_waves()
+                        .filter_map(|w| splash_color(w, pos, now))
+                        .max_by_key(|c| c.val)
+                        .map(hsv2rgb)
+                        .unwrap_or_default();
+                }
+            }
+            ReactiveMode::Heatmap => {
+                self.decay_heat(now);
+                for (led, out) in leds.iter().zip(frame.iter_mut()) {
+                    let heat = match led {
+                        Led::Key(row, col) => self.heat[*row as usize][*col as usize],
+                        Led::Underglow(_) => 0,
+                    };
+                    *out = hsv2rgb(Hsv {
+                        hue: 170 - (heat as u16 * 170 / 255) as u8,
+                        sat: 255,
+                        val: heat,
+                    });
+                }
+            }
+        }
+    }
+
+    fn active_waves(&self) -> impl Iterator<Item = &Wave> {
+        self.waves.iter().flatten()
+    }
+
+    fn expire_waves(&mut self, now: u32, lifetime: u32) {
+        for wave in self.waves.iter_mut() {
+            if wave.is_some_and(|w| now.wrapping_sub(w.started_at) >= lifetime) {
+                *wave = None;
+            }
+        }
+    }
+
+    fn decay_heat(&mut self, now: u32) {
+        let last = *self.last_decay.get_or_insert(now);
+        let steps = now.wrapping_sub(last) / HEAT_DECAY_MS;
+        if steps == 0 {
+            return;
+        }
+        self.last_decay = Some(last.wrapping_add(steps * HEAT_DECAY_MS));
+
+        let steps = steps.min(u8::MAX as u32) as u8;
+        for heat in self.heat.iter_mut().flatten() {
+            *heat = heat.saturating_sub(steps);
+        }
+    }
+}
+
+/// Brightness which fades linearly from 255 to 0 over `lifetime`.
+fn fade(elapsed: u32, lifetime: u32) -> u32 {
+    255 * lifetime.saturating_sub(elapsed) / lifetime
+}
+
+fn ripple_intensity(wave: &Wave, pos: Point, now: u32) -> u8 {
+    let elapsed = now.wrapping_sub(wave.started_at);
+    let radius = elapsed * RIPPLE_SPEED;
+    let gap = (wave.origin.distance(&pos) as u32).abs_diff(radius);
+    if gap >= RIPPLE_WIDTH {
+        return 0;
+    }
+    let ring = 255 * (RIPPLE_WIDTH - gap) / RIPPLE_WIDTH;
+    (ring * fade(elapsed, RIPPLE_LIFETIME) / 255) as u8
+}
+
+fn splash_color(wave: &Wave, pos: Point, now: u32) -> Option<Hsv> {
+    let elapsed = now.wrapping_sub(wave.started_at);
+    let radius = elapsed * SPLASH_SPEED;
+    let distance = wave.origin.distance(&pos) as u32;
+    // Only the area just behind the wave front is lit.
+    if distance > radius || radius - distance >= SPLASH_WIDTH {
+        return None;
+    }
+    let front = 255 * (SPLASH_WIDTH - (radius - distance)) / SPLASH_WIDTH;
+    Some(Hsv {
+        hue: (distance / 4) as u8,
+        sat: 255,
+        val: (front * fade(elapsed, SPLASH_LIFETIME) / 255) as u8,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::layout::{LEFT_LEDS, LEFT_LED_COUNT, RIGHT_LEDS, RIGHT_LED_COUNT};
+
+    const BLACK: RGB8 = RGB8 { r: 0, g: 0, b: 0 };
+
+    fn led_index(leds: &[Led], row: u8, col: u8) -> usize {
+        leds.iter().position(|l| *l == Led::Key(row, col)).unwrap()
+    }
+
+    fn render_left(engine: &mut ReactiveEngine, now: u32) -> [RGB8; LEFT_LED_COUNT] {
+        let mut frame = [BLACK; LEFT_LED_COUNT];
+        engine.render(now, &LEFT_LEDS, &mut frame);
+        frame
+    }
+
+    #[test]
+    fn frames_are_reproducible() {
+        for mode in [
+            ReactiveMode::Ripple,
+            ReactiveMode::Heatmap,
+            ReactiveMode::Splash,
+        ] {
+            let mut a = ReactiveEngine::new(mode);
+            let mut b = ReactiveEngine::new(mode);
+            let presses = [(0, (2, 2)), (30, (3, 6)), (90, (0, 0)), (90, (4, 4))];
+            for now in (0..1200).step_by(20) {
+                for (_, (row, col)) in presses.iter().filter(|(at, _)| *at == now) {
+                    a.key_pressed(*row, *col, now);
+                    b.key_pressed(*row, *col, now);
+                }
+                assert_eq!(render_left(&mut a, now), render_left(&mut b, now));
+            }
+        }
+    }
+
+    #[test]
+    fn ripple_expands_from_the_pressed_key() {
+        let mut engine = ReactiveEngine::new(ReactiveMode::Ripple);
+        engine.key_pressed(2, 2, 1000);
+
+        let frame = render_left(&mut engine, 1000);
+        assert_ne!(frame[led_index(&LEFT_LEDS, 2, 2)], BLACK);
+        assert_eq!(frame[led_index(&LEFT_LEDS, 2, 0)], BLACK);
+
+        // (2, 0) is about 200 units away, which the ring reaches after 100 ms.
+        let frame = render_left(&mut engine, 1100);
+        assert_eq!(frame[led_index(&LEFT_LEDS, 2, 2)], BLACK);
+        assert_ne!(frame[led_index(&LEFT_LEDS, 2, 0)], BLACK);
+
+        let frame = render_left(&mut engine, 1000 + RIPPLE_LIFETIME);
+        assert!(frame.iter().all(|c| *c == BLACK));
+    }
+
+    #[test]
+    fn ripple_survives_timer_wrap_around() {
+        let mut engine = ReactiveEngine::new(ReactiveMode::Ripple);
+        engine.key_pressed(2, 2, u32::MAX - 10);
+        let frame = render_left(&mut engine, 40);
+        assert!(frame.iter().any(|c| *c != BLACK));
+    }
+
+    #[test]
+    fn splash_crosses_to_the_other_half() {
+        let mut engine = ReactiveEngine::new(ReactiveMode::Splash);
+        engine.key_pressed(3, 6, 0);
+
+        // (3, 7) is 350 units away from (3, 6).
+        let mut frame = [BLACK; RIGHT_LED_COUNT];
+        engine.render(100, &RIGHT_LEDS, &mut frame);
+        assert_eq!(frame[led_index(&RIGHT_LEDS, 3, 7)], BLACK);
+        engine.render(200, &RIGHT_LEDS, &mut frame);
+        assert_ne!(frame[led_index(&RIGHT_LEDS, 3, 7)], BLACK);
+    }
+
+    #[test]
+    fn heat_builds_up_and_decays() {
+        let mut engine = ReactiveEngine::new(ReactiveMode::Heatmap);
+        let key = led_index(&LEFT_LEDS, 1, 1);
+        engine.key_pressed(1, 1, 0);
+        let once = render_left(&mut engine, 0)[key];
+        engine.key_pressed(1, 1, 0);
+        let twice = render_left(&mut engine, 0)[key];
+        assert_ne!(once, BLACK);
+        assert_ne!(once, twice);
+
+        let cooled = 2 * HEAT_STEP as u32 * HEAT_DECAY_MS;
+        assert_ne!(render_left(&mut engine, cooled / 2)[key], BLACK);
+        assert_eq!(render_left(&mut engine, cooled)[key], BLACK);
+    }
+
+    #[test]
+    fn oldest_wave_is_replaced() {
+        let mut engine = ReactiveEngine::new(ReactiveMode::Ripple);
+        engine.key_pressed(0, 0, 0);
+        for col in 1..=MAX_WAVES as u8 {
+            engine.key_pressed(4, col.min(6), 0);
+        }
+        let origin = key_position(0, 0).unwrap();
+        assert_eq!(engine.active_waves().count(), MAX_WAVES);
+        assert!(engine.active_waves().all(|w| w.origin != origin));
+    }
+
+    #[test]
+    fn keys_without_position_are_ignored() {
+        let mut engine = ReactiveEngine::new(ReactiveMode::Ripple);
+        engine.key_pressed(0, 6, 0);
+        engine.key_pressed(ROWS as u8, 0, 0);
+        assert_eq!(engine.active_waves().count(), 0);
+    }
+
+    #[test]
+    fn mode_round_trips() {
+        let mut mode = ReactiveMode::Ripple;
+        for _ in 0..3 {
+            assert_eq!(ReactiveMode::from_u8(mode.to_u8()), Some(mode));
+            mode = mode.next();
+        }
+        assert_eq!(mode, ReactiveMode::Ripple);
+        assert_eq!(ReactiveMode::from_u8(3), None);
+    }
+}
@@ -0,0 +1,183 @@
+//! Battery level of wireless builds.
+//!
+//! The MCU specific part only has to provide a [`BatterySensor`]. Readings are smoothed, converted
+//! to percent with a LiPo discharge curve and then shown on the OLED and the backlight, and passed
+//! to a [`BatteryReporter`] such as the BLE Battery Service.
+
+use core::sync::atomic::{AtomicU8, Ordering};
+
+use embassy_time::{Duration, Ticker};
+
+use crate::display;
+
+pub const SAMPLE_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Below this level, the battery is shown as low.
+pub const LOW_BATTERY_PERCENT: u8 = 15;
+
+/// Open-circuit voltage of a typical single-cell LiPo in mV, and the remaining capacity at that
+/// voltage.
+const DISCHARGE_CURVE: [(u16, u8); 12] = [
+    (4200, 100),
+    (4100, 90),
+    (4000, 80),
+    (3920, 70),
+    (3870, 60),
+    (3830, 50),
+    (3790, 40),
+    (3750, 30),
+    (3710, 20),
+    (3670, 10),
+    (3600, 5),
+    (3300, 0),
+];
+
+/// Converts battery voltage to remaining capacity, interpolating linearly between the points of
+/// the discharge curve.
+pub fn voltage_to_percent(millivolts: u16) -> u8 {
+    let (max_mv, max_percent) = DISCHARGE_CURVE[0];
+    if millivolts >= max_mv {
+        return max_percent;
+    }
+    for pair in DISCHARGE_CURVE.windows(2) {
+        let (high_mv, high_percent) = pair[0];
+        let (low_mv, low_percent) = pair[1];
+        if millivolts >= low_mv {
+            let span = (high_percent - low_percent) as u32;
+            let offset = (millivolts - low_mv) as u32 * span / (high_mv - low_mv) as u32;
+            return low_percent + offset as u8;
+        }
+    }
+    0
+}
+
+/// Exponential moving average which suppresses noise of single readings.
+pub struct Smoother {
+    /// Average in 1/16 mV
+    value: Option<u32>,
+}
+
+impl Smoother {
+    const WEIGHT: u32 = 8;
+
+    pub const fn new() -> Self {
+        Self { value: None }
+    }
+
+    pub fn update(&mut self, millivolts: u16) -> u16 {
+        let sample = millivolts as u32 * 16;
+        let value = match self.value {
+            Some(v) => v - v / Self::WEIGHT + sample / Self::WEIGHT,
+            None => sample,
+        };
+        self.value = Some(value);
+        (value / 16) as u16
+    }
+}
+
+impl Default for Smoother {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+pub trait BatterySensor {
+    async fn read_millivolts(&mut self) -> u16;
+}
+
+pub trait BatteryReporter {
+    fn report(&mut self, percent: u8);
+}
+
+impl<F: FnMut(u8)> BatteryReporter for F {
+    fn report(&mut self, percent: u8) {
+        self(percent)
+    }
+}
+
+const UNKNOWN: u8 = u8::MAX;
+
+static LEVEL: AtomicU8 = AtomicU8::new(UNKNOWN);
+
+/// Last measured level in percent, if this half measures its battery.
+pub fn level() -> Option<u8> {
+    match LEVEL.load(Ordering::Relaxed) {
+        UNKNOWN => None,
+        level => Some(level),
+    }
+}
+
+pub fn is_low() -> bool {
+    level().is_some_and(|l| l < LOW_BATTERY_PERCENT)
+}
+
+pub async fn run(mut sensor: impl BatterySensor, mut reporter: impl BatteryReporter) -> ! {
+    let mut smoother = Smoother::new();
+    let mut ticker = Ticker::every(SAMPLE_INTERVAL);
+    loop {
+        let millivolts = smoother.update(sensor.read_millivolts().await);
+        let percent = voltage_to_percent(millivolts);
+
+        LEVEL.store(percent, Ordering::Relaxed);
+        display::update(|s| s.battery = Some(percent));
+        reporter.report(percent);
+
+        ticker.next().await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn curve_points_map_to_their_level() {
+        for (millivolts, percent) in DISCHARGE_CURVE {
+            assert_eq!(voltage_to_percent(millivolts), percent);
+        }
+    }
+
+    #[test]
+    fn clamps_outside_of_the_curve() {
+        assert_eq!(voltage_to_percent(4350), 100);
+        assert_eq!(voltage_to_percent(u16::MAX), 100);
+        assert_eq!(voltage_to_percent(3299), 0);
+        assert_eq!(voltage_to_percent(0), 0);
+    }
+
+    #[test]
+    fn interpolates_between_points() {
+        assert_eq!(voltage_to_percent(4150), 95);
+        assert_eq!(voltage_to_percent(3850), 55);
+        assert_eq!(voltage_to_percent(3450), 2);
+    }
+
+    #[test]
+    fn level_never_drops_with_rising_voltage() {
+        let mut last = 0;
+        for millivolts in 3000..4300 {
+            let percent = voltage_to_percent(millivolts);
+            assert!(percent >= last, "{millivolts} mV");
+            last = percent;
+        }
+    }
+
+    #[test]
+    fn smoother_starts_at_the_first_reading() {
+        let mut smoother = Smoother::new();
+        assert_eq!(smoother.update(3900), 3900);
+        assert_eq!(smoother.update(3900), 3900);
+    }
+
+    #[test]
+    fn smoother_damps_single_readings() {
+        let mut smoother = Smoother::new();
+        smoother.update(4000);
+        assert_eq!(smoother.update(3200), 3900);
+        let mut value = 0;
+        for _ in 0..100 {
+            value = smoother.update(3700);
+        }
+        assert!(value.abs_diff(3700) <= 1, "{value}");
+    }
+}
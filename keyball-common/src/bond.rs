@@ -0,0 +1,325 @@
+//! BLE bond slots.
+//!
+//! Each slot advertises with its own random static address, so that hosts see every slot as a
+//! separate keyboard and keep a bond for each of them. The BLE stack only knows the bonds of the
+//! active slot, so a [`BondStore`] keeps a copy for each slot and swaps them when another slot
+//! becomes active. Clearing a slot deletes its bonds and moves it to a new address, and hosts have
+//! to pair with it again.
+//!
+//! The address can only be changed before BLE starts. Commands from keys are therefore carried over
+//! a reset by [`request`], and applied and stored by [`load`] on the next boot.
+//! [`BondSlots`] itself is plain data and does not depend on the SoftDevice.
+
+use core::sync::atomic::{AtomicBool, Ordering};
+
+use rktk::drivers::interface::storage::StorageDriver;
+
+use crate::{display, retained::Mailbox, storage};
+
+pub const SLOT_COUNT: u8 = 4;
+
+const ENCODED_LEN: usize = 1 + SLOT_COUNT as usize;
+
+const COMMAND_SELECT: u32 = 0;
+const COMMAND_CLEAR_CURRENT: u32 = 1;
+const COMMAND_CLEAR_ALL: u32 = 2;
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum BondCommand {
+    Select(u8),
+    ClearCurrent,
+    ClearAll,
+}
+
+impl BondCommand {
+    fn to_u32(self) -> u32 {
+        match self {
+            BondCommand::Select(slot) => COMMAND_SELECT << 8 | slot as u32,
+            BondCommand::ClearCurrent => COMMAND_CLEAR_CURRENT << 8,
+            BondCommand::ClearAll => COMMAND_CLEAR_ALL << 8,
+        }
+    }
+
+    fn from_u32(value: u32) -> Option<Self> {
+        match value >> 8 {
+            COMMAND_SELECT => Some(BondCommand::Select(value as u8)),
+            COMMAND_CLEAR_CURRENT => Some(BondCommand::ClearCurrent),
+            COMMAND_CLEAR_ALL => Some(BondCommand::ClearAll),
+            _ => None,
+        }
+    }
+}
+
+/// What has to happen to the bonds after a command has been applied.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum BondChange {
+    Switch { from: u8, to: u8 },
+    Clear(u8),
+    ClearAll,
+}
+
+/// Keeps the bonds of each slot apart from those of the BLE stack.
+pub trait BondStore {
+    /// Copies the bonds of the BLE stack to the copy of `slot`.
+    async fn save(&mut self, slot: u8);
+    /// Replaces the bonds of the BLE stack with the copy of `slot`. Leaves the BLE stack without
+    /// bonds if there is no copy.
+    async fn restore(&mut self, slot: u8);
+    /// Deletes the bonds of the BLE stack and the copy of `slot`.
+    async fn clear(&mut self, slot: u8);
+    /// Deletes the bonds of the BLE stack and the copies of all slots.
+    async fn clear_all(&mut self) {
+        for slot in 0..SLOT_COUNT {
+            self.clear(slot).await;
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct BondSlots {
+    active: u8,
+    /// Incremented whenever the bond of the slot is cleared.
+    generations: [u8; SLOT_COUNT as usize],
+}
+
+impl Default for BondSlots {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl BondSlots {
+    pub const fn new() -> Self {
+        Self {
+            active: 0,
+            generations: [0; SLOT_COUNT as usize],
+        }
+    }
+
+    pub fn active(&self) -> u8 {
+        self.active
+    }
+
+    /// Applies `command` and returns what has to happen to the bonds, or `None` if the slots did
+    /// not change.
+    pub fn apply(&mut self, command: BondCommand) -> Option<BondChange> {
+        match command {
+            BondCommand::Select(slot) => {
+                if slot >= SLOT_COUNT || slot == self.active {
+                    return None;
+                }
+                let from = self.active;
+                self.active = slot;
+                Some(BondChange::Switch { from, to: slot })
+            }
+            BondCommand::ClearCurrent => {
+                let generation = &mut self.generations[self.active as usize];
+                *generation = generation.wrapping_add(1);
+                Some(BondChange::Clear(self.active))
+            }
+            BondCommand::ClearAll => {
+                for generation in &mut self.generations {
+                    *generation = generation.wrapping_add(1);
+                }
+                Some(BondChange::ClearAll)
+            }
+        }
+    }
+
+    /// Address the active slot advertises with, derived from the address of the device. `base` and
+    /// the result are little endian. Slot 0 keeps `base` until it is cleared, so that existing
+    /// bonds stay valid.
+    pub fn address(&self, base: [u8; 6]) -> [u8; 6] {
+        let mut address = base;
+        address[0] ^= self.active;
+        address[1] ^= self.generations[self.active as usize];
+        // Random static address.
+        address[5] |= 0xc0;
+        address
+    }
+
+    pub fn encode(&self) -> [u8; ENCODED_LEN] {
+        let mut buf = [0; ENCODED_LEN];
+        buf[0] = self.active;
+        buf[1..].copy_from_slice(&self.generations);
+        buf
+    }
+
+    pub fn decode(buf: &[u8; ENCODED_LEN]) -> Option<Self> {
+        if buf[0] >= SLOT_COUNT {
+            return None;
+        }
+        let mut generations = [0; SLOT_COUNT as usize];
+        generations.copy_from_slice(&buf[1..]);
+        Some(Self {
+            active: buf[0],
+            generations,
+        })
+    }
+}
+
+#[link_section = ".uninit.keyball.bond"]
+static PENDING: Mailbox = Mailbox::new();
+
+/// Set by [`load`], so that builds without BLE ignore the keys.
+static ENABLED: AtomicBool = AtomicBool::new(false);
+/// Set by [`take_clear_all`].
+static CLEAR_ALL_ENABLED: AtomicBool = AtomicBool::new(false);
+
+/// Loads the slots, applies a command requested before the reset to them and to `bonds`, and stores
+/// the result. Must be called before BLE starts advertising and reads its bonds.
+pub async fn load<S: StorageDriver>(storage: &S, bonds: &mut impl BondStore) -> BondSlots {
+    let mut buf = [0; ENCODED_LEN];
+    let mut slots = match storage
+        .read::<ENCODED_LEN>(storage::BOND_SLOTS, &mut buf)
+        .await
+    {
+        Ok(()) => BondSlots::decode(&buf).unwrap_or_default(),
+        Err(_) => BondSlots::new(),
+    };
+
+    if let Some(command) = PENDING.take().and_then(BondCommand::from_u32) {
+        if let Some(change) = slots.apply(command) {
+            match change {
+                BondChange::Switch { from, to } => {
+                    bonds.save(from).await;
+                    bonds.restore(to).await;
+                }
+                BondChange::Clear(slot) => bonds.clear(slot).await,
+                BondChange::ClearAll => bonds.clear_all().await,
+            }
+            let _ = storage
+                .write::<ENCODED_LEN>(storage::BOND_SLOTS, &slots.encode())
+                .await;
+        }
+    }
+
+    ENABLED.store(true, Ordering::Relaxed);
+    display::update(|s| s.bond_slot = Some(slots.active()));
+    slots
+}
+
+/// For builds with bonds but without slots, such as those of the BLE split link only, which can
+/// only forget all their bonds. Returns whether that was requested before the reset.
+pub fn take_clear_all() -> bool {
+    CLEAR_ALL_ENABLED.store(true, Ordering::Relaxed);
+    PENDING.take().and_then(BondCommand::from_u32) == Some(BondCommand::ClearAll)
+}
+
+/// Resets the device to apply `command`. Does nothing if the build has no bond slots, or only
+/// supports [`BondCommand::ClearAll`] and `command` is another one.
+pub fn request(command: BondCommand) {
+    let enabled = ENABLED.load(Ordering::Relaxed)
+        || command == BondCommand::ClearAll && CLEAR_ALL_ENABLED.load(Ordering::Relaxed);
+    if !enabled {
+        return;
+    }
+    PENDING.put(command.to_u32());
+    cortex_m::peripheral::SCB::sys_reset();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const BASE: [u8; 6] = [0x11, 0x22, 0x33, 0x44, 0x55, 0x66];
+
+    #[test]
+    fn select_switches_the_bonds() {
+        let mut slots = BondSlots::new();
+        assert_eq!(
+            slots.apply(BondCommand::Select(2)),
+            Some(BondChange::Switch { from: 0, to: 2 })
+        );
+        assert_eq!(slots.active(), 2);
+        assert_eq!(
+            slots.apply(BondCommand::Select(1)),
+            Some(BondChange::Switch { from: 2, to: 1 })
+        );
+    }
+
+    #[test]
+    fn select_ignores_the_active_and_missing_slots() {
+        let mut slots = BondSlots::new();
+        assert_eq!(slots.apply(BondCommand::Select(0)), None);
+        assert_eq!(slots.apply(BondCommand::Select(SLOT_COUNT)), None);
+        assert_eq!(slots, BondSlots::new());
+    }
+
+    #[test]
+    fn clear_current_moves_only_the_active_slot() {
+        let mut slots = BondSlots::new();
+        slots.apply(BondCommand::Select(1));
+        let other = BondSlots::new().address(BASE);
+        let before = slots.address(BASE);
+
+        assert_eq!(
+            slots.apply(BondCommand::ClearCurrent),
+            Some(BondChange::Clear(1))
+        );
+        assert_ne!(slots.address(BASE), before);
+        slots.apply(BondCommand::Select(0));
+        assert_eq!(slots.address(BASE), other);
+    }
+
+    #[test]
+    fn clear_all_moves_every_slot() {
+        let mut slots = BondSlots::new();
+        let before: [_; SLOT_COUNT as usize] = core::array::from_fn(|slot| {
+            slots.apply(BondCommand::Select(slot as u8));
+            slots.address(BASE)
+        });
+
+        assert_eq!(
+            slots.apply(BondCommand::ClearAll),
+            Some(BondChange::ClearAll)
+        );
+        for (slot, before) in before.iter().enumerate() {
+            slots.apply(BondCommand::Select(slot as u8));
+            assert_ne!(slots.address(BASE), *before);
+        }
+    }
+
+    #[test]
+    fn slots_have_distinct_random_static_addresses() {
+        let mut slots = BondSlots::new();
+        assert_eq!(slots.address(BASE)[..5], BASE[..5]);
+        let addresses: [_; SLOT_COUNT as usize] = core::array::from_fn(|slot| {
+            slots.apply(BondCommand::Select(slot as u8));
+            slots.address(BASE)
+        });
+        for (i, address) in addresses.iter().enumerate() {
+            assert_eq!(address[5] & 0xc0, 0xc0);
+            assert!(!addresses[i + 1..].contains(address));
+        }
+    }
+
+    #[test]
+    fn encode_round_trips() {
+        let mut slots = BondSlots::new();
+        slots.apply(BondCommand::Select(3));
+        slots.apply(BondCommand::ClearCurrent);
+        slots.apply(BondCommand::ClearAll);
+        assert_eq!(BondSlots::decode(&slots.encode()), Some(slots));
+    }
+
+    #[test]
+    fn decode_rejects_a_missing_active_slot() {
+        let mut buf = BondSlots::new().encode();
+        buf[0] = SLOT_COUNT;
+        assert_eq!(BondSlots::decode(&buf), None);
+    }
+
+    #[test]
+    fn commands_round_trip() {
+        for command in [
+            BondCommand::Select(0),
+            BondCommand::Select(3),
+            BondCommand::ClearCurrent,
+            BondCommand::ClearAll,
+        ] {
+            assert_eq!(BondCommand::from_u32(command.to_u32()), Some(command));
+        }
+        assert_eq!(BondCommand::from_u32(3 << 8), None);
+    }
+}
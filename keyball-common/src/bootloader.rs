@@ -0,0 +1,56 @@
+//! Reboot into the bootloader by key, on both halves at once.
+//!
+//! The key has to be pressed twice within [`CONFIRM_WINDOW`]. The half which handles the key tells
+//! the other half over the split link and waits a moment for the message to go out before entering
+//! its own bootloader. How to enter the bootloader is up to the MCU crate.
+
+use core::cell::Cell;
+
+use embassy_sync::{
+    blocking_mutex::{raw::CriticalSectionRawMutex, Mutex},
+    signal::Signal,
+};
+use embassy_time::{Duration, Instant, Timer};
+
+use crate::split::{self, KeyballMessage};
+
+pub const CONFIRM_WINDOW: Duration = Duration::from_secs(2);
+
+/// Time given to the split link to deliver the request to the other half.
+const HANDOFF_DELAY: Duration = Duration::from_millis(100);
+
+static ARMED_AT: Mutex<CriticalSectionRawMutex, Cell<Option<Instant>>> =
+    Mutex::new(Cell::new(None));
+
+/// Whether the other half has to be told.
+static REQUEST: Signal<CriticalSectionRawMutex, bool> = Signal::new();
+
+/// Handles a press of the bootloader key. The first press arms it, the second one within
+/// [`CONFIRM_WINDOW`] enters the bootloader.
+pub(crate) fn key_pressed() {
+    let now = Instant::now();
+    let confirmed = ARMED_AT.lock(|armed| {
+        let confirmed = armed
+            .get()
+            .is_some_and(|at| now.duration_since(at) <= CONFIRM_WINDOW);
+        armed.set(if confirmed { None } else { Some(now) });
+        confirmed
+    });
+    if confirmed {
+        REQUEST.signal(true);
+    }
+}
+
+/// Called when the other half asked to enter the bootloader.
+pub(crate) fn requested_by_other_half() {
+    REQUEST.signal(false);
+}
+
+/// Waits for a request and calls `enter`, which reboots into the bootloader of the MCU.
+pub async fn run(enter: fn() -> !) -> ! {
+    if REQUEST.wait().await {
+        split::send_to_other_half(KeyballMessage::EnterBootloader);
+        Timer::after(HANDOFF_DELAY).await;
+    }
+    enter()
+}
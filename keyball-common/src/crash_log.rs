@@ -0,0 +1,165 @@
+//! Crash records kept in flash, for crashes which happen away from a debugger.
+//!
+//! Flash cannot be written from the panic handler, so [`record_panic`] leaves the record in RAM
+//! which survives the reset, and [`load`] moves it into a ring buffer of [`LOG_SIZE`] slots on the
+//! next boot. The host reads and clears the log with the requests in [`host`](crate::host).
+//!
+//! A record is [`RECORD_SIZE`] bytes. Integers are little endian, texts are UTF-8 padded with
+//! zeros and cut off if they do not fit.
+//!
+//! | Bytes    | Content                                    |
+//! |----------|--------------------------------------------|
+//! | 0..4     | Sequence number, counting from 1           |
+//! | 4..12    | Uptime in milliseconds                     |
+//! | 12       | Reason of the reset before the crash       |
+//! | 13..25   | Firmware version                           |
+//! | 25..29   | Line of the panic                          |
+//! | 29..61   | File of the panic, the end of it if longer |
+//! | 61..128  | Panic message                              |
+
+use core::{cell::RefCell, fmt::Write as _, ops::Range, panic::PanicInfo};
+
+use embassy_sync::blocking_mutex::{raw::CriticalSectionRawMutex, Mutex};
+use embassy_time::Instant;
+use heapless::Vec;
+use rktk::drivers::interface::storage::StorageDriver;
+
+use crate::{
+    reset_reason::{self, ResetReason},
+    retained::{Block, Mailbox},
+    storage,
+};
+
+pub const LOG_SIZE: u8 = 8;
+pub const RECORD_SIZE: usize = 128;
+
+const SEQUENCE: Range<usize> = 0..4;
+const UPTIME: Range<usize> = 4..12;
+const RESET_REASON: usize = 12;
+const VERSION: Range<usize> = 13..25;
+const LINE: Range<usize> = 25..29;
+const FILE: Range<usize> = 29..61;
+const MESSAGE: Range<usize> = 61..RECORD_SIZE;
+
+type Record = [u8; RECORD_SIZE];
+
+#[link_section = ".uninit.keyball.crash_log"]
+static PENDING: Block<RECORD_SIZE> = Block::new();
+
+const CLEAR_REQUESTED: u32 = 0x0000_c1ea;
+
+#[link_section = ".uninit.keyball.crash_log_clear"]
+static CLEAR: Mailbox = Mailbox::new();
+
+/// Records in flash, oldest first.
+static LOG: Mutex<CriticalSectionRawMutex, RefCell<Vec<Record, { LOG_SIZE as usize }>>> =
+    Mutex::new(RefCell::new(Vec::new()));
+
+/// Keeps a record of the panic for [`load`]. Call it from the panic handler before resetting.
+pub fn record_panic(info: &PanicInfo) {
+    let mut record = [0; RECORD_SIZE];
+    record[UPTIME].copy_from_slice(&Instant::now().as_millis().to_le_bytes());
+    record[RESET_REASON] = reset_reason::current().to_u8();
+    let _ = Field::new(&mut record[VERSION]).write_str(env!("CARGO_PKG_VERSION"));
+    if let Some(location) = info.location() {
+        record[LINE].copy_from_slice(&location.line().to_le_bytes());
+        let _ = Field::new(&mut record[FILE]).write_str(tail(location.file(), FILE.len()));
+    }
+    let _ = write!(Field::new(&mut record[MESSAGE]), "{}", info.message());
+    PENDING.put(&record);
+    reset_reason::mark_panic();
+}
+
+/// Loads the log from flash and stores the record of the last panic, if any. Call it on boot,
+/// after [`reset_reason::set`].
+pub async fn load<S: StorageDriver>(storage: &S) {
+    let mut records = Vec::<Record, { LOG_SIZE as usize }>::new();
+    if CLEAR.take() == Some(CLEAR_REQUESTED) {
+        for slot in 0..LOG_SIZE {
+            let _ = storage
+                .write::<RECORD_SIZE>(storage::crash_log(slot), &[0; RECORD_SIZE])
+                .await;
+        }
+    } else {
+        for slot in 0..LOG_SIZE {
+            let mut record = [0; RECORD_SIZE];
+            let read = storage
+                .read::<RECORD_SIZE>(storage::crash_log(slot), &mut record)
+                .await;
+            if read.is_ok() && sequence(&record) != 0 {
+                let _ = records.push(record);
+            }
+        }
+    }
+
+    if let Some(mut record) = PENDING.take() {
+        reset_reason::set(ResetReason::Panic);
+        let next = records.iter().map(sequence).max().unwrap_or(0) + 1;
+        let slot = (next % LOG_SIZE as u32) as u8;
+        record[SEQUENCE].copy_from_slice(&next.to_le_bytes());
+        let _ = storage
+            .write::<RECORD_SIZE>(storage::crash_log(slot), &record)
+            .await;
+        records.retain(|r| sequence(r) % LOG_SIZE as u32 != slot as u32);
+        let _ = records.push(record);
+    }
+
+    records.sort_unstable_by_key(sequence);
+    LOG.lock(|log| *log.borrow_mut() = records);
+}
+
+/// Number of records in the log.
+pub fn len() -> usize {
+    LOG.lock(|log| log.borrow().len())
+}
+
+/// Record at `index`, oldest first.
+pub fn get(index: usize) -> Option<Record> {
+    LOG.lock(|log| log.borrow().get(index).copied())
+}
+
+/// Clears the log on the next boot. The caller has to reset.
+pub fn prepare_clear() {
+    CLEAR.put(CLEAR_REQUESTED);
+}
+
+fn sequence(record: &Record) -> u32 {
+    u32::from_le_bytes(record[SEQUENCE].try_into().unwrap())
+}
+
+/// End of `text` which fits into `len` bytes.
+fn tail(text: &str, len: usize) -> &str {
+    let mut start = text.len().saturating_sub(len);
+    while !text.is_char_boundary(start) {
+        start += 1;
+    }
+    &text[start..]
+}
+
+/// Writes text into a field of a record, dropping what does not fit.
+struct Field<'a> {
+    buf: &'a mut [u8],
+    len: usize,
+}
+
+impl<'a> Field<'a> {
+    fn new(buf: &'a mut [u8]) -> Self {
+        Self { buf, len: 0 }
+    }
+}
+
+impl core::fmt::Write for Field<'_> {
+    fn write_str(&mut self, s: &str) -> core::fmt::Result {
+        for c in s.chars() {
+            let end = self.len + c.len_utf8();
+            if end > self.buf.len() {
+                // Do not let later, shorter text continue the cut off one.
+                self.len = self.buf.len();
+                break;
+            }
+            c.encode_utf8(&mut self.buf[self.len..end]);
+            self.len = end;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,390 @@
+//! Per-key debouncing of the matrix, shared by both MCUs.
+//!
+//! [`DebouncedKeyscan`] wraps the matrix scanner and passes its events through a [`Debouncer`],
+//! which keeps the state of every key. Each edge of a key is debounced either eagerly, reporting
+//! the change at once and ignoring the contacts for a while, or deferred, reporting the change only
+//! once the contacts have settled. [`DEBOUNCE`](crate::DEBOUNCE) picks the strategy of every key
+//! by its position in the keymap. The scanner reports positions within the half, whose columns are
+//! mapped to those of the keymap like rktk does once it has detected the hand.
+//!
+//! Keys whose last change did not reach the master are reported again once the split link is
+//! back, see [`degraded`]. While the [`matrix_tester`] is active, the edges before and after
+//! debouncing are recorded by it.
+
+use embassy_time::{Duration, Instant};
+use rktk::drivers::interface::keyscan::{Hand, KeyChangeEvent, KeyscanDriver};
+
+use crate::{
+    hooks,
+    layout::{keymap_col, COLS, ROWS},
+    matrix_tester,
+    split::degraded,
+    watchdog::{self, Task},
+};
+
+/// How one edge of a key is debounced.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Edge {
+    /// Reports the change at once and ignores the contacts for the duration. Fast, but passes on
+    /// noise which looks like a change.
+    Eager(Duration),
+    /// Reports the change once the contacts did not change for the duration. Ignores noise, but
+    /// delays every change by the duration.
+    Deferred(Duration),
+}
+
+/// How the press and the release of a key are debounced.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct Strategy {
+    pub press: Edge,
+    pub release: Edge,
+}
+
+impl Strategy {
+    /// Reports every change at once.
+    pub const NONE: Strategy = Strategy::eager(Duration::from_ticks(0));
+
+    /// Debounces both edges eagerly.
+    pub const fn eager(duration: Duration) -> Self {
+        Self::symmetric(Edge::Eager(duration))
+    }
+
+    /// Debounces both edges deferred.
+    pub const fn deferred(duration: Duration) -> Self {
+        Self::symmetric(Edge::Deferred(duration))
+    }
+
+    /// Debounces both edges the same way.
+    pub const fn symmetric(edge: Edge) -> Self {
+        Self {
+            press: edge,
+            release: edge,
+        }
+    }
+
+    /// Debounces press and release differently, e.g. an eager press and a deferred release.
+    pub const fn asymmetric(press: Edge, release: Edge) -> Self {
+        Self { press, release }
+    }
+}
+
+/// Strategies of all keys.
+pub struct DebounceConfig {
+    /// Strategy of keys which are not listed in `overrides`.
+    pub default: Strategy,
+    /// Strategies of single keys, by `(row, col)` of the keymap. Columns of the right half start at
+    /// `COLS / 2`, next to the left half.
+    pub overrides: &'static [((u8, u8), Strategy)],
+}
+
+impl DebounceConfig {
+    pub fn strategy(&self, row: u8, col: u8) -> Strategy {
+        self.overrides
+            .iter()
+            .find(|(pos, _)| *pos == (row, col))
+            .map_or(self.default, |(_, strategy)| *strategy)
+    }
+
+    /// Longest time by which any key may report a change later than its contacts showed it.
+    pub fn settle_time(&self) -> Duration {
+        let delay = |edge: Edge| match edge {
+            Edge::Eager(_) => Duration::from_ticks(0),
+            Edge::Deferred(duration) => duration,
+        };
+        core::iter::once(&self.default)
+            .chain(self.overrides.iter().map(|(_, strategy)| strategy))
+            .map(|strategy| delay(strategy.press).max(delay(strategy.release)))
+            .max()
+            .unwrap_or_default()
+    }
+}
+
+#[derive(Clone, Copy)]
+struct KeyState {
+    /// State last reported to rktk.
+    reported: bool,
+    /// State last seen on the contacts.
+    raw: bool,
+    /// When `raw` last changed.
+    raw_since: Instant,
+    /// End of the window of an eager edge.
+    locked_until: Instant,
+}
+
+impl KeyState {
+    const RELEASED: KeyState = KeyState {
+        reported: false,
+        raw: false,
+        raw_since: Instant::from_ticks(0),
+        locked_until: Instant::from_ticks(0),
+    };
+}
+
+/// Debounce state of a matrix. Independent of the scanner, so that it can be fed by anything which
+/// samples the keys.
+pub struct Debouncer<const R: usize, const C: usize> {
+    config: &'static DebounceConfig,
+    keys: [[KeyState; C]; R],
+    /// Hand of the half whose keys are input.
+    hand: Hand,
+}
+
+impl<const R: usize, const C: usize> Debouncer<R, C> {
+    pub const fn new(config: &'static DebounceConfig) -> Self {
+        Self {
+            config,
+            keys: [[KeyState::RELEASED; C]; R],
+            hand: Hand::Left,
+        }
+    }
+
+    /// Sets the hand of the half whose keys are input, by which the strategies are looked up in the
+    /// keymap. Positions passed to the other methods stay those within the half.
+    pub fn set_hand(&mut self, hand: Hand) {
+        self.hand = hand;
+    }
+
+    /// Records a change seen on the contacts of a key. Positions outside of the matrix are ignored.
+    pub fn input(&mut self, row: u8, col: u8, pressed: bool, now: Instant) {
+        let Some(key) = self
+            .keys
+            .get_mut(row as usize)
+            .and_then(|r| r.get_mut(col as usize))
+        else {
+            return;
+        };
+        if key.raw != pressed {
+            key.raw = pressed;
+            key.raw_since = now;
+        }
+    }
+
+    /// Debounced state of a key. Positions outside of the matrix are released.
+    pub fn is_pressed(&self, row: u8, col: u8) -> bool {
+        self.keys
+            .get(row as usize)
+            .and_then(|r| r.get(col as usize))
+            .is_some_and(|key| key.reported)
+    }
+
+    /// Calls `report` for every key whose debounced state changed. Must be called regularly, as
+    /// deferred edges are only reported here.
+    pub fn poll(&mut self, now: Instant, mut report: impl FnMut(u8, u8, bool)) {
+        for (row, keys) in self.keys.iter_mut().enumerate() {
+            for (col, key) in keys.iter_mut().enumerate() {
+                if key.raw == key.reported || now < key.locked_until {
+                    continue;
+                }
+                let strategy = self
+                    .config
+                    .strategy(row as u8, keymap_col(self.hand, col as u8));
+                let edge = if key.raw {
+                    strategy.press
+                } else {
+                    strategy.release
+                };
+                let settled = match edge {
+                    Edge::Eager(duration) => {
+                        key.locked_until = now + duration;
+                        true
+                    }
+                    Edge::Deferred(duration) => now.duration_since(key.raw_since) >= duration,
+                };
+                if settled {
+                    key.reported = key.raw;
+                    report(row as u8, col as u8, key.raw);
+                }
+            }
+        }
+    }
+}
+
+/// Matrix scanner which debounces the events of `inner` with [`Debouncer`]. Use it with
+/// `none_driver!(Debounce)`.
+pub struct DebouncedKeyscan<K: KeyscanDriver> {
+    inner: K,
+    debouncer: Debouncer<ROWS, COLS>,
+}
+
+impl<K: KeyscanDriver> DebouncedKeyscan<K> {
+    pub const fn new(inner: K, config: &'static DebounceConfig) -> Self {
+        Self {
+            inner,
+            debouncer: Debouncer::new(config),
+        }
+    }
+}
+
+impl<K: KeyscanDriver> KeyscanDriver for DebouncedKeyscan<K> {
+    async fn scan(&mut self, mut callback: impl FnMut(KeyChangeEvent)) {
+        watchdog::check_in(Task::Keyscan);
+        let now = Instant::now();
+        let debouncer = &mut self.debouncer;
+        if let Some(hand) = hooks::hand() {
+            debouncer.set_hand(hand);
+        }
+        self.inner
+            .scan(|event| {
+                matrix_tester::raw_edge(event.row, event.col);
+                debouncer.input(event.row, event.col, event.pressed, now)
+            })
+            .await;
+        debouncer.poll(now, |row, col, pressed| {
+            degraded::key_reported(row, col, pressed);
+            matrix_tester::key_changed(row, col, pressed);
+            callback(KeyChangeEvent { row, col, pressed })
+        });
+
+        if let Some(pressed) = degraded::layer_key_change() {
+            let (row, col) = degraded::LAYER_KEY;
+            callback(KeyChangeEvent { row, col, pressed });
+        }
+
+        if degraded::take_resync() {
+            for (row, unconfirmed) in degraded::unconfirmed().into_iter().enumerate() {
+                for col in (0..COLS).filter(|col| unconfirmed & (1 << col) != 0) {
+                    let (row, col) = (row as u8, col as u8);
+                    let pressed = self.debouncer.is_pressed(row, col);
+                    degraded::key_reported(row, col, pressed);
+                    callback(KeyChangeEvent { row, col, pressed });
+                }
+            }
+        }
+    }
+
+    async fn current_hand(&mut self) -> Hand {
+        self.inner.current_hand().await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MS: u64 = 1000;
+
+    fn at(ms: u64) -> Instant {
+        Instant::from_micros(ms * MS)
+    }
+
+    fn polled<const R: usize, const C: usize>(
+        debouncer: &mut Debouncer<R, C>,
+        ms: u64,
+    ) -> heapless::Vec<(u8, u8, bool), 8> {
+        let mut events = heapless::Vec::new();
+        debouncer.poll(at(ms), |row, col, pressed| {
+            events.push((row, col, pressed)).unwrap();
+        });
+        events
+    }
+
+    static EAGER: DebounceConfig = DebounceConfig {
+        default: Strategy::eager(Duration::from_millis(5)),
+        overrides: &[],
+    };
+
+    #[test]
+    fn eager_reports_at_once_and_ignores_chatter() {
+        let mut debouncer = Debouncer::<1, 1>::new(&EAGER);
+        debouncer.input(0, 0, true, at(10));
+        assert_eq!(polled(&mut debouncer, 10), [(0, 0, true)]);
+        assert!(debouncer.is_pressed(0, 0));
+
+        // Bounces within the window are ignored.
+        debouncer.input(0, 0, false, at(11));
+        assert!(polled(&mut debouncer, 11).is_empty());
+        debouncer.input(0, 0, true, at(12));
+        assert!(polled(&mut debouncer, 14).is_empty());
+
+        debouncer.input(0, 0, false, at(20));
+        assert_eq!(polled(&mut debouncer, 20), [(0, 0, false)]);
+    }
+
+    #[test]
+    fn eager_reports_a_change_which_lasts_past_the_window() {
+        let mut debouncer = Debouncer::<1, 1>::new(&EAGER);
+        debouncer.input(0, 0, true, at(10));
+        polled(&mut debouncer, 10);
+        debouncer.input(0, 0, false, at(12));
+        assert!(polled(&mut debouncer, 12).is_empty());
+        assert_eq!(polled(&mut debouncer, 15), [(0, 0, false)]);
+    }
+
+    static DEFERRED: DebounceConfig = DebounceConfig {
+        default: Strategy::deferred(Duration::from_millis(5)),
+        overrides: &[],
+    };
+
+    #[test]
+    fn deferred_reports_once_settled() {
+        let mut debouncer = Debouncer::<1, 1>::new(&DEFERRED);
+        debouncer.input(0, 0, true, at(10));
+        assert!(polled(&mut debouncer, 14).is_empty());
+        assert!(!debouncer.is_pressed(0, 0));
+        assert_eq!(polled(&mut debouncer, 15), [(0, 0, true)]);
+    }
+
+    #[test]
+    fn deferred_ignores_noise() {
+        let mut debouncer = Debouncer::<1, 1>::new(&DEFERRED);
+        debouncer.input(0, 0, true, at(10));
+        debouncer.input(0, 0, false, at(12));
+        assert!(polled(&mut debouncer, 20).is_empty());
+
+        // Bounces restart the wait.
+        debouncer.input(0, 0, true, at(30));
+        debouncer.input(0, 0, false, at(33));
+        debouncer.input(0, 0, true, at(34));
+        assert!(polled(&mut debouncer, 37).is_empty());
+        assert_eq!(polled(&mut debouncer, 39), [(0, 0, true)]);
+    }
+
+    static ASYMMETRIC: DebounceConfig = DebounceConfig {
+        default: Strategy::asymmetric(
+            Edge::Eager(Duration::from_millis(5)),
+            Edge::Deferred(Duration::from_millis(10)),
+        ),
+        overrides: &[],
+    };
+
+    #[test]
+    fn asymmetric_debounces_each_edge_its_own_way() {
+        let mut debouncer = Debouncer::<1, 1>::new(&ASYMMETRIC);
+        debouncer.input(0, 0, true, at(10));
+        assert_eq!(polled(&mut debouncer, 10), [(0, 0, true)]);
+        debouncer.input(0, 0, false, at(20));
+        assert!(polled(&mut debouncer, 29).is_empty());
+        assert_eq!(polled(&mut debouncer, 30), [(0, 0, false)]);
+        assert_eq!(ASYMMETRIC.settle_time(), Duration::from_millis(10));
+    }
+
+    static OVERRIDES: DebounceConfig = DebounceConfig {
+        default: Strategy::NONE,
+        overrides: &[((0, 8), Strategy::deferred(Duration::from_millis(5)))],
+    };
+
+    #[test]
+    fn overrides_apply_to_keymap_positions() {
+        let mut left = Debouncer::<1, 7>::new(&OVERRIDES);
+        left.input(0, 1, true, at(10));
+        assert_eq!(polled(&mut left, 10), [(0, 1, true)]);
+
+        // Column 5 of the right half is column 8 of the keymap.
+        let mut right = Debouncer::<1, 7>::new(&OVERRIDES);
+        right.set_hand(Hand::Right);
+        right.input(0, 5, true, at(10));
+        right.input(0, 1, true, at(10));
+        assert_eq!(polled(&mut right, 10), [(0, 1, true)]);
+        assert_eq!(polled(&mut right, 15), [(0, 5, true)]);
+        assert_eq!(OVERRIDES.settle_time(), Duration::from_millis(5));
+    }
+
+    #[test]
+    fn positions_outside_of_the_matrix_are_ignored() {
+        let mut debouncer = Debouncer::<1, 1>::new(&EAGER);
+        debouncer.input(1, 0, true, at(10));
+        debouncer.input(0, 1, true, at(10));
+        assert!(polled(&mut debouncer, 10).is_empty());
+        assert!(!debouncer.is_pressed(1, 0));
+    }
+}
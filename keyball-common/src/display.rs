@@ -0,0 +1,407 @@
+//! OLED of the Keyball.
+//!
+//! Like the backlight, the display is driven by keyball-common instead of rktk so that Keyball
+//! specific state can be shown. Other modules change [`Status`] through [`update`] and the display
+//! is redrawn whenever it actually changed.
+//!
+//! Besides the status, the display has a diagnostics page for the split link, which is redrawn
+//! periodically while shown. If the other half runs an incompatible firmware, the status page says so
+//! in place of the host LEDs, as it does when the split link is lost.
+//!
+//! The matrix tester has a page of its own, which shows the keys of both halves live and details of
+//! the key of this half which changed last.
+//!
+//! With a timeout set, the display is blanked once this half was idle for that long, and shown again
+//! on the next activity.
+
+use core::{
+    cell::Cell,
+    fmt::Write as _,
+    sync::atomic::{AtomicU32, Ordering},
+};
+
+use embassy_futures::select::{select3, Either3};
+use embassy_sync::{
+    blocking_mutex::{raw::CriticalSectionRawMutex, Mutex},
+    signal::Signal,
+};
+use embassy_time::{Duration, Instant, Timer};
+use embedded_graphics::{
+    mono_font::{
+        ascii::{FONT_5X8, FONT_6X10},
+        MonoFont, MonoTextStyle,
+    },
+    pixelcolor::BinaryColor,
+    prelude::*,
+    primitives::{Line, PrimitiveStyle, Rectangle},
+    text::{Baseline, Text},
+};
+use rktk::drivers::interface::display::{DisplayDriver, DisplayDriverBuilder};
+use rktk_drivers_common::keyscan::duplex_matrix::ScanDir;
+
+use crate::{
+    battery::LOW_BATTERY_PERCENT,
+    host_leds::HostLeds,
+    key_pins,
+    layout::{self, COLS, ROWS},
+    matrix_tester::{self, Matrix, HALF_COLS},
+    pin_map::KEYBALL61,
+    power,
+    reset_reason::ResetReason,
+    split::{
+        self,
+        handshake::{self, Mismatch},
+        health,
+    },
+};
+
+const LINE_HEIGHT: i32 = 10;
+const SMALL_LINE_HEIGHT: i32 = 8;
+
+const DIAGNOSTICS_REFRESH: Duration = Duration::from_millis(500);
+const MATRIX_REFRESH: Duration = Duration::from_millis(50);
+/// Interval at which a blanked display checks for activity.
+const WAKE_POLL_INTERVAL: Duration = Duration::from_millis(50);
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Page {
+    Status,
+    /// Health of the split link.
+    Link,
+    /// Matrix tester. Not part of the pages cycled by [`next_page`].
+    Matrix,
+}
+
+impl Page {
+    pub fn to_u8(self) -> u8 {
+        match self {
+            Page::Status => 0,
+            Page::Link => 1,
+            Page::Matrix => 2,
+        }
+    }
+
+    pub fn from_u8(value: u8) -> Option<Self> {
+        match value {
+            0 => Some(Page::Status),
+            1 => Some(Page::Link),
+            2 => Some(Page::Matrix),
+            _ => None,
+        }
+    }
+
+    fn next(self) -> Self {
+        match self {
+            Page::Status => Page::Link,
+            Page::Link | Page::Matrix => Page::Status,
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct Status {
+    pub layer: u8,
+    pub host_leds: HostLeds,
+    /// Battery level in percent. `None` if this half has no battery measurement.
+    pub battery: Option<u8>,
+    /// Active BLE bond slot. `None` on builds without BLE.
+    pub bond_slot: Option<u8>,
+    pub reset_reason: Option<ResetReason>,
+    /// Why the other half cannot work with this one, as found by the [`handshake`].
+    pub split_mismatch: Option<Mismatch>,
+    /// Whether this half runs on its own, see [`degraded`](crate::split::degraded).
+    pub split_lost: bool,
+    pub page: Page,
+}
+
+impl Status {
+    const fn new() -> Self {
+        Self {
+            layer: 0,
+            host_leds: HostLeds(0),
+            battery: None,
+            bond_slot: None,
+            reset_reason: None,
+            split_mismatch: None,
+            split_lost: false,
+            page: Page::Status,
+        }
+    }
+}
+
+static STATUS: Mutex<CriticalSectionRawMutex, Cell<Status>> = Mutex::new(Cell::new(Status::new()));
+static REFRESH: Signal<CriticalSectionRawMutex, ()> = Signal::new();
+static TURN_OFF: Signal<CriticalSectionRawMutex, ()> = Signal::new();
+static TURNED_OFF: Signal<CriticalSectionRawMutex, ()> = Signal::new();
+static TIMEOUT_SECS: AtomicU32 = AtomicU32::new(0);
+
+pub fn update(f: impl FnOnce(&mut Status)) {
+    let changed = STATUS.lock(|status| {
+        let old = status.get();
+        let mut new = old;
+        f(&mut new);
+        status.set(new);
+        old != new
+    });
+    if changed {
+        REFRESH.signal(());
+    }
+}
+
+/// Sets how long this half must be idle before the display is blanked. Zero keeps it on.
+pub fn set_timeout(timeout: Duration) {
+    TIMEOUT_SECS.store(timeout.as_secs() as u32, Ordering::Relaxed);
+    REFRESH.signal(());
+}
+
+/// When the display is blanked, as of the last activity.
+fn blank_at() -> Option<Instant> {
+    match TIMEOUT_SECS.load(Ordering::Relaxed) {
+        0 => None,
+        secs => Some(power::last_activity() + Duration::from_secs(secs as u64)),
+    }
+}
+
+fn is_blanked() -> bool {
+    blank_at().is_some_and(|at| Instant::now() >= at)
+}
+
+/// Shows `page` on this half.
+pub(crate) fn set_page(page: Page) {
+    matrix_tester::set_active(page == Page::Matrix);
+    update(|s| s.page = page);
+}
+
+fn current_page() -> Page {
+    STATUS.lock(|s| s.get().page)
+}
+
+/// Shows the next page on both halves.
+pub fn next_page() {
+    show_on_both_halves(current_page().next());
+}
+
+/// Opens the matrix tester on both halves, or closes it if it is open.
+pub fn toggle_matrix_tester() {
+    let page = match current_page() {
+        Page::Matrix => Page::Status,
+        _ => Page::Matrix,
+    };
+    show_on_both_halves(page);
+}
+
+fn show_on_both_halves(page: Page) {
+    set_page(page);
+    split::send_to_other_half(split::KeyballMessage::DisplayPage(page));
+}
+
+/// Blanks the display and stops redrawing it for good. Returns once the display has been cleared.
+///
+/// Does not return if [`run`] is not running.
+pub async fn turn_off() {
+    TURN_OFF.signal(());
+    TURNED_OFF.wait().await;
+}
+
+pub async fn run<B: DisplayDriverBuilder>(builder: B) -> ! {
+    let Ok(mut display) = builder.build().await else {
+        loop {
+            TURN_OFF.wait().await;
+            TURNED_OFF.signal(());
+        }
+    };
+
+    loop {
+        let status = STATUS.lock(|s| s.get());
+        let blanked = is_blanked();
+        let _ = display.as_mut().clear(BinaryColor::Off);
+        if !blanked {
+            match status.page {
+                Page::Status => draw_status(display.as_mut(), &status),
+                Page::Link => draw_link(display.as_mut(), &health::stats()),
+                Page::Matrix => draw_matrix(display.as_mut(), &matrix_tester::matrix()),
+            }
+        }
+        let _ = display.flush().await;
+
+        let refresh = async {
+            if blanked {
+                while is_blanked() {
+                    Timer::after(WAKE_POLL_INTERVAL).await;
+                }
+                return;
+            }
+            let diagnostics = match status.page {
+                Page::Status => None,
+                Page::Link => Some(Instant::now() + DIAGNOSTICS_REFRESH),
+                Page::Matrix => Some(Instant::now() + MATRIX_REFRESH),
+            };
+            // Activity until the blank deadline moves it, which is noticed when redrawing.
+            match [diagnostics, blank_at()].into_iter().flatten().min() {
+                Some(at) => Timer::at(at).await,
+                None => core::future::pending().await,
+            }
+        };
+        if let Either3::Second(()) = select3(REFRESH.wait(), TURN_OFF.wait(), refresh).await {
+            let _ = display.as_mut().clear(BinaryColor::Off);
+            let _ = display.flush().await;
+            TURNED_OFF.signal(());
+            core::future::pending().await
+        }
+    }
+}
+
+fn draw_status<D: DrawTarget<Color = BinaryColor>>(target: &mut D, status: &Status) {
+    let mut lines: [heapless::String<21>; 3] = Default::default();
+
+    let _ = write!(lines[0], "Layer {}", status.layer);
+    if let Some(slot) = status.bond_slot {
+        let _ = write!(lines[0], "  BT{}", slot + 1);
+    }
+    if let Some(reason) = status.reset_reason {
+        let _ = write!(lines[0], "  {}", reason.name());
+    }
+    let leds = status.host_leds;
+    match status.split_mismatch {
+        _ if status.split_lost => {
+            let _ = write!(lines[1], "SPLIT LOST");
+        }
+        Some(Mismatch::Protocol { own, peer }) => {
+            let _ = write!(lines[1], "SPLIT VER {} != {}", own, peer);
+        }
+        Some(Mismatch::Features(_)) => {
+            let _ = write!(lines[1], "SPLIT LINK MISMATCH");
+        }
+        Some(Mismatch::NoHandshake) => {
+            let _ = write!(lines[1], "SPLIT NO HANDSHAKE");
+        }
+        None => {
+            let _ = write!(
+                lines[1],
+                "{} {} {}",
+                if leds.num_lock() { "NUM" } else { "---" },
+                if leds.caps_lock() { "CAPS" } else { "----" },
+                if leds.scroll_lock() { "SCRL" } else { "----" },
+            );
+        }
+    }
+    if let Some(battery) = status.battery {
+        let _ = write!(lines[2], "BAT {}%", battery);
+        if battery < LOW_BATTERY_PERCENT {
+            let _ = write!(lines[2], " LOW");
+        }
+    }
+
+    draw_lines(target, &lines, &FONT_6X10, LINE_HEIGHT);
+}
+
+fn draw_link<D: DrawTarget<Color = BinaryColor>>(target: &mut D, stats: &health::LinkStats) {
+    // Counters are capped so that the lines fit.
+    let short = |n: u32| n.min(9999);
+    let long = |n: u32| n.min(999_999);
+    let mut lines: [heapless::String<25>; 4] = Default::default();
+
+    let _ = write!(lines[0], "LINK {}", if stats.up { "UP" } else { "DOWN" });
+    if let Some(peer) = handshake::peer() {
+        let _ = write!(lines[0], "  PEER {}", peer.board.name());
+    }
+    let _ = write!(
+        lines[1],
+        "TX {}  RX {}",
+        long(stats.sent),
+        long(stats.received)
+    );
+    let _ = write!(
+        lines[2],
+        "FRAME {}  CRC {}",
+        short(stats.framing_errors),
+        short(stats.crc_failures)
+    );
+    let _ = write!(
+        lines[3],
+        "TIMEOUT {}  RECON {}",
+        short(stats.timeouts),
+        short(stats.reconnects)
+    );
+
+    draw_lines(target, &lines, &FONT_5X8, SMALL_LINE_HEIGHT);
+}
+
+/// Draws the keys of the whole keyboard as a grid, with the details of the key of this half which
+/// changed last next to it.
+fn draw_matrix<D: DrawTarget<Color = BinaryColor>>(target: &mut D, matrix: &Matrix) {
+    const PITCH: i32 = 6;
+    const CELL: u32 = 5;
+    // Gap between the halves.
+    const GAP: i32 = 2;
+    const TEXT_X: i32 = COLS as i32 * PITCH + GAP + 1;
+
+    let on = PrimitiveStyle::with_stroke(BinaryColor::On, 1);
+    let off = PrimitiveStyle::with_stroke(BinaryColor::Off, 1);
+    let fill = PrimitiveStyle::with_fill(BinaryColor::On);
+    for row in 0..ROWS as u8 {
+        for col in 0..COLS as u8 {
+            if layout::key_position(row as usize, col as usize).is_none() {
+                continue;
+            }
+            let gap = if col as usize >= HALF_COLS { GAP } else { 0 };
+            let origin = Point::new(col as i32 * PITCH + gap, row as i32 * PITCH);
+            let pressed = matrix.is_pressed(row, col);
+            let _ = Rectangle::new(origin, Size::new_equal(CELL))
+                .into_styled(if pressed { fill } else { on })
+                .draw(target);
+            // Chattering keys are crossed out.
+            if matrix.chatters(row, col) {
+                let style = if pressed { off } else { on };
+                let end = origin + Size::new_equal(CELL - 1);
+                let _ = Line::new(origin, end).into_styled(style).draw(target);
+                let _ = Line::new(Point::new(origin.x, end.y), Point::new(end.x, origin.y))
+                    .into_styled(style)
+                    .draw(target);
+            }
+        }
+    }
+
+    let mut lines: [heapless::String<8>; 4] = Default::default();
+    if let Some(key) = matrix.last() {
+        let _ = write!(lines[0], "R{} C{}", key.row, key.col);
+        if let Some((dir, row_pin, col_pin)) = key_pins(key.row as usize, key.local_col as usize) {
+            let row = KEYBALL61.rows[row_pin].name();
+            let col = KEYBALL61.cols[col_pin].name();
+            // In the direction of the diode.
+            let _ = match dir {
+                ScanDir::Col2Row => write!(lines[1], "{}>{}", col, row),
+                ScanDir::Row2Col => write!(lines[1], "{}>{}", row, col),
+            };
+        }
+        let _ = write!(lines[2], "N {}", key.stats.presses.min(9999));
+        let bounces = key.stats.bounces();
+        if bounces > 0 {
+            let _ = write!(lines[3], "BNC {}", bounces.min(999));
+        }
+    }
+    draw_lines(
+        &mut target.translated(Point::new(TEXT_X, 0)),
+        &lines,
+        &FONT_5X8,
+        SMALL_LINE_HEIGHT,
+    );
+}
+
+fn draw_lines<D: DrawTarget<Color = BinaryColor>, const N: usize>(
+    target: &mut D,
+    lines: &[heapless::String<N>],
+    font: &MonoFont,
+    line_height: i32,
+) {
+    let style = MonoTextStyle::new(font, BinaryColor::On);
+    for (i, line) in lines.iter().enumerate() {
+        let _ = Text::with_baseline(
+            line,
+            Point::new(0, i as i32 * line_height),
+            style,
+            Baseline::Top,
+        )
+        .draw(target);
+    }
+}
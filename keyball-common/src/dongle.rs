@@ -0,0 +1,409 @@
+//! Dongle setup, where a third device runs the keymap and both halves only report their input to
+//! it.
+//!
+//! On a half, the hooks pass every key and ball event to [`next_event`] instead of the local keymap
+//! once [`enable_forwarding`] was called. On the dongle, received events are fed into
+//! [`RemoteKeyscan`] and [`RemoteMouse`], which stand in for the matrix and the ball of a normal
+//! build.
+//!
+//! Key events pass through a [`KeyQueue`] on both ends, so that a key is never left pressed when
+//! events are dropped. Ball motion is summed up instead of queued.
+
+use core::{
+    cell::Cell,
+    sync::atomic::{AtomicBool, Ordering},
+};
+
+use embassy_futures::select::select;
+use embassy_sync::{
+    blocking_mutex::{
+        raw::{CriticalSectionRawMutex, RawMutex},
+        Mutex,
+    },
+    channel::Channel,
+    signal::Signal,
+};
+use rktk::drivers::interface::{
+    keyscan::{Hand, KeyChangeEvent, KeyscanDriver},
+    mouse::{MouseDriver, MouseDriverBuilder},
+};
+
+use crate::watchdog::{self, Task};
+
+const EVENT_KEY: u8 = 0;
+const EVENT_MOUSE: u8 = 1;
+
+pub const MAX_EVENT_SIZE: usize = 4;
+
+/// Input of a half. Key positions are those of the whole keymap.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum DongleEvent {
+    Key { row: u8, col: u8, pressed: bool },
+    Mouse { x: i8, y: i8 },
+}
+
+impl DongleEvent {
+    pub fn encode(&self) -> ([u8; MAX_EVENT_SIZE], usize) {
+        match *self {
+            DongleEvent::Key { row, col, pressed } => ([EVENT_KEY, row, col, pressed as u8], 4),
+            DongleEvent::Mouse { x, y } => ([EVENT_MOUSE, x as u8, y as u8, 0], 3),
+        }
+    }
+
+    pub fn decode(buf: &[u8]) -> Option<Self> {
+        match buf {
+            [EVENT_KEY, row, col, pressed, ..] => Some(DongleEvent::Key {
+                row: *row,
+                col: *col,
+                pressed: *pressed != 0,
+            }),
+            [EVENT_MOUSE, x, y, ..] => Some(DongleEvent::Mouse {
+                x: *x as i8,
+                y: *y as i8,
+            }),
+            _ => None,
+        }
+    }
+}
+
+/// Number of halves the dongle connects to.
+pub const HALF_COUNT: usize = 2;
+
+/// Key events on their way to a consumer which may fall behind. Events which do not fit into the
+/// queue are dropped, and the consumer is brought to the current state of the keys once it took
+/// the queued ones instead, so that no key stays pressed. Positions must be below 8 rows and 16
+/// columns.
+pub struct KeyQueue<M: RawMutex, const N: usize> {
+    events: Channel<M, (u8, u8, bool), N>,
+    /// Keys pressed now, and keys the consumer sees pressed once it took all queued events.
+    keys: Mutex<M, Cell<(u128, u128)>>,
+    dropped: Signal<M, ()>,
+}
+
+impl<M: RawMutex, const N: usize> Default for KeyQueue<M, N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<M: RawMutex, const N: usize> KeyQueue<M, N> {
+    pub const fn new() -> Self {
+        Self {
+            events: Channel::new(),
+            keys: Mutex::new(Cell::new((0, 0))),
+            dropped: Signal::new(),
+        }
+    }
+
+    pub fn push(&self, row: u8, col: u8, pressed: bool) {
+        let Some(bit) = key_bit(row, col) else {
+            return;
+        };
+        self.keys.lock(|keys| {
+            let (now, mut queued) = keys.get();
+            if self.events.try_send((row, col, pressed)).is_ok() {
+                queued = with_key(queued, bit, pressed);
+            } else {
+                self.dropped.signal(());
+            }
+            keys.set((with_key(now, bit, pressed), queued));
+        });
+    }
+
+    /// Takes the next event, or the next change which got lost once the queue is empty.
+    pub fn try_pop(&self) -> Option<(u8, u8, bool)> {
+        self.keys.lock(|keys| {
+            if let Ok(event) = self.events.try_receive() {
+                return Some(event);
+            }
+            let (now, queued) = keys.get();
+            let lost = now ^ queued;
+            if lost == 0 {
+                return None;
+            }
+            let bit = lost.trailing_zeros();
+            let pressed = now & 1 << bit != 0;
+            keys.set((now, with_key(queued, bit, pressed)));
+            Some(((bit / 16) as u8, (bit % 16) as u8, pressed))
+        })
+    }
+
+    pub async fn pop(&self) -> (u8, u8, bool) {
+        loop {
+            if let Some(event) = self.try_pop() {
+                return event;
+            }
+            self.ready().await;
+        }
+    }
+
+    /// Waits until [`try_pop`](Self::try_pop) may have something, without taking it.
+    pub async fn ready(&self) {
+        select(self.events.ready_to_receive(), self.dropped.wait()).await;
+    }
+
+    /// Keys which are pressed now.
+    fn pressed(&self) -> u128 {
+        self.keys.lock(|keys| keys.get().0)
+    }
+}
+
+fn key_bit(row: u8, col: u8) -> Option<u32> {
+    (row < 8 && col < 16).then_some(row as u32 * 16 + col as u32)
+}
+
+fn with_key(keys: u128, bit: u32, pressed: bool) -> u128 {
+    if pressed {
+        keys | 1 << bit
+    } else {
+        keys & !(1 << bit)
+    }
+}
+
+/// Ball motion summed up until it is taken.
+struct Motion(Mutex<CriticalSectionRawMutex, Cell<(i16, i16)>>);
+
+impl Motion {
+    const fn new() -> Self {
+        Self(Mutex::new(Cell::new((0, 0))))
+    }
+
+    fn add(&self, x: i8, y: i8) {
+        self.0.lock(|motion| {
+            let (mx, my) = motion.get();
+            motion.set((mx.saturating_add(x as i16), my.saturating_add(y as i16)));
+        });
+    }
+
+    /// Takes as much of the motion as fits into one report.
+    fn take(&self) -> (i8, i8) {
+        self.0.lock(|motion| {
+            let (x, y) = motion.get();
+            let taken = (x.clamp(-127, 127), y.clamp(-127, 127));
+            motion.set((x - taken.0, y - taken.1));
+            (taken.0 as i8, taken.1 as i8)
+        })
+    }
+}
+
+static FORWARDING: AtomicBool = AtomicBool::new(false);
+static OUTGOING_KEYS: KeyQueue<CriticalSectionRawMutex, 16> = KeyQueue::new();
+static OUTGOING_MOTION: Motion = Motion::new();
+static MOTION_ADDED: Signal<CriticalSectionRawMutex, ()> = Signal::new();
+
+/// Makes this half send its input to the dongle instead of handling it itself.
+pub fn enable_forwarding() {
+    FORWARDING.store(true, Ordering::Relaxed);
+}
+
+/// Queues `event` for the dongle if forwarding is enabled. Returns whether it was taken.
+pub(crate) fn forward(event: DongleEvent) -> bool {
+    if !FORWARDING.load(Ordering::Relaxed) {
+        return false;
+    }
+    match event {
+        DongleEvent::Key { row, col, pressed } => OUTGOING_KEYS.push(row, col, pressed),
+        DongleEvent::Mouse { x, y } => {
+            OUTGOING_MOTION.add(x, y);
+            MOTION_ADDED.signal(());
+        }
+    }
+    true
+}
+
+/// Waits for the next event to send to the dongle. Keys go first.
+pub async fn next_event() -> DongleEvent {
+    loop {
+        if let Some((row, col, pressed)) = OUTGOING_KEYS.try_pop() {
+            return DongleEvent::Key { row, col, pressed };
+        }
+        let (x, y) = OUTGOING_MOTION.take();
+        if (x, y) != (0, 0) {
+            return DongleEvent::Mouse { x, y };
+        }
+        select(OUTGOING_KEYS.ready(), MOTION_ADDED.wait()).await;
+    }
+}
+
+static KEYS: KeyQueue<CriticalSectionRawMutex, 32> = KeyQueue::new();
+/// Keys pressed on each half, to release them when the half is lost.
+static HALF_KEYS: Mutex<CriticalSectionRawMutex, Cell<[u128; HALF_COUNT]>> =
+    Mutex::new(Cell::new([0; HALF_COUNT]));
+static MOTION: Motion = Motion::new();
+
+/// Passes an event received from `half` to [`RemoteKeyscan`] and [`RemoteMouse`].
+pub fn received(half: usize, event: DongleEvent) {
+    match event {
+        DongleEvent::Key { row, col, pressed } => {
+            let Some(bit) = key_bit(row, col) else {
+                return;
+            };
+            HALF_KEYS.lock(|keys| {
+                let mut half_keys = keys.get();
+                half_keys[half] = with_key(half_keys[half], bit, pressed);
+                keys.set(half_keys);
+            });
+            KEYS.push(row, col, pressed);
+        }
+        DongleEvent::Mouse { x, y } => MOTION.add(x, y),
+    }
+}
+
+/// Releases the keys of `half`, whose connection dropped.
+pub fn half_lost(half: usize) {
+    let lost = HALF_KEYS.lock(|keys| {
+        let mut half_keys = keys.get();
+        let lost = half_keys[half];
+        half_keys[half] = 0;
+        keys.set(half_keys);
+        lost
+    });
+    release(&KEYS, lost);
+}
+
+/// Releases `keys` of `queue`.
+fn release<M: RawMutex, const N: usize>(queue: &KeyQueue<M, N>, mut keys: u128) {
+    keys &= queue.pressed();
+    while keys != 0 {
+        let bit = keys.trailing_zeros();
+        queue.push((bit / 16) as u8, (bit % 16) as u8, false);
+        keys &= keys - 1;
+    }
+}
+
+/// Key scanner of the dongle, which reports the keys received from both halves.
+pub struct RemoteKeyscan;
+
+impl KeyscanDriver for RemoteKeyscan {
+    async fn scan(&mut self, mut callback: impl FnMut(KeyChangeEvent)) {
+        watchdog::check_in(Task::Keyscan);
+        while let Some((row, col, pressed)) = KEYS.try_pop() {
+            callback(KeyChangeEvent { row, col, pressed });
+        }
+    }
+
+    /// The dongle has no hand. Positions are already those of the whole keymap.
+    async fn current_hand(&mut self) -> Hand {
+        Hand::Left
+    }
+}
+
+/// Ball of the dongle, which reports the motion received from both halves.
+pub struct RemoteMouse;
+
+impl MouseDriverBuilder for RemoteMouse {
+    type Output = RemoteMouse;
+    type Error = core::convert::Infallible;
+
+    async fn build(self) -> Result<Self::Output, Self::Error> {
+        Ok(self)
+    }
+}
+
+impl MouseDriver for RemoteMouse {
+    type Error = core::convert::Infallible;
+
+    async fn read(&mut self) -> Result<(i8, i8), Self::Error> {
+        Ok(MOTION.take())
+    }
+
+    /// The resolution is set on the halves.
+    async fn set_cpi(&mut self, _cpi: u16) -> Result<(), Self::Error> {
+        Ok(())
+    }
+
+    async fn get_cpi(&mut self) -> Result<u16, Self::Error> {
+        Ok(0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use embassy_futures::block_on;
+    use embassy_sync::blocking_mutex::raw::NoopRawMutex;
+
+    use super::*;
+
+    fn drain<const N: usize>(
+        queue: &KeyQueue<NoopRawMutex, N>,
+    ) -> heapless::Vec<(u8, u8, bool), 16> {
+        let mut events = heapless::Vec::new();
+        while let Some(event) = queue.try_pop() {
+            events.push(event).unwrap();
+        }
+        events
+    }
+
+    #[test]
+    fn events_keep_their_order() {
+        let queue = KeyQueue::<NoopRawMutex, 4>::new();
+        queue.push(1, 2, true);
+        queue.push(3, 4, true);
+        queue.push(1, 2, false);
+        assert_eq!(drain(&queue), [(1, 2, true), (3, 4, true), (1, 2, false)]);
+    }
+
+    #[test]
+    fn dropped_release_follows_the_queued_events() {
+        let queue = KeyQueue::<NoopRawMutex, 2>::new();
+        queue.push(0, 1, true);
+        queue.push(0, 2, true);
+        queue.push(0, 1, false);
+        assert_eq!(drain(&queue), [(0, 1, true), (0, 2, true), (0, 1, false)]);
+    }
+
+    #[test]
+    fn dropped_tap_is_not_replayed() {
+        let queue = KeyQueue::<NoopRawMutex, 1>::new();
+        queue.push(0, 1, true);
+        queue.push(0, 2, true);
+        queue.push(0, 2, false);
+        assert_eq!(drain(&queue), [(0, 1, true)]);
+    }
+
+    #[test]
+    fn pop_returns_dropped_changes() {
+        let queue = KeyQueue::<NoopRawMutex, 1>::new();
+        queue.push(4, 15, true);
+        queue.push(7, 0, true);
+        assert_eq!(block_on(queue.pop()), (4, 15, true));
+        assert_eq!(block_on(queue.pop()), (7, 0, true));
+        assert_eq!(queue.try_pop(), None);
+    }
+
+    #[test]
+    fn positions_out_of_range_are_ignored() {
+        let queue = KeyQueue::<NoopRawMutex, 4>::new();
+        queue.push(8, 0, true);
+        queue.push(0, 16, true);
+        assert_eq!(queue.try_pop(), None);
+    }
+
+    #[test]
+    fn release_releases_pressed_keys_only() {
+        let queue = KeyQueue::<NoopRawMutex, 8>::new();
+        queue.push(0, 1, true);
+        queue.push(2, 3, true);
+        drain(&queue);
+        let lost = 1 << key_bit(0, 1).unwrap() | 1 << key_bit(4, 4).unwrap();
+        release(&queue, lost);
+        assert_eq!(drain(&queue), [(0, 1, false)]);
+        assert_eq!(queue.pressed(), 1 << key_bit(2, 3).unwrap());
+    }
+
+    #[test]
+    fn events_round_trip() {
+        for event in [
+            DongleEvent::Key {
+                row: 4,
+                col: 13,
+                pressed: true,
+            },
+            DongleEvent::Mouse { x: -127, y: 5 },
+        ] {
+            let (buf, len) = event.encode();
+            assert_eq!(DongleEvent::decode(&buf[..len]), Some(event));
+        }
+        assert_eq!(DongleEvent::decode(&[2, 0, 0, 0]), None);
+    }
+}
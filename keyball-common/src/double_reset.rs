@@ -0,0 +1,78 @@
+//! Double reset detection, for controllers whose reset button is the only one reachable.
+//!
+//! [`detect`] leaves a marker in RAM which survives a reset, and [`disarm_after`] removes it once
+//! the window has passed. A reset within the window finds the marker on the next boot. Only resets
+//! by the RUN pin count, so that a watchdog reset or a crash soon after a reset does not enter the
+//! bootloader. The reason has to come from
+//! [`reset_reason::set_from_hardware`](crate::reset_reason::set_from_hardware), as the RP2040
+//! reports a reset after a panic like the reset before it.
+
+use embassy_time::{Duration, Timer};
+
+use crate::{reset_reason::ResetReason, retained::Mailbox};
+
+pub const DEFAULT_WINDOW: Duration = Duration::from_millis(500);
+
+const ARMED: u32 = 0x0000_2b2b;
+
+#[link_section = ".uninit.keyball.double_reset"]
+static MARKER: Mailbox = Mailbox::new();
+
+/// Returns whether this boot was caused by the second of two resets by the RUN pin. `reason` is
+/// the reason of this boot. Call it as early as possible after boot, and run [`disarm_after`] if it
+/// returned false.
+pub fn detect(reason: ResetReason) -> bool {
+    let armed = MARKER.take() == Some(ARMED);
+    if reason != ResetReason::Pin {
+        return false;
+    }
+    if armed {
+        return true;
+    }
+    MARKER.put(ARMED);
+    false
+}
+
+pub async fn disarm_after(window: Duration) {
+    Timer::after(window).await;
+    MARKER.take();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::reset_reason;
+
+    // The marker is shared, so the cases run in one test.
+    #[test]
+    fn only_two_pin_resets_are_detected() {
+        MARKER.take();
+        assert!(!detect(ResetReason::Pin));
+        assert!(detect(ResetReason::Pin));
+
+        // Not armed again after a double reset.
+        assert!(!detect(ResetReason::Pin));
+        MARKER.take();
+
+        assert!(!detect(ResetReason::Pin));
+        assert!(!detect(ResetReason::Watchdog));
+        assert!(!detect(ResetReason::Pin));
+
+        MARKER.take();
+        assert!(!detect(ResetReason::PowerOn));
+        assert!(!detect(ResetReason::Pin));
+        assert!(!detect(ResetReason::Software));
+        assert!(!detect(ResetReason::Pin));
+
+        // A panic within the window after a pin reset, reported as a pin reset by the hardware.
+        reset_reason::mark_panic();
+        let reason = reset_reason::set_from_hardware(ResetReason::Pin);
+        assert_eq!(reason, ResetReason::Panic);
+        assert!(!detect(reason));
+        assert_eq!(
+            reset_reason::set_from_hardware(ResetReason::Pin),
+            ResetReason::Pin
+        );
+        assert!(!detect(ResetReason::Pin));
+    }
+}
@@ -0,0 +1,106 @@
+//! Handedness of builds which cannot detect it from the matrix, read from a strap pin by
+//! [`read_strap`] or stored in flash.
+//!
+//! To store it, hold an outermost key while connecting the half: (0, 0) of the top row for the left
+//! hand, or (1, 0) of the second row for the right hand. Column 0 is the outermost one on both
+//! halves, so the key picks the hand, not the half it is pressed on. The half is reset afterwards
+//! so that the new value takes effect.
+
+use embassy_time::{Duration, Instant, Timer};
+use rktk::drivers::interface::{
+    keyscan::{Hand, KeyscanDriver},
+    storage::StorageDriver,
+};
+
+use crate::{storage, DEBOUNCE};
+
+const LEFT_KEY: (u8, u8) = (0, 0);
+const RIGHT_KEY: (u8, u8) = (1, 0);
+
+/// How long the keys are watched for the combination, on top of the debounce settle time.
+const BOOT_COMBO_WINDOW: Duration = Duration::from_millis(50);
+const SCAN_INTERVAL: Duration = Duration::from_millis(1);
+
+/// Reads of the strap pin, of which the majority decides.
+const STRAP_SAMPLES: u8 = 5;
+/// Time for the pull-up to charge the strap line, before the first read and between the reads.
+const STRAP_SETTLE_TIME: Duration = Duration::from_micros(50);
+
+/// Reads the hand from a strap pin, which is pulled up and tied to GND on the right half. `is_low`
+/// reads the pin, whose pull-up may just have been enabled.
+pub fn read_strap(mut is_low: impl FnMut() -> bool) -> Hand {
+    let mut low = 0;
+    for _ in 0..STRAP_SAMPLES {
+        embassy_time::block_for(STRAP_SETTLE_TIME);
+        low += is_low() as u8;
+    }
+    if low > STRAP_SAMPLES / 2 {
+        Hand::Right
+    } else {
+        Hand::Left
+    }
+}
+
+pub async fn load<S: StorageDriver>(storage: &S) -> Option<Hand> {
+    let mut buf = [0; 1];
+    storage.read::<1>(storage::HAND, &mut buf).await.ok()?;
+    match buf[0] {
+        0 => Some(Hand::Left),
+        1 => Some(Hand::Right),
+        _ => None,
+    }
+}
+
+pub async fn save<S: StorageDriver>(storage: &S, hand: Hand) -> Result<(), S::Error> {
+    let value = match hand {
+        Hand::Left => 0,
+        Hand::Right => 1,
+    };
+    storage.write::<1>(storage::HAND, &[value]).await
+}
+
+/// Stores the handedness if its key combination is held, and resets if the value changed. Must be
+/// called before rktk starts, with the debounced scanner of this half.
+pub async fn handle_boot_combo<K: KeyscanDriver, S: StorageDriver>(
+    key_scanner: &mut K,
+    storage: &S,
+) {
+    // Deferred debouncing reports a press only after several scans.
+    let deadline = Instant::now() + DEBOUNCE.settle_time() + BOOT_COMBO_WINDOW;
+    let mut held = None;
+    while held.is_none() && Instant::now() < deadline {
+        key_scanner
+            .scan(|event| match (event.row, event.col) {
+                LEFT_KEY if event.pressed => held = Some(Hand::Left),
+                RIGHT_KEY if event.pressed => held = Some(Hand::Right),
+                _ => {}
+            })
+            .await;
+        Timer::after(SCAN_INTERVAL).await;
+    }
+
+    let Some(hand) = held else {
+        return;
+    };
+    if load(storage).await != Some(hand) && save(storage, hand).await.is_ok() {
+        cortex_m::peripheral::SCB::sys_reset();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn the_strap_is_read_by_majority() {
+        let reads = |levels: [bool; STRAP_SAMPLES as usize]| {
+            let mut levels = levels.into_iter();
+            read_strap(|| levels.next().unwrap())
+        };
+        assert_eq!(reads([true; 5]), Hand::Right);
+        assert_eq!(reads([false, true, true, false, true]), Hand::Right);
+        assert_eq!(reads([false; 5]), Hand::Left);
+        // Still charging on the first reads.
+        assert_eq!(reads([true, true, false, false, false]), Hand::Left);
+    }
+}
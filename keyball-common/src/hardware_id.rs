@@ -0,0 +1,26 @@
+//! Serial number derived from the unique ID of the MCU, so that multiple Keyballs connected to the
+//! same host can be told apart.
+
+use core::fmt::Write as _;
+
+use once_cell::sync::OnceCell;
+
+/// Source of an ID which is unique to each chip.
+pub trait HardwareId {
+    fn hardware_id(&mut self) -> [u8; 8];
+}
+
+static SERIAL_NUMBER: OnceCell<heapless::String<16>> = OnceCell::new();
+
+/// Returns the hardware ID as a hex string. The ID is read on the first call only.
+pub fn serial_number(id: &mut impl HardwareId) -> &'static str {
+    SERIAL_NUMBER
+        .get_or_init(|| {
+            let mut serial = heapless::String::new();
+            for byte in id.hardware_id() {
+                let _ = write!(serial, "{:02X}", byte);
+            }
+            serial
+        })
+        .as_str()
+}
@@ -0,0 +1,156 @@
+//! rktk hooks used by all Keyball builds.
+
+use core::sync::atomic::{AtomicU8, Ordering};
+
+use embassy_sync::{blocking_mutex::raw::CriticalSectionRawMutex, signal::Signal};
+use rktk::{
+    drivers::interface::{
+        keyscan::{Hand, KeyChangeEvent, KeyscanDriver},
+        mouse::MouseDriver,
+        reporter::ReporterDriver,
+        storage::StorageDriver,
+    },
+    hooks::{
+        empty_hooks::{EmptyBacklightHooks, EmptySlaveHooks},
+        interface::{CommonHooks, MasterHooks},
+        Hooks,
+    },
+    keymanager::state::StateReport,
+};
+
+use crate::{
+    backlight, display,
+    dongle::{self, DongleEvent},
+    keycode::{self, KeyballKey},
+    matrix_tester,
+    output::{self, Output},
+    power,
+    split::{self, degraded, KeyballMessage},
+    ONE_HANDED,
+};
+
+/// Hand of this half. Signaled once rktk has detected it.
+pub static HAND: Signal<CriticalSectionRawMutex, Hand> = Signal::new();
+
+const HAND_UNKNOWN: u8 = 0xff;
+static DETECTED_HAND: AtomicU8 = AtomicU8::new(HAND_UNKNOWN);
+
+/// Hand of this half, once rktk has detected it. Unlike [`HAND`], this can be read any number of
+/// times.
+pub fn hand() -> Option<Hand> {
+    match DETECTED_HAND.load(Ordering::Relaxed) {
+        0 => Some(Hand::Left),
+        1 => Some(Hand::Right),
+        _ => None,
+    }
+}
+
+pub struct KeyballCommonHooks;
+
+impl CommonHooks for KeyballCommonHooks {
+    async fn on_init(
+        &mut self,
+        hand: Hand,
+        _key_scanner: &mut impl KeyscanDriver,
+        _mouse: Option<&mut impl MouseDriver>,
+        _storage: Option<&mut impl StorageDriver>,
+    ) {
+        let detected = match hand {
+            Hand::Left => 0,
+            Hand::Right => 1,
+        };
+        DETECTED_HAND.store(detected, Ordering::Relaxed);
+        HAND.signal(hand);
+    }
+}
+
+pub struct KeyballMasterHooks;
+
+impl MasterHooks for KeyballMasterHooks {
+    fn on_keyboard_event(&mut self, event: &mut KeyChangeEvent) -> bool {
+        power::notify_activity();
+        if matrix_tester::holds_back(event.row, event.col, event.pressed) {
+            // Keys do not reach the keymap while testing, so the tester is closed here.
+            if event.pressed
+                && keycode::is_on_any_layer(KeyballKey::MatrixTester, event.row, event.col)
+            {
+                display::toggle_matrix_tester();
+            }
+            return false;
+        }
+        degraded::remap(ONE_HANDED, event);
+        if event.pressed {
+            backlight::key_pressed(event.row, event.col);
+            split::send_to_other_half(KeyballMessage::KeyPressed {
+                row: event.row,
+                col: event.col,
+            });
+        }
+        !dongle::forward(DongleEvent::Key {
+            row: event.row,
+            col: event.col,
+            pressed: event.pressed,
+        })
+    }
+
+    fn on_mouse_event(&mut self, data: &mut (i8, i8)) -> bool {
+        power::notify_activity();
+        !dongle::forward(DongleEvent::Mouse {
+            x: data.0,
+            y: data.1,
+        })
+    }
+
+    fn on_custom_event(&mut self, id: u8, pressed: bool) {
+        if let Some(key) = KeyballKey::from_id(id) {
+            keycode::handle(key, pressed);
+        }
+    }
+
+    /// Sends the reports to the selected output only, and keeps rktk from sending them to both.
+    fn on_state_update(
+        &mut self,
+        state_report: &mut StateReport,
+        usb: &Option<impl ReporterDriver>,
+        ble: &Option<impl ReporterDriver>,
+    ) -> bool {
+        display::update(|s| s.layer = state_report.highest_layer);
+        degraded::set_layer(state_report.highest_layer);
+
+        // Fall back to the other output on builds which lack the selected one.
+        let to_usb = match output::current() {
+            Output::Usb => usb.is_some() || ble.is_none(),
+            Output::Ble => ble.is_none(),
+        };
+        if to_usb {
+            if let Some(usb) = usb {
+                send_reports(usb, state_report);
+            }
+        } else if let Some(ble) = ble {
+            send_reports(ble, state_report);
+        }
+        false
+    }
+}
+
+fn send_reports(reporter: &impl ReporterDriver, state_report: &StateReport) {
+    if let Some(report) = state_report.keyboard_report {
+        let _ = reporter.try_send_keyboard_report(report);
+    }
+    if let Some(report) = state_report.mouse_report {
+        let _ = reporter.try_send_mouse_report(report);
+    }
+    if let Some(report) = state_report.media_keyboard_report {
+        let _ = reporter.try_send_media_keyboard_report(report);
+    }
+}
+
+pub fn create_hooks(
+) -> Hooks<KeyballCommonHooks, KeyballMasterHooks, EmptySlaveHooks, EmptyBacklightHooks> {
+    Hooks {
+        common: KeyballCommonHooks,
+        master: KeyballMasterHooks,
+        slave: EmptySlaveHooks,
+        backlight: EmptyBacklightHooks,
+    }
+}
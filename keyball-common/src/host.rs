@@ -0,0 +1,87 @@
+//! Commands from host tools, sent as vendor control requests to the device.
+//!
+//! IN requests return data, OUT requests carry none. Requests which change persistent state reset
+//! the half once they were accepted, and the change takes effect on the next boot.
+//!
+//! | Request | Direction | wValue | Action                                                    |
+//! |---------|-----------|--------|-----------------------------------------------------------|
+//! | 0x01    | IN        |        | Number of crash records, one byte                         |
+//! | 0x02    | IN        | index  | Crash record, oldest first. See [`crash_log`]             |
+//! | 0x03    | OUT       |        | Clears the crash log and resets                           |
+//! | 0x04    | IN        |        | Reset reason of this and the boot before, one byte each   |
+//! | 0x05    | IN        |        | Split link health of this half. See [`LinkStats::encode`] |
+//! | 0x06    | IN        |        | Runtime settings. See [`Revision::encode`]                |
+//!
+//! [`LinkStats::encode`]: crate::split::health::LinkStats::encode
+//! [`Revision::encode`]: crate::settings::Revision::encode
+
+use crate::{crash_log, reset_reason, settings, split::health};
+
+const REQUEST_CRASH_LOG_LEN: u8 = 0x01;
+const REQUEST_CRASH_LOG_GET: u8 = 0x02;
+const REQUEST_CRASH_LOG_CLEAR: u8 = 0x03;
+const REQUEST_RESET_REASON: u8 = 0x04;
+const REQUEST_LINK_STATS: u8 = 0x05;
+const REQUEST_SETTINGS: u8 = 0x06;
+
+/// Size of the largest response.
+pub(crate) const MAX_RESPONSE_SIZE: usize = crash_log::RECORD_SIZE;
+
+pub(crate) enum Response {
+    /// Send this many bytes of the buffer.
+    Data(usize),
+    /// Accept the request, then reset.
+    Reset,
+    Reject,
+}
+
+/// Whether `request` is one of the commands above, in the direction `device_to_host`. Other vendor
+/// requests are left to embassy-usb.
+pub(crate) fn is_command(request: u8, device_to_host: bool) -> bool {
+    match request {
+        REQUEST_CRASH_LOG_LEN
+        | REQUEST_CRASH_LOG_GET
+        | REQUEST_RESET_REASON
+        | REQUEST_LINK_STATS
+        | REQUEST_SETTINGS => device_to_host,
+        REQUEST_CRASH_LOG_CLEAR => !device_to_host,
+        _ => false,
+    }
+}
+
+/// Handles a vendor request. `buf` receives the data of IN requests.
+pub(crate) fn handle(request: u8, value: u16, buf: &mut [u8; MAX_RESPONSE_SIZE]) -> Response {
+    match request {
+        REQUEST_CRASH_LOG_LEN => {
+            buf[0] = crash_log::len() as u8;
+            Response::Data(1)
+        }
+        REQUEST_CRASH_LOG_GET => match crash_log::get(value as usize) {
+            Some(record) => {
+                buf[..record.len()].copy_from_slice(&record);
+                Response::Data(record.len())
+            }
+            None => Response::Reject,
+        },
+        REQUEST_CRASH_LOG_CLEAR => {
+            crash_log::prepare_clear();
+            Response::Reset
+        }
+        REQUEST_RESET_REASON => {
+            buf[0] = reset_reason::current().to_u8();
+            buf[1] = reset_reason::previous().to_u8();
+            Response::Data(2)
+        }
+        REQUEST_LINK_STATS => {
+            let stats = health::stats().encode();
+            buf[..stats.len()].copy_from_slice(&stats);
+            Response::Data(stats.len())
+        }
+        REQUEST_SETTINGS => {
+            let settings = settings::current().encode();
+            buf[..settings.len()].copy_from_slice(&settings);
+            Response::Data(settings.len())
+        }
+        _ => Response::Reject,
+    }
+}
@@ -0,0 +1,55 @@
+//! Keyboard LED state (Num/Caps/Scroll Lock) reported by the host.
+
+use core::sync::atomic::{AtomicU8, Ordering};
+
+use crate::{
+    display,
+    split::{self, KeyballMessage},
+};
+
+/// Bits of the HID keyboard LED output report.
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Default)]
+pub struct HostLeds(pub u8);
+
+impl HostLeds {
+    pub const NUM_LOCK: u8 = 1 << 0;
+    pub const CAPS_LOCK: u8 = 1 << 1;
+    pub const SCROLL_LOCK: u8 = 1 << 2;
+
+    pub fn num_lock(&self) -> bool {
+        self.0 & Self::NUM_LOCK != 0
+    }
+
+    pub fn caps_lock(&self) -> bool {
+        self.0 & Self::CAPS_LOCK != 0
+    }
+
+    pub fn scroll_lock(&self) -> bool {
+        self.0 & Self::SCROLL_LOCK != 0
+    }
+}
+
+static HOST_LEDS: AtomicU8 = AtomicU8::new(0);
+
+pub fn get() -> HostLeds {
+    HostLeds(HOST_LEDS.load(Ordering::Relaxed))
+}
+
+/// Called with the LED output report received from the host on this half. The state is forwarded
+/// to the other half.
+pub fn report(leds: HostLeds) {
+    if apply(leds) {
+        split::send_to_other_half(KeyballMessage::HostLeds(leds.0));
+    }
+}
+
+/// Updates the local state without notifying the other half. Returns `true` if the state changed.
+pub(crate) fn apply(leds: HostLeds) -> bool {
+    // Only load/store are used because thumbv6m has no atomic swap.
+    if HOST_LEDS.load(Ordering::Relaxed) == leds.0 {
+        return false;
+    }
+    HOST_LEDS.store(leds.0, Ordering::Relaxed);
+    display::update(|s| s.host_leds = leds);
+    true
+}
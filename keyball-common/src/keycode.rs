@@ -0,0 +1,166 @@
+//! Keyball specific keycodes.
+//!
+//! They are carried through rktk as `KeyCode::Custom1` with the id of the [`KeyballKey`], and
+//! handled by [`hooks::KeyballMasterHooks`](crate::hooks::KeyballMasterHooks).
+
+use rktk::keymanager::keycode::{KeyAction, KeyCode};
+
+use crate::{
+    bond::{self, BondCommand, SLOT_COUNT},
+    bootloader, display,
+    output::{self, Output, OutputMode},
+    settings, KEYMAP,
+};
+
+const ID_OUTPUT_AUTO: u8 = 0;
+const ID_OUTPUT_USB: u8 = 1;
+const ID_OUTPUT_BLE: u8 = 2;
+const ID_BOND_CLEAR: u8 = 3;
+const ID_BOND_CLEAR_ALL: u8 = 4;
+const ID_BOOTLOADER: u8 = 5;
+const ID_DISPLAY_PAGE: u8 = 6;
+const ID_CPI_UP: u8 = 7;
+const ID_CPI_DOWN: u8 = 8;
+const ID_RGB_MODE: u8 = 9;
+const ID_OLED_TIMEOUT: u8 = 10;
+const ID_MATRIX_TESTER: u8 = 11;
+const ID_AUTO_MOUSE_TIMEOUT: u8 = 12;
+const ID_SLEEP_TIMEOUT: u8 = 13;
+/// Followed by one id per slot.
+const ID_BOND_SELECT: u8 = 0x10;
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum KeyballKey {
+    /// Send reports to USB while VBUS is present, and to BLE otherwise.
+    OutputAuto,
+    OutputUsb,
+    OutputBle,
+    BondSelect(u8),
+    /// Clear the bond of the active slot.
+    BondClear,
+    BondClearAll,
+    /// Reboot both halves into the bootloader. Has to be pressed twice to take effect.
+    Bootloader,
+    /// Show the next page of the display on both halves.
+    DisplayPage,
+    /// Raise the resolution of both balls by [`settings::CPI_STEP`].
+    CpiUp,
+    CpiDown,
+    /// Select the next backlight effect.
+    RgbMode,
+    /// Select the next of [`settings::OLED_TIMEOUTS`].
+    OledTimeout,
+    /// Select the next of [`settings::AUTO_MOUSE_TIMEOUTS`]. rktk reads the timeout only at boot, so
+    /// it takes effect once the master was restarted.
+    AutoMouseTimeout,
+    /// Select the next of [`settings::SLEEP_TIMEOUTS`].
+    SleepTimeout,
+    /// Open or close the matrix tester on both halves.
+    MatrixTester,
+}
+
+impl KeyballKey {
+    pub const fn id(self) -> u8 {
+        match self {
+            KeyballKey::OutputAuto => ID_OUTPUT_AUTO,
+            KeyballKey::OutputUsb => ID_OUTPUT_USB,
+            KeyballKey::OutputBle => ID_OUTPUT_BLE,
+            KeyballKey::BondSelect(slot) => ID_BOND_SELECT + slot,
+            KeyballKey::BondClear => ID_BOND_CLEAR,
+            KeyballKey::BondClearAll => ID_BOND_CLEAR_ALL,
+            KeyballKey::Bootloader => ID_BOOTLOADER,
+            KeyballKey::DisplayPage => ID_DISPLAY_PAGE,
+            KeyballKey::CpiUp => ID_CPI_UP,
+            KeyballKey::CpiDown => ID_CPI_DOWN,
+            KeyballKey::RgbMode => ID_RGB_MODE,
+            KeyballKey::OledTimeout => ID_OLED_TIMEOUT,
+            KeyballKey::AutoMouseTimeout => ID_AUTO_MOUSE_TIMEOUT,
+            KeyballKey::SleepTimeout => ID_SLEEP_TIMEOUT,
+            KeyballKey::MatrixTester => ID_MATRIX_TESTER,
+        }
+    }
+
+    pub const fn from_id(id: u8) -> Option<Self> {
+        match id {
+            ID_OUTPUT_AUTO => Some(KeyballKey::OutputAuto),
+            ID_OUTPUT_USB => Some(KeyballKey::OutputUsb),
+            ID_OUTPUT_BLE => Some(KeyballKey::OutputBle),
+            ID_BOND_CLEAR => Some(KeyballKey::BondClear),
+            ID_BOND_CLEAR_ALL => Some(KeyballKey::BondClearAll),
+            ID_BOOTLOADER => Some(KeyballKey::Bootloader),
+            ID_DISPLAY_PAGE => Some(KeyballKey::DisplayPage),
+            ID_CPI_UP => Some(KeyballKey::CpiUp),
+            ID_CPI_DOWN => Some(KeyballKey::CpiDown),
+            ID_RGB_MODE => Some(KeyballKey::RgbMode),
+            ID_OLED_TIMEOUT => Some(KeyballKey::OledTimeout),
+            ID_AUTO_MOUSE_TIMEOUT => Some(KeyballKey::AutoMouseTimeout),
+            ID_SLEEP_TIMEOUT => Some(KeyballKey::SleepTimeout),
+            ID_MATRIX_TESTER => Some(KeyballKey::MatrixTester),
+            id if id >= ID_BOND_SELECT && id < ID_BOND_SELECT + SLOT_COUNT => {
+                Some(KeyballKey::BondSelect(id - ID_BOND_SELECT))
+            }
+            _ => None,
+        }
+    }
+
+    pub const fn action(self) -> KeyAction {
+        KeyAction::Normal(KeyCode::Custom1(self.id()))
+    }
+}
+
+pub const OUT_AUTO: KeyAction = KeyballKey::OutputAuto.action();
+pub const OUT_USB: KeyAction = KeyballKey::OutputUsb.action();
+pub const OUT_BLE: KeyAction = KeyballKey::OutputBle.action();
+
+pub const BT_CLR: KeyAction = KeyballKey::BondClear.action();
+pub const BT_CLRA: KeyAction = KeyballKey::BondClearAll.action();
+
+pub const BOOT: KeyAction = KeyballKey::Bootloader.action();
+pub const DISP: KeyAction = KeyballKey::DisplayPage.action();
+pub const MTX: KeyAction = KeyballKey::MatrixTester.action();
+
+pub const CPI_UP: KeyAction = KeyballKey::CpiUp.action();
+pub const CPI_DN: KeyAction = KeyballKey::CpiDown.action();
+pub const RGB_MOD: KeyAction = KeyballKey::RgbMode.action();
+pub const OLED_TO: KeyAction = KeyballKey::OledTimeout.action();
+pub const AML_TO: KeyAction = KeyballKey::AutoMouseTimeout.action();
+pub const SLP_TO: KeyAction = KeyballKey::SleepTimeout.action();
+
+/// Whether the key at `(row, col)` is `key` on any layer of [`KEYMAP`].
+pub(crate) fn is_on_any_layer(key: KeyballKey, row: u8, col: u8) -> bool {
+    KEYMAP.layers.iter().any(|layer| {
+        let action = layer
+            .map
+            .get(row as usize)
+            .and_then(|r| r.get(col as usize));
+        matches!(action, Some(KeyAction::Normal(KeyCode::Custom1(id))) if *id == key.id())
+    })
+}
+
+/// Selects the BLE bond slot `slot`, counted from 0.
+pub const fn bt(slot: u8) -> KeyAction {
+    KeyballKey::BondSelect(slot).action()
+}
+
+pub(crate) fn handle(key: KeyballKey, pressed: bool) {
+    if !pressed {
+        return;
+    }
+    match key {
+        KeyballKey::OutputAuto => output::set_mode(OutputMode::Auto),
+        KeyballKey::OutputUsb => output::set_mode(OutputMode::Forced(Output::Usb)),
+        KeyballKey::OutputBle => output::set_mode(OutputMode::Forced(Output::Ble)),
+        KeyballKey::BondSelect(slot) => bond::request(BondCommand::Select(slot)),
+        KeyballKey::BondClear => bond::request(BondCommand::ClearCurrent),
+        KeyballKey::BondClearAll => bond::request(BondCommand::ClearAll),
+        KeyballKey::Bootloader => bootloader::key_pressed(),
+        KeyballKey::DisplayPage => display::next_page(),
+        KeyballKey::CpiUp => settings::change(|s| s.step_cpi(1)),
+        KeyballKey::CpiDown => settings::change(|s| s.step_cpi(-1)),
+        KeyballKey::RgbMode => settings::change(|s| s.rgb_mode = s.rgb_mode.next()),
+        KeyballKey::OledTimeout => settings::change(|s| s.next_oled_timeout()),
+        KeyballKey::AutoMouseTimeout => settings::change(|s| s.next_auto_mouse_timeout()),
+        KeyballKey::SleepTimeout => settings::change(|s| s.next_sleep_timeout()),
+        KeyballKey::MatrixTester => display::toggle_matrix_tester(),
+    }
+}
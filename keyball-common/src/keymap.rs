@@ -5,6 +5,8 @@ use rktk::keymanager::keycode::{
 use rktk::keymanager::keymap::TapDanceDefinition;
 use rktk::keymap_config::{Keymap, Layer, LayerMap};
 
+use crate::keycode::*;
+
 const L2ENTER: KeyAction = KeyAction::TapHold(
     KeyCode::Key(Key::Enter),
     KeyCode::Layer(LayerOp::Momentary(2)),
@@ -25,11 +27,18 @@ const L4GRV: KeyAction = KeyAction::TapHold(
     KeyCode::Layer(LayerOp::Momentary(4)),
 );
 
+/// Held by the master while the split link is lost, at a position without a switch. See
+/// [`ONE_HANDED_LAYER`](crate::ONE_HANDED_LAYER).
+const ONE_HND: KeyAction = match crate::ONE_HANDED_LAYER {
+    Some(layer) => KeyAction::Normal(KeyCode::Layer(LayerOp::Momentary(layer))),
+    None => _____,
+};
+
 const FL_CLR: KeyAction = KeyAction::Normal(KeyCode::Special(Special::FlashClear));
 
 #[rustfmt::skip]
 const L0: LayerMap = [
-    [ L4GRV , D1    , D2    , D3    , D4    , D5    , _____ , /**/ _____ , D6    , D7    , D8    , D9    , D0   , EQUAL ],
+    [ L4GRV , D1    , D2    , D3    , D4    , D5    ,ONE_HND, /**/ONE_HND, D6    , D7    , D8    , D9    , D0   , EQUAL ],
     [  TAB  , Q     , W     , E     , R     , T     , _____ , /**/ _____ , Y     , U     , I     , O     , P    , MINUS],
     [  ESC  , A     , S     , D     , F     , G     , _____ , /**/ _____ , H     , J     , K     , L     , SCLN , QUOTE],
     [ L_SHFT, Z     , X     , C     , V     , B     , LBRC  , /**/ TD(0) , N     , M     , COMM  , DOT   , SLASH, BSLSH],
@@ -67,10 +76,10 @@ const L3: LayerMap = [
 
 #[rustfmt::skip]
 const L4: LayerMap = [
-    [ _____ , _____ , _____ , _____ , _____ , _____ , _____ , /**/ _____ , _____ , _____ , _____ , _____ , _____ , _____ ],
-    [ _____ , _____ , _____ , _____ , _____ , _____ , _____ , /**/ _____ , _____ , _____ , _____ , _____ , _____ , _____ ],
-    [ _____ , _____ , _____ , _____ , _____ , _____ , _____ , /**/ _____ , _____ , _____ , _____ , _____ , _____ , _____ ],
-    [ _____ , _____ , _____ , _____ , _____ , _____ , _____ , /**/ _____ , _____ , _____ , _____ , _____ , _____ , _____ ],
+    [ _____ ,OUT_AUTO,OUT_USB,OUT_BLE, _____ , BOOT  , _____ , /**/ _____ , _____ , _____ , _____ , _____ , _____ , _____ ],
+    [ _____ , bt(0) , bt(1) , bt(2) , bt(3) , BT_CLR, _____ , /**/ _____ , _____ , _____ , _____ , _____ , _____ , _____ ],
+    [ _____ , _____ , _____ , _____ , MTX   ,BT_CLRA, DISP  , /**/ _____ , _____ , _____ , _____ , _____ , _____ , _____ ],
+    [ _____ , CPI_DN, CPI_UP,RGB_MOD,OLED_TO,AML_TO , SLP_TO, /**/ _____ , _____ , _____ , _____ , _____ , _____ , _____ ],
     [ _____ , _____ , _____ , _____ , _____ , _____ , _____ , /**/ _____ , _____ , _____ , _____ , _____ , _____ , _____ ],
 ];
 
@@ -0,0 +1,128 @@
+//! Physical layout of the Keyball61.
+//!
+//! Coordinates are derived from the KLE layout in `rktk.json` and expressed in 1/100 key units, with
+//! the origin at the top-left corner of the left half. Rotated thumb keys are stored at the rotated
+//! centre of the key.
+
+use rktk::drivers::interface::keyscan::Hand;
+
+pub const ROWS: usize = 5;
+pub const COLS: usize = 14;
+
+/// Column of the keymap of column `col` of the half of `hand`. Like rktk, the columns of the right
+/// half are mirrored, so that column 0 of either half is its outermost one.
+pub const fn keymap_col(hand: Hand, col: u8) -> u8 {
+    match hand {
+        Hand::Left => col,
+        Hand::Right => COLS as u8 - 1 - col,
+    }
+}
+
+pub const LEFT_LED_COUNT: usize = 37;
+pub const RIGHT_LED_COUNT: usize = 34;
+pub const MAX_LED_COUNT: usize = LEFT_LED_COUNT;
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct Point {
+    pub x: i16,
+    pub y: i16,
+}
+
+impl Point {
+    pub const fn new(x: i16, y: i16) -> Self {
+        Self { x, y }
+    }
+
+    /// Euclidean distance in layout units.
+    pub fn distance(&self, other: &Point) -> u16 {
+        let dx = (self.x as i32 - other.x as i32).unsigned_abs();
+        let dy = (self.y as i32 - other.y as i32).unsigned_abs();
+        isqrt(dx * dx + dy * dy) as u16
+    }
+}
+
+fn isqrt(n: u32) -> u32 {
+    if n < 2 {
+        return n;
+    }
+    let mut x = n;
+    let mut y = (x + 1) / 2;
+    while y < x {
+        x = y;
+        y = (x + n / x) / 2;
+    }
+    x
+}
+
+const fn p(x: i16, y: i16) -> Option<Point> {
+    Some(Point::new(x, y))
+}
+
+const NA: Option<Point> = None;
+
+#[rustfmt::skip]
+pub const KEY_POSITIONS: [[Option<Point>; COLS]; ROWS] = [
+    [ p(50, 110), p(150, 110), p(250,  75), p(350,  50), p(450,  62), p(550,  75), NA        , /**/ NA        , p(1100,  75), p(1200,  62), p(1300,  50), p(1400,  75), p(1500, 110), p(1600, 110) ],
+    [ p(50, 210), p(150, 210), p(250, 175), p(350, 150), p(450, 162), p(550, 175), NA        , /**/ NA        , p(1100, 175), p(1200, 162), p(1300, 150), p(1400, 175), p(1500, 210), p(1600, 210) ],
+    [ p(50, 310), p(150, 310), p(250, 275), p(350, 250), p(450, 262), p(550, 275), NA        , /**/ NA        , p(1100, 275), p(1200, 262), p(1300, 250), p(1400, 275), p(1500, 310), p(1600, 310) ],
+    [ p(50, 410), p(150, 410), p(250, 375), p(350, 350), p(450, 362), p(550, 375), p(650, 390), /**/ p(1000, 390), p(1100, 375), p(1200, 362), p(1300, 350), p(1400, 375), p(1500, 410), p(1600, 410) ],
+    [ p(50, 510), p(150, 510), p(250, 475), p(350, 450), p(480, 500), p(593, 509), p(701, 540), /**/ p(949, 541), p(1057, 511), p(1170, 500), p(1300, 450), p(1400, 475), p(1500, 510), p(1600, 510) ],
+];
+
+pub fn key_position(row: usize, col: usize) -> Option<Point> {
+    KEY_POSITIONS.get(row)?.get(col).copied().flatten()
+}
+
+/// What a single LED of the chain sits under.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Led {
+    Key(u8, u8),
+    Underglow(Point),
+}
+
+impl Led {
+    pub fn position(&self) -> Point {
+        match self {
+            Led::Key(row, col) => key_position(*row as usize, *col as usize)
+                .expect("LED table references a key that does not exist"),
+            Led::Underglow(p) => *p,
+        }
+    }
+}
+
+const fn k(row: u8, col: u8) -> Led {
+    Led::Key(row, col)
+}
+
+const fn u(x: i16, y: i16) -> Led {
+    Led::Underglow(Point::new(x, y))
+}
+
+/// LED chain of the left half. Per-key LEDs come first, followed by the underglow.
+#[rustfmt::skip]
+pub const LEFT_LEDS: [Led; LEFT_LED_COUNT] = [
+    k(0, 0), k(0, 1), k(0, 2), k(0, 3), k(0, 4), k(0, 5),
+    k(1, 0), k(1, 1), k(1, 2), k(1, 3), k(1, 4), k(1, 5),
+    k(2, 0), k(2, 1), k(2, 2), k(2, 3), k(2, 4), k(2, 5),
+    k(3, 0), k(3, 1), k(3, 2), k(3, 3), k(3, 4), k(3, 5), k(3, 6),
+    k(4, 0), k(4, 1), k(4, 2), k(4, 3), k(4, 4), k(4, 5), k(4, 6),
+    u(100, 150), u(400, 100), u(600, 300), u(450, 450), u(100, 450),
+];
+
+/// LED chain of the right half. The trackball takes the place of keys (4, 9) to (4, 11).
+#[rustfmt::skip]
+pub const RIGHT_LEDS: [Led; RIGHT_LED_COUNT] = [
+    k(0, 8), k(0, 9), k(0, 10), k(0, 11), k(0, 12), k(0, 13),
+    k(1, 8), k(1, 9), k(1, 10), k(1, 11), k(1, 12), k(1, 13),
+    k(2, 8), k(2, 9), k(2, 10), k(2, 11), k(2, 12), k(2, 13),
+    k(3, 7), k(3, 8), k(3, 9), k(3, 10), k(3, 11), k(3, 12), k(3, 13),
+    k(4, 7), k(4, 8), k(4, 12), k(4, 13),
+    u(1550, 150), u(1250, 100), u(1050, 300), u(1200, 450), u(1550, 450),
+];
+
+pub fn leds(hand: Hand) -> &'static [Led] {
+    match hand {
+        Hand::Left => &LEFT_LEDS,
+        Hand::Right => &RIGHT_LEDS,
+    }
+}
@@ -1,11 +1,57 @@
 //! Common definitions for the Keyball keyboard firmware. Independent of the specific MCU used.
-#![no_std]
+#![cfg_attr(not(test), no_std)]
 
+pub mod backlight;
+pub mod battery;
+pub mod bond;
+pub mod bootloader;
+pub mod crash_log;
+pub mod debounce;
+pub mod display;
+pub mod dongle;
+pub mod double_reset;
+pub mod handedness;
+pub mod hardware_id;
+pub mod hooks;
+pub mod host;
+pub mod host_leds;
+pub mod keycode;
 pub mod keymap;
+pub mod layout;
+pub mod matrix_tester;
+pub mod output;
+pub mod pin_map;
+pub mod power;
+pub mod reset_reason;
+pub mod retained;
+pub mod settings;
+pub mod split;
+pub mod storage;
+pub mod usb;
+pub mod watchdog;
 
 pub use keymap::KEYMAP;
 
+use debounce::{DebounceConfig, Strategy};
+use embassy_time::Duration;
 use rktk_drivers_common::{keyscan::duplex_matrix::ScanDir, mouse::paw3395, usb::UsbDriverConfig};
+use split::degraded::Remap;
+
+/// Debounce of the matrix on both MCUs. Switches which chatter can be given their own strategy in
+/// `overrides` by their position in the keymap, e.g.
+/// `((2, 3), Strategy::deferred(Duration::from_millis(10)))`.
+pub const DEBOUNCE: DebounceConfig = DebounceConfig {
+    default: Strategy::eager(Duration::from_millis(20)),
+    overrides: &[],
+};
+
+/// Keys which the master remaps while the split link is lost, as `(from, to)` positions of the
+/// keymap, e.g. `((4, 5), (4, 7))` to reach Backspace from the left half. Empty to keep the keys.
+pub const ONE_HANDED: &[Remap] = &[];
+
+/// Layer which the master holds while the split link is lost, e.g. one which puts the keys of the
+/// other half on this one. `None` to keep the layers.
+pub const ONE_HANDED_LAYER: Option<u8> = None;
 
 pub const PAW3395_CONFIG: paw3395::config::Config = paw3395::config::Config {
     mode: paw3395::config::HP_MODE,
@@ -13,11 +59,10 @@ pub const PAW3395_CONFIG: paw3395::config::Config = paw3395::config::Config {
 };
 
 pub const USB_CONFIG: UsbDriverConfig = {
-    let mut config = UsbDriverConfig::new(0xc0de, 0xcafe);
+    let mut config = UsbDriverConfig::new(UsbIdentity::DEFAULT.vid, UsbIdentity::DEFAULT.pid);
 
-    config.manufacturer = Some("Yowkees/nazo6");
-    config.product = Some("keyball");
-    config.serial_number = Some("12345678");
+    config.manufacturer = Some(UsbIdentity::DEFAULT.manufacturer);
+    config.product = Some(UsbIdentity::DEFAULT.product);
     config.max_power = 100;
     config.max_packet_size_0 = 64;
     config.supports_remote_wakeup = true;
@@ -25,6 +70,38 @@ pub const USB_CONFIG: UsbDriverConfig = {
     config
 };
 
+/// USB descriptors which identify the board to the host.
+///
+/// Each build variant generates its own from the `keyball.usb` section of `rktk.json` in its build
+/// script, so that host tools can tell RP2040 and nRF52840 builds apart.
+pub struct UsbIdentity {
+    pub vid: u16,
+    pub pid: u16,
+    pub manufacturer: &'static str,
+    pub product: &'static str,
+}
+
+impl UsbIdentity {
+    pub const DEFAULT: UsbIdentity = UsbIdentity {
+        vid: 0xc0de,
+        pid: 0xcafe,
+        manufacturer: "Yowkees/nazo6",
+        product: "keyball",
+    };
+}
+
+/// [`USB_CONFIG`] with the identity and serial number set. Use [`hardware_id::serial_number`] to get
+/// a serial number which is unique to the board.
+pub fn usb_config(identity: &UsbIdentity, serial_number: &'static str) -> UsbDriverConfig {
+    let mut config = USB_CONFIG;
+    config.vendor_id = identity.vid;
+    config.product_id = identity.pid;
+    config.manufacturer = Some(identity.manufacturer);
+    config.product = Some(identity.product);
+    config.serial_number = Some(serial_number);
+    config
+}
+
 // Left
 //    [COL2ROW] [ROW2COL]
 // COL 0 1 2    0 1 2 3
@@ -41,3 +118,40 @@ pub fn translate_key_position(dir: ScanDir, row: usize, col: usize) -> Option<(u
         ScanDir::Row2Col => Some((row, col + 3)),
     }
 }
+
+/// Inverse of [`translate_key_position`]. Returns the scan direction and the indices of the row and
+/// column pins which read the key at `(row, col)` of a half.
+pub fn key_pins(row: usize, col: usize) -> Option<(ScanDir, usize, usize)> {
+    if row >= pin_map::KEYBALL61.rows.len() {
+        return None;
+    }
+    match col {
+        0..=2 => Some((ScanDir::Col2Row, row, col)),
+        3..=6 => Some((ScanDir::Row2Col, row, col - 3)),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn key_pins_read_the_key_back() {
+        for row in 0..layout::ROWS {
+            for col in 0..matrix_tester::HALF_COLS {
+                let (dir, row_pin, col_pin) = key_pins(row, col).unwrap();
+                assert_eq!(
+                    translate_key_position(dir, row_pin, col_pin),
+                    Some((row, col))
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn key_pins_of_positions_outside_of_a_half_are_none() {
+        assert_eq!(key_pins(layout::ROWS, 0), None);
+        assert_eq!(key_pins(0, matrix_tester::HALF_COLS), None);
+    }
+}
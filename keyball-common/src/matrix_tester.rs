@@ -0,0 +1,298 @@
+//! Matrix tester, shown on the display while it is active.
+//!
+//! Each half records the keys of its own matrix: their debounced state, how often they were
+//! pressed, and edges on the contacts which the debouncer filtered out, which mark a chattering
+//! switch. Debounced changes are also sent to the other half, so that both displays show the whole
+//! keyboard. The recording starts over whenever the tester is opened.
+//!
+//! While the tester is active, the master keeps the keys from rktk, so that testing does not type
+//! into the host. Only keys which were pressed before it opened are released as usual.
+
+use core::{
+    cell::Cell,
+    sync::atomic::{AtomicBool, Ordering},
+};
+
+use embassy_sync::blocking_mutex::{raw::CriticalSectionRawMutex, Mutex};
+use rktk::drivers::interface::keyscan::Hand;
+
+use crate::{
+    hooks,
+    layout::{keymap_col, COLS, ROWS},
+    split::{self, KeyballMessage},
+};
+
+/// Columns of one half.
+pub const HALF_COLS: usize = COLS / 2;
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Default)]
+pub struct KeyStats {
+    pub presses: u16,
+    /// Edges seen on the contacts.
+    pub raw_edges: u16,
+    /// Edges left after debouncing.
+    pub edges: u16,
+}
+
+impl KeyStats {
+    const NEW: KeyStats = KeyStats {
+        presses: 0,
+        raw_edges: 0,
+        edges: 0,
+    };
+
+    /// Edges on the contacts which the debouncer filtered out.
+    pub fn bounces(&self) -> u16 {
+        self.raw_edges.saturating_sub(self.edges)
+    }
+}
+
+/// Key of this half which changed last.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct LastKey {
+    pub row: u8,
+    /// Column in the whole keyboard.
+    pub col: u8,
+    /// Column within the half.
+    pub local_col: u8,
+    pub stats: KeyStats,
+}
+
+#[derive(Clone, Copy)]
+pub struct Matrix {
+    /// Debounced state of the whole keyboard, one bit per column.
+    pressed: [u16; ROWS],
+    /// Keys of the whole keyboard which chattered, one bit per column.
+    chatter: [u16; ROWS],
+    /// Keys of this half, by their position within the half.
+    keys: [[KeyStats; HALF_COLS]; ROWS],
+    /// Position within the half of the key which changed last.
+    last: Option<(u8, u8)>,
+}
+
+impl Matrix {
+    const NEW: Matrix = Matrix {
+        pressed: [0; ROWS],
+        chatter: [0; ROWS],
+        keys: [[KeyStats::NEW; HALF_COLS]; ROWS],
+        last: None,
+    };
+
+    /// Whether the key at `(row, col)` of the whole keyboard is pressed.
+    pub fn is_pressed(&self, row: u8, col: u8) -> bool {
+        bit(&self.pressed, row, col)
+    }
+
+    /// Whether the key at `(row, col)` of the whole keyboard chattered.
+    pub fn chatters(&self, row: u8, col: u8) -> bool {
+        bit(&self.chatter, row, col)
+    }
+
+    pub fn last(&self) -> Option<LastKey> {
+        let (row, local_col) = self.last?;
+        Some(LastKey {
+            row,
+            col: whole_col(local_col),
+            local_col,
+            stats: self.keys[row as usize][local_col as usize],
+        })
+    }
+
+    fn key_mut(&mut self, row: u8, col: u8) -> Option<&mut KeyStats> {
+        self.keys.get_mut(row as usize)?.get_mut(col as usize)
+    }
+
+    fn set(&mut self, row: u8, col: u8, pressed: bool, chatter: bool) {
+        if row as usize >= ROWS || col as usize >= COLS {
+            return;
+        }
+        let bit = 1 << col;
+        let row = row as usize;
+        if pressed {
+            self.pressed[row] |= bit;
+        } else {
+            self.pressed[row] &= !bit;
+        }
+        if chatter {
+            self.chatter[row] |= bit;
+        }
+    }
+}
+
+fn bit(bits: &[u16; ROWS], row: u8, col: u8) -> bool {
+    bits.get(row as usize)
+        .is_some_and(|bits| col < 16 && bits & (1 << col) != 0)
+}
+
+/// Column in the whole keyboard of a column of this half.
+fn whole_col(col: u8) -> u8 {
+    keymap_col(hooks::hand().unwrap_or(Hand::Left), col)
+}
+
+static ACTIVE: AtomicBool = AtomicBool::new(false);
+static MATRIX: Mutex<CriticalSectionRawMutex, Cell<Matrix>> = Mutex::new(Cell::new(Matrix::NEW));
+/// Keys of the keymap whose presses were held back from rktk, one bit per column.
+static HELD_BACK: Mutex<CriticalSectionRawMutex, Cell<[u16; ROWS]>> =
+    Mutex::new(Cell::new([0; ROWS]));
+
+fn modify(f: impl FnOnce(&mut Matrix)) {
+    MATRIX.lock(|matrix| {
+        let mut m = matrix.get();
+        f(&mut m);
+        matrix.set(m);
+    });
+}
+
+pub fn is_active() -> bool {
+    ACTIVE.load(Ordering::Relaxed)
+}
+
+/// Starts or stops recording. Starting clears the previous recording.
+pub(crate) fn set_active(active: bool) {
+    if active && !is_active() {
+        MATRIX.lock(|matrix| matrix.set(Matrix::NEW));
+    }
+    ACTIVE.store(active, Ordering::Relaxed);
+}
+
+/// Whether a key event of the keymap is held back from rktk. Presses are held back while the tester
+/// is active, and releases of the keys whose press was.
+pub(crate) fn holds_back(row: u8, col: u8, pressed: bool) -> bool {
+    if row as usize >= ROWS || col >= 16 {
+        return false;
+    }
+    let bit = 1 << col;
+    HELD_BACK.lock(|held_back| {
+        let mut keys = held_back.get();
+        let held = if pressed {
+            is_active()
+        } else {
+            keys[row as usize] & bit != 0
+        };
+        if held && pressed {
+            keys[row as usize] |= bit;
+        } else {
+            keys[row as usize] &= !bit;
+        }
+        held_back.set(keys);
+        held
+    })
+}
+
+pub fn matrix() -> Matrix {
+    MATRIX.lock(|matrix| matrix.get())
+}
+
+/// Records an edge on the contacts of a key of this half.
+pub(crate) fn raw_edge(row: u8, col: u8) {
+    if !is_active() {
+        return;
+    }
+    modify(|m| {
+        let Some(key) = m.key_mut(row, col) else {
+            return;
+        };
+        key.raw_edges = key.raw_edges.saturating_add(1);
+        // One edge may still wait for debouncing, more than that were filtered out.
+        if key.raw_edges > key.edges.saturating_add(1) {
+            m.set(row, whole_col(col), m.is_pressed(row, whole_col(col)), true);
+        }
+    });
+}
+
+/// Records a debounced change of a key of this half and passes it on to the other half.
+pub(crate) fn key_changed(row: u8, col: u8, pressed: bool) {
+    if !is_active() {
+        return;
+    }
+    let mut chatter = None;
+    modify(|m| {
+        let Some(key) = m.key_mut(row, col) else {
+            return;
+        };
+        key.edges = key.edges.saturating_add(1);
+        if pressed {
+            key.presses = key.presses.saturating_add(1);
+        }
+        let bounced = key.bounces() > 0;
+        m.last = Some((row, col));
+        m.set(row, whole_col(col), pressed, bounced);
+        chatter = Some(m.chatters(row, whole_col(col)));
+    });
+    if let Some(chatter) = chatter {
+        split::send_to_other_half(KeyballMessage::MatrixKey {
+            row,
+            col: whole_col(col),
+            pressed,
+            chatter,
+        });
+    }
+}
+
+/// Records a debounced change of a key of the other half.
+pub(crate) fn remote_key_changed(row: u8, col: u8, pressed: bool, chatter: bool) {
+    if is_active() {
+        modify(|m| m.set(row, col, pressed, chatter));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Mutex;
+
+    use super::*;
+
+    /// The tester is global, so tests which open it must not overlap.
+    static TESTER: Mutex<()> = Mutex::new(());
+
+    #[test]
+    fn bounces_mark_the_key_as_chattering() {
+        let _tester = TESTER.lock().unwrap();
+        set_active(true);
+
+        // A clean press and release.
+        raw_edge(0, 1);
+        key_changed(0, 1, true);
+        raw_edge(0, 1);
+        key_changed(0, 1, false);
+        assert!(!matrix().chatters(0, 1));
+
+        // One edge may still wait for debouncing.
+        raw_edge(1, 2);
+        assert!(!matrix().chatters(1, 2));
+        raw_edge(1, 2);
+        raw_edge(1, 2);
+        assert!(matrix().chatters(1, 2));
+        key_changed(1, 2, true);
+        assert!(matrix().is_pressed(1, 2));
+
+        let last = matrix().last().unwrap();
+        assert_eq!((last.row, last.col, last.local_col), (1, 2, 2));
+        assert_eq!(last.stats.presses, 1);
+        assert_eq!(last.stats.bounces(), 2);
+        assert!(!matrix().chatters(0, 1));
+
+        // Opening the tester again starts over.
+        set_active(false);
+        set_active(true);
+        assert!(!matrix().chatters(1, 2));
+        assert_eq!(matrix().last(), None);
+        set_active(false);
+    }
+
+    #[test]
+    fn keys_are_held_back_while_active() {
+        let _tester = TESTER.lock().unwrap();
+        assert!(!holds_back(2, 3, true));
+
+        set_active(true);
+        assert!(!holds_back(2, 3, false));
+        assert!(holds_back(0, 4, true));
+
+        // The release of a held back press is held back, even once the tester was closed.
+        set_active(false);
+        assert!(holds_back(0, 4, false));
+        assert!(!holds_back(0, 4, true));
+        assert!(!holds_back(0, 4, false));
+    }
+}
@@ -0,0 +1,62 @@
+//! Selection of the output HID reports are sent to.
+//!
+//! By default reports go to USB while VBUS is present and to BLE otherwise. The output can also be
+//! forced to either of them with [`keycode::KeyballKey`](crate::keycode::KeyballKey)s.
+
+use core::sync::atomic::{AtomicBool, AtomicU8, Ordering};
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Output {
+    Usb,
+    Ble,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum OutputMode {
+    /// USB if VBUS is present, BLE otherwise.
+    Auto,
+    Forced(Output),
+}
+
+const MODE_AUTO: u8 = 0;
+const MODE_USB: u8 = 1;
+const MODE_BLE: u8 = 2;
+
+static MODE: AtomicU8 = AtomicU8::new(MODE_AUTO);
+// Builds which can not detect VBUS never update this, so they keep sending to USB.
+static VBUS: AtomicBool = AtomicBool::new(true);
+
+pub fn set_mode(mode: OutputMode) {
+    let mode = match mode {
+        OutputMode::Auto => MODE_AUTO,
+        OutputMode::Forced(Output::Usb) => MODE_USB,
+        OutputMode::Forced(Output::Ble) => MODE_BLE,
+    };
+    MODE.store(mode, Ordering::Relaxed);
+}
+
+pub fn mode() -> OutputMode {
+    match MODE.load(Ordering::Relaxed) {
+        MODE_USB => OutputMode::Forced(Output::Usb),
+        MODE_BLE => OutputMode::Forced(Output::Ble),
+        _ => OutputMode::Auto,
+    }
+}
+
+/// Called by the MCU crate whenever VBUS appears or disappears.
+pub fn set_vbus(present: bool) {
+    VBUS.store(present, Ordering::Relaxed);
+}
+
+pub fn vbus() -> bool {
+    VBUS.load(Ordering::Relaxed)
+}
+
+/// Output reports should currently go to.
+pub fn current() -> Output {
+    match mode() {
+        OutputMode::Forced(output) => output,
+        OutputMode::Auto if vbus() => Output::Usb,
+        OutputMode::Auto => Output::Ble,
+    }
+}
@@ -0,0 +1,159 @@
+//! Pin assignments of the Keyball, described by Pro Micro pin names.
+//!
+//! [`KEYBALL61`] says which Pro Micro pin each signal is wired to, and each supported controller
+//! provides a [`ControllerPins`] table which resolves those names to pins of its MCU. Supporting a
+//! new Pro Micro compatible controller only needs a new table.
+
+/// Pins of the Pro Micro footprint, named after the silkscreen. Pins are listed counterclockwise
+/// from TX, skipping power pins.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum ProMicroPin {
+    TX,
+    RX,
+    D2,
+    D3,
+    D4,
+    D5,
+    D6,
+    D7,
+    D8,
+    D9,
+    D10,
+    D16,
+    D14,
+    D15,
+    A0,
+    A1,
+    A2,
+    A3,
+}
+
+pub const PRO_MICRO_PIN_COUNT: usize = 18;
+
+impl ProMicroPin {
+    pub const fn name(self) -> &'static str {
+        match self {
+            ProMicroPin::TX => "TX",
+            ProMicroPin::RX => "RX",
+            ProMicroPin::D2 => "D2",
+            ProMicroPin::D3 => "D3",
+            ProMicroPin::D4 => "D4",
+            ProMicroPin::D5 => "D5",
+            ProMicroPin::D6 => "D6",
+            ProMicroPin::D7 => "D7",
+            ProMicroPin::D8 => "D8",
+            ProMicroPin::D9 => "D9",
+            ProMicroPin::D10 => "D10",
+            ProMicroPin::D16 => "D16",
+            ProMicroPin::D14 => "D14",
+            ProMicroPin::D15 => "D15",
+            ProMicroPin::A0 => "A0",
+            ProMicroPin::A1 => "A1",
+            ProMicroPin::A2 => "A2",
+            ProMicroPin::A3 => "A3",
+        }
+    }
+}
+
+/// Signals of a Keyball and the Pro Micro pins they are wired to.
+pub struct KeyballPins {
+    pub rows: [ProMicroPin; 5],
+    pub cols: [ProMicroPin; 4],
+    pub oled_sda: ProMicroPin,
+    pub oled_scl: ProMicroPin,
+    pub ball_sck: ProMicroPin,
+    pub ball_miso: ProMicroPin,
+    pub ball_mosi: ProMicroPin,
+    pub ball_cs: ProMicroPin,
+    /// One-wire half-duplex link to the other half.
+    pub split: ProMicroPin,
+    /// Data line of the WS2812 chain.
+    pub rgb: ProMicroPin,
+    /// Optional strap for builds which detect the hand by pin. Pulled up, tied to GND on the right
+    /// half.
+    pub hand_strap: ProMicroPin,
+}
+
+pub const KEYBALL61: KeyballPins = KeyballPins {
+    rows: [
+        ProMicroPin::D4,
+        ProMicroPin::D5,
+        ProMicroPin::D6,
+        ProMicroPin::D7,
+        ProMicroPin::D8,
+    ],
+    cols: [
+        ProMicroPin::A3,
+        ProMicroPin::A2,
+        ProMicroPin::A1,
+        ProMicroPin::A0,
+    ],
+    oled_sda: ProMicroPin::D2,
+    oled_scl: ProMicroPin::D3,
+    ball_sck: ProMicroPin::D15,
+    ball_miso: ProMicroPin::D14,
+    ball_mosi: ProMicroPin::D16,
+    ball_cs: ProMicroPin::D10,
+    split: ProMicroPin::RX,
+    rgb: ProMicroPin::TX,
+    hand_strap: ProMicroPin::D9,
+};
+
+/// [`KEYBALL61`] as wired by the nRF52840 build, which drives the ball CS from TX and the LED chain
+/// from D10, the other way round than the RP2040 build. Both builds keep the pins they were
+/// originally written with.
+pub const KEYBALL61_NRF: KeyballPins = KeyballPins {
+    ball_cs: ProMicroPin::TX,
+    rgb: ProMicroPin::D10,
+    ..KEYBALL61
+};
+
+/// Resolution table of a controller, in the order of [`ProMicroPin`].
+///
+/// Entries are the pin numbers the MCU HAL uses to address a pin: the GPIO number on RP2040, and
+/// `port * 32 + pin` on nRF52840.
+pub struct ControllerPins {
+    pub name: &'static str,
+    pub pins: [u8; PRO_MICRO_PIN_COUNT],
+}
+
+impl ControllerPins {
+    pub const fn resolve(&self, pin: ProMicroPin) -> u8 {
+        self.pins[pin as usize]
+    }
+}
+
+const fn nrf(port: u8, pin: u8) -> u8 {
+    port * 32 + pin
+}
+
+#[rustfmt::skip]
+pub const PRO_MICRO_RP2040: ControllerPins = ControllerPins {
+    name: "ProMicro RP2040",
+    pins: [
+        0, 1, 2, 3, 4, 5, 6, 7, 8, 9,
+        21, 23, 20, 22, 26, 27, 28, 29,
+    ],
+};
+
+#[rustfmt::skip]
+pub const NICE_NANO: ControllerPins = ControllerPins {
+    name: "nice!nano",
+    pins: [
+        nrf(0, 6), nrf(0, 8), nrf(0, 17), nrf(0, 20), nrf(0, 22), nrf(0, 24), nrf(1, 0), nrf(0, 11), nrf(1, 4), nrf(1, 6),
+        nrf(0, 9), nrf(0, 10), nrf(1, 11), nrf(1, 13), nrf(1, 15), nrf(0, 2), nrf(0, 29), nrf(0, 31),
+    ],
+};
+
+/// Same as [`NICE_NANO`] except for TX and RX, which are P0.13 and P0.15. Follows the pin
+/// assignment of the BLE Micro Pro firmware by sekigon-gonnoc, which numbers the pins of the Pro
+/// Micro footprint in the order of [`ProMicroPin`]. Not yet checked against its schematic or on
+/// hardware.
+#[rustfmt::skip]
+pub const BLE_MICRO_PRO: ControllerPins = ControllerPins {
+    name: "BLE Micro Pro",
+    pins: [
+        nrf(0, 13), nrf(0, 15), nrf(0, 17), nrf(0, 20), nrf(0, 22), nrf(0, 24), nrf(1, 0), nrf(0, 11), nrf(1, 4), nrf(1, 6),
+        nrf(0, 9), nrf(0, 10), nrf(1, 11), nrf(1, 13), nrf(1, 15), nrf(0, 2), nrf(0, 29), nrf(0, 31),
+    ],
+};
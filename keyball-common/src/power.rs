@@ -0,0 +1,65 @@
+//! Idle tracking for builds which put the MCU to sleep.
+//!
+//! Every key press and ball motion of this half counts as activity. How the board goes to sleep
+//! once it is idle is up to the MCU crate; [`wait_for_idle`] only decides when.
+
+use core::cell::Cell;
+use core::sync::atomic::{AtomicU32, Ordering};
+
+use embassy_sync::blocking_mutex::{raw::CriticalSectionRawMutex, Mutex};
+use embassy_time::{Duration, Instant, Timer};
+
+pub const DEFAULT_SLEEP_TIMEOUT: Duration = Duration::from_secs(15 * 60);
+
+/// Interval at which the inhibit condition is polled while it holds.
+const INHIBIT_POLL_INTERVAL: Duration = Duration::from_secs(5);
+
+static LAST_ACTIVITY: Mutex<CriticalSectionRawMutex, Cell<Instant>> =
+    Mutex::new(Cell::new(Instant::from_ticks(0)));
+static SLEEP_TIMEOUT_SECS: AtomicU32 = AtomicU32::new(DEFAULT_SLEEP_TIMEOUT.as_secs() as u32);
+
+pub fn notify_activity() {
+    LAST_ACTIVITY.lock(|last| last.set(Instant::now()));
+}
+
+/// When this half last saw a key press or ball motion.
+pub fn last_activity() -> Instant {
+    LAST_ACTIVITY.lock(|last| last.get())
+}
+
+/// Sets how long this half must be idle before it goes to sleep. Zero disables sleep.
+pub fn set_sleep_timeout(timeout: Duration) {
+    SLEEP_TIMEOUT_SECS.store(timeout.as_secs() as u32, Ordering::Relaxed);
+}
+
+pub fn sleep_timeout() -> Duration {
+    Duration::from_secs(SLEEP_TIMEOUT_SECS.load(Ordering::Relaxed) as u64)
+}
+
+/// Returns once there was no activity for the sleep timeout and `inhibit` returns false.
+///
+/// While `inhibit` returns true, the idle timer is held at zero, so the full timeout has to pass
+/// again once it stops.
+pub async fn wait_for_idle(mut inhibit: impl FnMut() -> bool) {
+    loop {
+        if inhibit() {
+            notify_activity();
+            Timer::after(INHIBIT_POLL_INTERVAL).await;
+            continue;
+        }
+
+        let timeout = sleep_timeout();
+        if timeout.as_secs() == 0 {
+            Timer::after(INHIBIT_POLL_INTERVAL).await;
+            continue;
+        }
+
+        let deadline = last_activity() + timeout;
+        if Instant::now() >= deadline {
+            return;
+        }
+        // Activity while waiting moves the deadline, which is checked again after waking up. The
+        // wait is capped so that changes of the inhibit condition and the timeout are noticed.
+        Timer::at(deadline.min(Instant::now() + INHIBIT_POLL_INTERVAL)).await;
+    }
+}
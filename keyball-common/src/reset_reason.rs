@@ -0,0 +1,132 @@
+//! Why the half was reset last.
+//!
+//! The MCU crates read the reason from the hardware and pass it to [`set_from_hardware`] early after
+//! boot. Resets after a panic look like software resets to the hardware, or on the RP2040 even like
+//! the reset before, so the panic handler leaves a mark in RAM by which the reason is corrected to
+//! [`ResetReason::Panic`]. [`record`] then stores the reason and shows it on the display. The reason of the boot before stays readable by the host, so that it is not
+//! lost when the half is power cycled after a watchdog reset.
+
+use core::sync::atomic::{AtomicU8, Ordering};
+
+use rktk::drivers::interface::storage::StorageDriver;
+
+use crate::{display, retained::Mailbox, storage};
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum ResetReason {
+    PowerOn,
+    /// Reset pin or button.
+    Pin,
+    Watchdog,
+    Panic,
+    /// Reset requested by the firmware, e.g. to switch the bond slot.
+    Software,
+    BrownOut,
+    Lockup,
+    /// Wake-up from System OFF.
+    WakeUp,
+    Unknown,
+}
+
+impl ResetReason {
+    pub fn to_u8(self) -> u8 {
+        match self {
+            ResetReason::PowerOn => 0,
+            ResetReason::Pin => 1,
+            ResetReason::Watchdog => 2,
+            ResetReason::Panic => 3,
+            ResetReason::Software => 4,
+            ResetReason::BrownOut => 5,
+            ResetReason::Lockup => 6,
+            ResetReason::WakeUp => 7,
+            ResetReason::Unknown => 0xff,
+        }
+    }
+
+    pub fn from_u8(value: u8) -> Self {
+        match value {
+            0 => ResetReason::PowerOn,
+            1 => ResetReason::Pin,
+            2 => ResetReason::Watchdog,
+            3 => ResetReason::Panic,
+            4 => ResetReason::Software,
+            5 => ResetReason::BrownOut,
+            6 => ResetReason::Lockup,
+            7 => ResetReason::WakeUp,
+            _ => ResetReason::Unknown,
+        }
+    }
+
+    /// Short name for the display.
+    pub fn name(self) -> &'static str {
+        match self {
+            ResetReason::PowerOn => "POR",
+            ResetReason::Pin => "PIN",
+            ResetReason::Watchdog => "WDT",
+            ResetReason::Panic => "PANIC",
+            ResetReason::Software => "SW",
+            ResetReason::BrownOut => "BOD",
+            ResetReason::Lockup => "LOCKUP",
+            ResetReason::WakeUp => "WAKE",
+            ResetReason::Unknown => "?",
+        }
+    }
+}
+
+static REASON: AtomicU8 = AtomicU8::new(0xff);
+
+const PANICKED: u32 = 0x0000_dead;
+
+#[link_section = ".uninit.keyball.reset_reason"]
+static PANIC_MARK: Mailbox = Mailbox::new();
+
+pub fn set(reason: ResetReason) {
+    REASON.store(reason.to_u8(), Ordering::Relaxed);
+}
+
+/// Sets the reason read from the hardware, or [`ResetReason::Panic`] if the half panicked before
+/// this boot, and returns it.
+pub fn set_from_hardware(reason: ResetReason) -> ResetReason {
+    let reason = if PANIC_MARK.take() == Some(PANICKED) {
+        ResetReason::Panic
+    } else {
+        reason
+    };
+    set(reason);
+    reason
+}
+
+/// Marks the next boot as one after a panic. Called by
+/// [`crash_log::record_panic`](crate::crash_log::record_panic).
+pub(crate) fn mark_panic() {
+    PANIC_MARK.put(PANICKED);
+}
+
+pub fn current() -> ResetReason {
+    ResetReason::from_u8(REASON.load(Ordering::Relaxed))
+}
+
+static PREVIOUS: AtomicU8 = AtomicU8::new(0xff);
+
+/// Reason of the boot before this one.
+pub fn previous() -> ResetReason {
+    ResetReason::from_u8(PREVIOUS.load(Ordering::Relaxed))
+}
+
+/// Stores the reason of this boot and shows it on the display. Call it after
+/// [`crash_log::load`](crate::crash_log::load).
+pub async fn record<S: StorageDriver>(storage: &S) {
+    let mut buf = [0xff; 1];
+    if storage
+        .read::<1>(storage::RESET_REASON, &mut buf)
+        .await
+        .is_ok()
+    {
+        PREVIOUS.store(buf[0], Ordering::Relaxed);
+    }
+    let reason = current();
+    let _ = storage
+        .write::<1>(storage::RESET_REASON, &[reason.to_u8()])
+        .await;
+    display::update(|s| s.reset_reason = Some(reason));
+}
@@ -0,0 +1,77 @@
+//! Values handed over to the firmware after a software reset.
+//!
+//! A [`Mailbox`] must be placed in a `.uninit` section, which the runtime does not initialize, so
+//! that its content survives the reset. After power-on it holds garbage, which is told apart by a
+//! magic number and the complement of the value. A [`Block`] does the same for more than one word.
+
+use core::{cell::UnsafeCell, mem::MaybeUninit, ptr};
+
+const MAGIC: u32 = 0x4b42_4d42;
+
+pub struct Mailbox(UnsafeCell<MaybeUninit<[u32; 3]>>);
+
+// Only accessed with volatile reads and writes of the whole content, on a single core.
+unsafe impl Sync for Mailbox {}
+
+impl Mailbox {
+    #[allow(clippy::new_without_default)]
+    pub const fn new() -> Self {
+        Self(UnsafeCell::new(MaybeUninit::uninit()))
+    }
+
+    pub fn put(&self, value: u32) {
+        unsafe { ptr::write_volatile(self.0.get().cast(), [MAGIC, value, !value]) }
+    }
+
+    /// Returns the value put before the reset, if any, and empties the mailbox.
+    pub fn take(&self) -> Option<u32> {
+        let [magic, value, check] = unsafe { ptr::read_volatile(self.0.get().cast::<[u32; 3]>()) };
+        unsafe { ptr::write_volatile(self.0.get().cast(), [0u32; 3]) }
+        (magic == MAGIC && check == !value).then_some(value)
+    }
+}
+
+/// Like [`Mailbox`], but for a block of bytes. Garbage is told apart by a checksum.
+pub struct Block<const N: usize>(UnsafeCell<MaybeUninit<BlockContent<N>>>);
+
+#[derive(Clone, Copy)]
+#[repr(C)]
+struct BlockContent<const N: usize> {
+    magic: u32,
+    checksum: u32,
+    data: [u8; N],
+}
+
+// Same as for Mailbox.
+unsafe impl<const N: usize> Sync for Block<N> {}
+
+impl<const N: usize> Block<N> {
+    #[allow(clippy::new_without_default)]
+    pub const fn new() -> Self {
+        Self(UnsafeCell::new(MaybeUninit::uninit()))
+    }
+
+    pub fn put(&self, data: &[u8; N]) {
+        let content = BlockContent {
+            magic: MAGIC,
+            checksum: checksum(data),
+            data: *data,
+        };
+        unsafe { ptr::write_volatile(self.0.get().cast(), content) }
+    }
+
+    /// Returns the block put before the reset, if any, and empties it.
+    pub fn take(&self) -> Option<[u8; N]> {
+        let content = unsafe { ptr::read_volatile(self.0.get().cast::<BlockContent<N>>()) };
+        unsafe { ptr::write_volatile(self.0.get().cast::<u32>(), 0) }
+        (content.magic == MAGIC && content.checksum == checksum(&content.data))
+            .then_some(content.data)
+    }
+}
+
+/// FNV-1a.
+fn checksum(data: &[u8]) -> u32 {
+    data.iter().fold(0x811c_9dc5, |hash, byte| {
+        (hash ^ *byte as u32).wrapping_mul(0x0100_0193)
+    })
+}
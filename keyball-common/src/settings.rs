@@ -0,0 +1,453 @@
+//! Runtime settings, kept in sync between the halves.
+//!
+//! Each half stores the settings together with a revision. The revisions work like a Lamport
+//! clock: a change gets the revision after the highest one this half has seen from either half.
+//! Changes are sent to the other half at once, and both halves send their settings whenever the
+//! split link comes up. A half adopts received settings only if their revision is higher than its
+//! own, so a half which missed changes while it was off takes them over from the other half instead
+//! of bringing back the values it had stored, and a change always replaces the settings which the
+//! changing half had seen before. Only changes made on both halves while the link was down compete,
+//! and the halves then settle on the same one of them.
+//!
+//! The resolution of the ball is set per hand, for Keyball61 builds with a ball on both halves.
+//!
+//! rktk reads the timeout of the auto mouse layer from its own state config when it starts, so the
+//! timeout is written there and takes effect on the next start of the master. rktk stores the state
+//! config once it was set through its remote protocol, and uses the defaults of rktk.json until
+//! then.
+
+use core::cell::Cell;
+
+use embassy_sync::{
+    blocking_mutex::{raw::CriticalSectionRawMutex, Mutex},
+    signal::Signal,
+};
+use embassy_time::{Duration, Timer};
+use rktk::drivers::interface::{
+    keyscan::Hand,
+    mouse::{MouseDriver, MouseDriverBuilder},
+    storage::StorageDriver,
+};
+
+use rktk::{
+    config::storage_config::StorageConfigManager,
+    keymanager::state::config::{KeyResolverConfig, MouseConfig, Output, StateConfig},
+};
+
+use crate::{
+    backlight::{self, reactive::ReactiveMode},
+    display, hooks, power,
+    split::{self, KeyballMessage},
+    storage, KEYMAP,
+};
+
+/// Version of the encoding. Bump it together with
+/// [`PROTOCOL_VERSION`](crate::split::handshake::PROTOCOL_VERSION) whenever it changes. Stored
+/// settings of another version are replaced by the defaults.
+pub const VERSION: u8 = 1;
+
+pub const ENCODED_LEN: usize = 16;
+
+pub const DEFAULT_CPI: u16 = 500;
+pub const MIN_CPI: u16 = 100;
+pub const MAX_CPI: u16 = 12000;
+pub const CPI_STEP: u16 = 100;
+
+/// Display timeouts selectable with [`OLED_TO`](crate::keycode::OLED_TO), in seconds. Zero keeps
+/// the display on.
+pub const OLED_TIMEOUTS: [u16; 4] = [0, 30, 60, 300];
+
+/// Timeouts of the auto mouse layer selectable with [`AML_TO`](crate::keycode::AML_TO), in
+/// milliseconds. The default is the one of rktk.
+pub const AUTO_MOUSE_TIMEOUTS: [u16; 4] = [300, 500, 1000, 2000];
+pub const DEFAULT_AUTO_MOUSE_MS: u16 = 500;
+
+/// Sleep timeouts selectable with [`SLP_TO`](crate::keycode::SLP_TO), in minutes. Zero keeps the
+/// board awake.
+pub const SLEEP_TIMEOUTS: [u16; 4] = [0, 5, 15, 60];
+pub const DEFAULT_SLEEP_MINS: u16 = (power::DEFAULT_SLEEP_TIMEOUT.as_secs() / 60) as u16;
+
+/// Changes are written to storage once no further change came for this long.
+const SAVE_DELAY: Duration = Duration::from_secs(1);
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct Settings {
+    /// Resolution of the ball of the left and the right half.
+    pub cpi: [u16; 2],
+    pub rgb_mode: ReactiveMode,
+    /// Time without activity after which the display is blanked, in seconds. Zero keeps it on.
+    pub oled_timeout_secs: u16,
+    /// Time without ball movement after which the auto mouse layer is left, in milliseconds.
+    pub auto_mouse_ms: u16,
+    /// Time without activity after which builds which can sleep go to sleep, in minutes. Zero
+    /// keeps them awake.
+    pub sleep_timeout_mins: u16,
+}
+
+impl Settings {
+    pub const DEFAULT: Settings = Settings {
+        cpi: [DEFAULT_CPI; 2],
+        rgb_mode: ReactiveMode::Ripple,
+        oled_timeout_secs: 0,
+        auto_mouse_ms: DEFAULT_AUTO_MOUSE_MS,
+        sleep_timeout_mins: DEFAULT_SLEEP_MINS,
+    };
+
+    /// Changes the resolution of both balls by `steps` of [`CPI_STEP`].
+    pub fn step_cpi(&mut self, steps: i16) {
+        for cpi in &mut self.cpi {
+            let stepped = *cpi as i32 + steps as i32 * CPI_STEP as i32;
+            *cpi = stepped.clamp(MIN_CPI as i32, MAX_CPI as i32) as u16;
+        }
+    }
+
+    /// Selects the next of [`OLED_TIMEOUTS`].
+    pub fn next_oled_timeout(&mut self) {
+        let next = OLED_TIMEOUTS
+            .iter()
+            .position(|secs| *secs == self.oled_timeout_secs)
+            .map_or(0, |i| (i + 1) % OLED_TIMEOUTS.len());
+        self.oled_timeout_secs = OLED_TIMEOUTS[next];
+    }
+
+    /// Selects the next of [`AUTO_MOUSE_TIMEOUTS`].
+    pub fn next_auto_mouse_timeout(&mut self) {
+        let next = AUTO_MOUSE_TIMEOUTS
+            .iter()
+            .position(|ms| *ms == self.auto_mouse_ms)
+            .map_or(0, |i| (i + 1) % AUTO_MOUSE_TIMEOUTS.len());
+        self.auto_mouse_ms = AUTO_MOUSE_TIMEOUTS[next];
+    }
+
+    /// Selects the next of [`SLEEP_TIMEOUTS`].
+    pub fn next_sleep_timeout(&mut self) {
+        let next = SLEEP_TIMEOUTS
+            .iter()
+            .position(|mins| *mins == self.sleep_timeout_mins)
+            .map_or(0, |i| (i + 1) % SLEEP_TIMEOUTS.len());
+        self.sleep_timeout_mins = SLEEP_TIMEOUTS[next];
+    }
+}
+
+/// Settings as of a revision.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct Revision {
+    pub revision: u32,
+    pub settings: Settings,
+}
+
+impl Revision {
+    /// [`VERSION`], the revision and the settings, with numbers in little endian.
+    pub fn encode(&self) -> [u8; ENCODED_LEN] {
+        let s = &self.settings;
+        let mut buf = [0; ENCODED_LEN];
+        buf[0] = VERSION;
+        buf[1..5].copy_from_slice(&self.revision.to_le_bytes());
+        buf[5..7].copy_from_slice(&s.cpi[0].to_le_bytes());
+        buf[7..9].copy_from_slice(&s.cpi[1].to_le_bytes());
+        buf[9] = s.rgb_mode.to_u8();
+        buf[10..12].copy_from_slice(&s.oled_timeout_secs.to_le_bytes());
+        buf[12..14].copy_from_slice(&s.auto_mouse_ms.to_le_bytes());
+        buf[14..16].copy_from_slice(&s.sleep_timeout_mins.to_le_bytes());
+        buf
+    }
+
+    /// Decodes settings of the current [`VERSION`].
+    pub fn decode(buf: &[u8]) -> Option<Self> {
+        let buf: &[u8; ENCODED_LEN] = buf.get(..ENCODED_LEN)?.try_into().ok()?;
+        if buf[0] != VERSION {
+            return None;
+        }
+        let u16_at = |i: usize| u16::from_le_bytes([buf[i], buf[i + 1]]);
+        Some(Self {
+            revision: u32::from_le_bytes([buf[1], buf[2], buf[3], buf[4]]),
+            settings: Settings {
+                cpi: [u16_at(5), u16_at(7)],
+                rgb_mode: ReactiveMode::from_u8(buf[9])?,
+                oled_timeout_secs: u16_at(10),
+                auto_mouse_ms: u16_at(12),
+                sleep_timeout_mins: u16_at(14),
+            },
+        })
+    }
+
+    /// Whether these settings replace `other`. Halves which changed the settings independently to
+    /// the same revision settle on the same one of them.
+    pub fn supersedes(&self, other: &Revision) -> bool {
+        (self.revision, self.encode()) > (other.revision, other.encode())
+    }
+}
+
+static CURRENT: Mutex<CriticalSectionRawMutex, Cell<Revision>> = Mutex::new(Cell::new(Revision {
+    revision: 0,
+    settings: Settings::DEFAULT,
+}));
+static CHANGED: Signal<CriticalSectionRawMutex, ()> = Signal::new();
+/// Highest revision received from the other half.
+static SEEN: Mutex<CriticalSectionRawMutex, Cell<u32>> = Mutex::new(Cell::new(0));
+
+pub fn current() -> Revision {
+    CURRENT.lock(|current| current.get())
+}
+
+/// Loads the stored settings and applies them. Must be called before [`run`].
+pub async fn load<S: StorageDriver>(storage: &S) {
+    let mut buf = [0; ENCODED_LEN];
+    let stored = match storage
+        .read::<ENCODED_LEN>(storage::SETTINGS, &mut buf)
+        .await
+    {
+        Ok(()) => Revision::decode(&buf),
+        Err(_) => None,
+    };
+    if let Some(stored) = stored {
+        CURRENT.lock(|current| current.set(stored));
+    }
+    apply(&current().settings);
+    write_auto_mouse_timeout(storage).await;
+}
+
+/// Changes the settings of both halves.
+pub fn change(f: impl FnOnce(&mut Settings)) {
+    let changed = CURRENT.lock(|current| {
+        let mut new = current.get();
+        f(&mut new.settings);
+        if new.settings == current.get().settings {
+            return None;
+        }
+        new.revision = new.revision.max(SEEN.lock(Cell::get)).wrapping_add(1);
+        current.set(new);
+        Some(new)
+    });
+    if let Some(new) = changed {
+        apply(&new.settings);
+        CHANGED.signal(());
+        split::send_to_other_half(KeyballMessage::Settings(new));
+    }
+}
+
+/// Sends the settings to the other half, whose link just came up.
+pub(crate) fn link_up() {
+    split::send_to_other_half(KeyballMessage::Settings(current()));
+}
+
+/// Handles the settings of the other half. Adopts them if they are newer, and answers with the own
+/// ones if those are.
+pub(crate) fn received(remote: Revision) {
+    SEEN.lock(|seen| seen.set(seen.get().max(remote.revision)));
+    let local = current();
+    if remote.supersedes(&local) {
+        CURRENT.lock(|current| current.set(remote));
+        apply(&remote.settings);
+        CHANGED.signal(());
+    } else if local.supersedes(&remote) {
+        split::send_to_other_half(KeyballMessage::Settings(local));
+    }
+}
+
+/// Resolution of the ball of this half. `None` until the hand is known.
+fn cpi() -> Option<u16> {
+    let [left, right] = current().settings.cpi;
+    match hooks::hand()? {
+        Hand::Left => Some(left),
+        Hand::Right => Some(right),
+    }
+}
+
+fn apply(settings: &Settings) {
+    backlight::set_mode(settings.rgb_mode);
+    display::set_timeout(Duration::from_secs(settings.oled_timeout_secs as u64));
+    power::set_sleep_timeout(Duration::from_secs(settings.sleep_timeout_mins as u64 * 60));
+}
+
+/// Writes changed settings to storage.
+pub async fn run<S: StorageDriver>(storage: &S) -> ! {
+    loop {
+        CHANGED.wait().await;
+        Timer::after(SAVE_DELAY).await;
+        CHANGED.reset();
+        let _ = storage
+            .write::<ENCODED_LEN>(storage::SETTINGS, &current().encode())
+            .await;
+        write_auto_mouse_timeout(storage).await;
+    }
+}
+
+/// Writes the auto mouse timeout of the settings into the state config of rktk, unless it is there
+/// already. rktk reads the state config only at boot, so a new timeout takes effect on the next
+/// one. A state config is written if none is stored, as rktk would not take the timeout otherwise.
+async fn write_auto_mouse_timeout<S: StorageDriver>(storage: &S) {
+    let config = StorageConfigManager::new(storage::Shared(storage));
+    let duration = current().settings.auto_mouse_ms as u32;
+    let state = match config.read_state_config().await {
+        Ok(state) if state.mouse.auto_mouse_duration == duration => return,
+        Ok(mut state) => {
+            state.mouse.auto_mouse_duration = duration;
+            state
+        }
+        Err(_) => default_state_config(duration),
+    };
+    let _ = config.write_state_config(&state).await;
+}
+
+/// The state config which rktk uses if none is stored, with the auto mouse timeout `duration`. The
+/// values are the defaults of rktk, which `rktk.json` keeps.
+fn default_state_config(duration: u32) -> StateConfig {
+    StateConfig {
+        mouse: MouseConfig {
+            auto_mouse_layer: 1,
+            auto_mouse_duration: duration,
+            auto_mouse_threshold: 1,
+            scroll_divider_x: 20,
+            scroll_divider_y: -12,
+        },
+        key_resolver: KeyResolverConfig {
+            tap_threshold: 200,
+            tap_dance_threshold: 100,
+            tap_dance: KEYMAP.tap_dance.clone(),
+        },
+        initial_output: Output::Usb,
+    }
+}
+
+/// Ball driver builder which makes the ball follow the resolution of the settings.
+pub struct SettingsMouseBuilder<B: MouseDriverBuilder>(pub B);
+
+impl<B: MouseDriverBuilder> MouseDriverBuilder for SettingsMouseBuilder<B> {
+    type Output = SettingsMouse<B::Output>;
+    type Error = B::Error;
+
+    async fn build(self) -> Result<Self::Output, Self::Error> {
+        Ok(SettingsMouse {
+            inner: self.0.build().await?,
+            applied: None,
+        })
+    }
+}
+
+pub struct SettingsMouse<M: MouseDriver> {
+    inner: M,
+    /// Resolution last set from the settings.
+    applied: Option<u16>,
+}
+
+impl<M: MouseDriver> MouseDriver for SettingsMouse<M> {
+    type Error = M::Error;
+
+    async fn read(&mut self) -> Result<(i8, i8), Self::Error> {
+        if let Some(cpi) = cpi().filter(|cpi| self.applied != Some(*cpi)) {
+            self.inner.set_cpi(cpi).await?;
+            self.applied = Some(cpi);
+        }
+        self.inner.read().await
+    }
+
+    async fn set_cpi(&mut self, cpi: u16) -> Result<(), Self::Error> {
+        self.inner.set_cpi(cpi).await
+    }
+
+    async fn get_cpi(&mut self) -> Result<u16, Self::Error> {
+        self.inner.get_cpi().await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn revision(revision: u32, cpi: u16) -> Revision {
+        Revision {
+            revision,
+            settings: Settings {
+                cpi: [cpi; 2],
+                ..Settings::DEFAULT
+            },
+        }
+    }
+
+    #[test]
+    fn revisions_round_trip() {
+        let revision = Revision {
+            revision: 0x0102_0304,
+            settings: Settings {
+                cpi: [800, 1200],
+                rgb_mode: ReactiveMode::Ripple,
+                oled_timeout_secs: 60,
+                auto_mouse_ms: 1000,
+                sleep_timeout_mins: 5,
+            },
+        };
+        assert_eq!(Revision::decode(&revision.encode()), Some(revision));
+    }
+
+    #[test]
+    fn other_versions_and_short_buffers_are_rejected() {
+        let mut buf = revision(1, 500).encode();
+        assert_eq!(Revision::decode(&buf[..ENCODED_LEN - 1]), None);
+        buf[0] = VERSION + 1;
+        assert_eq!(Revision::decode(&buf), None);
+    }
+
+    #[test]
+    fn higher_revisions_supersede() {
+        assert!(revision(2, 500).supersedes(&revision(1, 600)));
+        assert!(!revision(1, 600).supersedes(&revision(2, 500)));
+        assert!(!revision(1, 500).supersedes(&revision(1, 500)));
+
+        // Independent changes to the same revision are settled the same way on both halves.
+        let (a, b) = (revision(3, 500), revision(3, 600));
+        assert_ne!(a.supersedes(&b), b.supersedes(&a));
+    }
+
+    #[test]
+    fn changes_supersede_everything_seen() {
+        // The other half is far ahead, but has older settings than this half.
+        received(revision(10, 700));
+        assert_eq!(current(), revision(10, 700));
+        received(revision(5, 300));
+        assert_eq!(current(), revision(10, 700));
+
+        change(|s| s.cpi = [900; 2]);
+        assert_eq!(current(), revision(11, 900));
+        assert!(current().supersedes(&revision(10, 1200)));
+    }
+
+    #[test]
+    fn cpi_steps_are_clamped() {
+        let mut settings = Settings::DEFAULT;
+        settings.step_cpi(-10);
+        assert_eq!(settings.cpi, [MIN_CPI; 2]);
+        settings.step_cpi(1000);
+        assert_eq!(settings.cpi, [MAX_CPI; 2]);
+    }
+
+    #[test]
+    fn timeouts_cycle() {
+        let mut settings = Settings::DEFAULT;
+        for secs in OLED_TIMEOUTS
+            .iter()
+            .cycle()
+            .skip(1)
+            .take(OLED_TIMEOUTS.len() + 1)
+        {
+            settings.next_oled_timeout();
+            assert_eq!(settings.oled_timeout_secs, *secs);
+        }
+        let first = AUTO_MOUSE_TIMEOUTS
+            .iter()
+            .position(|ms| *ms == DEFAULT_AUTO_MOUSE_MS)
+            .unwrap();
+        for ms in AUTO_MOUSE_TIMEOUTS.iter().cycle().skip(first + 1).take(4) {
+            settings.next_auto_mouse_timeout();
+            assert_eq!(settings.auto_mouse_ms, *ms);
+        }
+        let first = SLEEP_TIMEOUTS
+            .iter()
+            .position(|mins| *mins == DEFAULT_SLEEP_MINS)
+            .unwrap();
+        for mins in SLEEP_TIMEOUTS.iter().cycle().skip(first + 1).take(4) {
+            settings.next_sleep_timeout();
+            assert_eq!(settings.sleep_timeout_mins, *mins);
+        }
+    }
+}
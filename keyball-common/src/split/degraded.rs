@@ -0,0 +1,354 @@
+//! Operation of a single half while the split link is down.
+//!
+//! The link counts as lost once nothing arrived for [`LINK_TIMEOUT`], also right after boot. While
+//! it is lost, each half keeps running on its own input: frames for the other half are sent without
+//! caring whether they arrive, the display shows that the link is lost, and the master remaps its
+//! keys by [`ONE_HANDED`](crate::ONE_HANDED). The master also holds
+//! [`ONE_HANDED_LAYER`](crate::ONE_HANDED_LAYER) by pressing [`LAYER_KEY`], which has no switch,
+//! and releases it when the link is back.
+//!
+//! When the link is lost, the master releases the keys of the slave which it saw pressed, as their
+//! releases may never arrive. Once the link is back, the master sends its layer, and the slave
+//! reports the current state of every key which is pressed or whose last change was not
+//! acknowledged by the master, so that both agree on the keys again. Sends which fail while the link
+//! is up request the same.
+//!
+//! Key events of the slave are rktk frames, a COBS encoded postcard `(usize, SlaveToMaster)` of
+//! which the `usize` counts the frames.
+
+use core::{
+    cell::Cell,
+    sync::atomic::{AtomicBool, AtomicU8, AtomicUsize, Ordering},
+};
+
+use embassy_sync::blocking_mutex::{raw::CriticalSectionRawMutex, Mutex};
+use embassy_time::Instant;
+use rktk::drivers::interface::{keyscan::KeyChangeEvent, split::SlaveToMaster};
+
+use super::{health::LINK_TIMEOUT, send_to_other_half, KeyballMessage, MAX_FRAME_SIZE};
+use crate::{display, layout::ROWS, ONE_HANDED_LAYER};
+
+/// Remaps a key of the keymap to another position of the keymap.
+pub type Remap = ((u8, u8), (u8, u8));
+
+/// Number of remaps which are tracked while pressed.
+pub const MAX_REMAPS: usize = 32;
+
+/// Position within either half without a switch, at which the keymap holds
+/// [`ONE_HANDED_LAYER`](crate::ONE_HANDED_LAYER).
+pub const LAYER_KEY: (u8, u8) = (0, 6);
+
+static LOST: AtomicBool = AtomicBool::new(false);
+static HOLD_LAYER: AtomicBool = AtomicBool::new(false);
+static LAYER_KEY_PRESSED: AtomicBool = AtomicBool::new(false);
+static RESYNC: AtomicBool = AtomicBool::new(false);
+static LAYER: AtomicU8 = AtomicU8::new(0);
+/// Remaps whose key is pressed, by index.
+static REMAPPED: Mutex<CriticalSectionRawMutex, Cell<u32>> = Mutex::new(Cell::new(0));
+
+/// Keys of a half by their position within the half, one bit per column.
+pub(crate) type KeySet = [u16; ROWS];
+type SharedKeySet = Mutex<CriticalSectionRawMutex, Cell<KeySet>>;
+
+/// Debounced state of the keys of this half.
+static PRESSED: SharedKeySet = Mutex::new(Cell::new([0; ROWS]));
+/// Keys of this half whose last change was not acknowledged by the master yet.
+static UNCONFIRMED: SharedKeySet = Mutex::new(Cell::new([0; ROWS]));
+/// Keys of the slave which the master saw pressed.
+static PEER_PRESSED: SharedKeySet = Mutex::new(Cell::new([0; ROWS]));
+/// Number of the last frame received from the slave, which rktk expects to increase.
+static PEER_FRAME_ID: AtomicUsize = AtomicUsize::new(0);
+
+/// Whether this half runs on its own.
+pub fn is_lost() -> bool {
+    LOST.load(Ordering::Relaxed)
+}
+
+/// Updates the state from the health of the link.
+pub(crate) fn update(up: bool, is_master: bool) {
+    let lost = !up && Instant::now() >= Instant::from_ticks(0) + LINK_TIMEOUT;
+    if lost == is_lost() {
+        return;
+    }
+    LOST.store(lost, Ordering::Relaxed);
+    HOLD_LAYER.store(
+        lost && is_master && ONE_HANDED_LAYER.is_some(),
+        Ordering::Relaxed,
+    );
+    display::update(|s| s.split_lost = lost);
+    if lost {
+        return;
+    }
+    if is_master {
+        send_to_other_half(KeyballMessage::Layer(LAYER.load(Ordering::Relaxed)));
+    } else {
+        // The master released them when it lost the link.
+        let pressed = PRESSED.lock(Cell::get);
+        UNCONFIRMED.lock(|keys| {
+            let mut new = keys.get();
+            new.iter_mut()
+                .zip(pressed)
+                .for_each(|(keys, pressed)| *keys |= pressed);
+            keys.set(new);
+        });
+        RESYNC.store(true, Ordering::Relaxed);
+    }
+}
+
+/// Returns the change of [`LAYER_KEY`] to report, if any.
+pub(crate) fn layer_key_change() -> Option<bool> {
+    let hold = HOLD_LAYER.load(Ordering::Relaxed);
+    (LAYER_KEY_PRESSED.swap(hold, Ordering::Relaxed) != hold).then_some(hold)
+}
+
+/// Records the highest active layer of the master and passes it on to the slave.
+pub(crate) fn set_layer(layer: u8) {
+    if LAYER.load(Ordering::Relaxed) == layer {
+        return;
+    }
+    LAYER.store(layer, Ordering::Relaxed);
+    if !is_lost() {
+        send_to_other_half(KeyballMessage::Layer(layer));
+    }
+}
+
+/// Whether the keys of [`unconfirmed`] have to be reported again. Clears the request.
+pub(crate) fn take_resync() -> bool {
+    let resync = RESYNC.load(Ordering::Relaxed);
+    if resync {
+        RESYNC.store(false, Ordering::Relaxed);
+    }
+    resync
+}
+
+/// Records a change of a key of this half which is passed on to rktk.
+pub(crate) fn key_reported(row: u8, col: u8, pressed: bool) {
+    set_key(&PRESSED, row, col, pressed);
+    set_key(&UNCONFIRMED, row, col, true);
+}
+
+/// Keys of this half whose last change was not acknowledged by the master.
+pub(crate) fn unconfirmed() -> KeySet {
+    UNCONFIRMED.lock(Cell::get)
+}
+
+/// Records that the slave sent the rktk `frame` and the master acknowledged it.
+pub(crate) fn frame_confirmed(frame: &[u8]) {
+    let Some((row, col, pressed)) = decode(frame).and_then(|(_, message)| key_event(message))
+    else {
+        return;
+    };
+    // Otherwise a later change of the key is still on its way.
+    if is_set(&PRESSED, row, col) == pressed {
+        set_key(&UNCONFIRMED, row, col, false);
+    }
+}
+
+/// Records that a frame of the slave may not have arrived although the link is up.
+pub(crate) fn send_failed() {
+    RESYNC.store(true, Ordering::Relaxed);
+}
+
+/// Records the rktk `frame` which the master received from the slave.
+pub(crate) fn peer_frame_received(frame: &[u8]) {
+    let Some((id, message)) = decode(frame) else {
+        return;
+    };
+    PEER_FRAME_ID.store(id, Ordering::Relaxed);
+    if let Some((row, col, pressed)) = key_event(message) {
+        set_key(&PEER_PRESSED, row, col, pressed);
+    }
+}
+
+/// Writes the release of a key of the slave, which the master saw pressed, as an rktk frame into
+/// `buf` while the link is lost. Returns `false` if there is none.
+pub(crate) fn take_peer_release(buf: &mut [u8]) -> bool {
+    if !is_lost() {
+        return false;
+    }
+    let released = PEER_PRESSED.lock(|keys| {
+        let mut new = keys.get();
+        let (row, pressed) = new.iter_mut().enumerate().find(|(_, keys)| **keys != 0)?;
+        let col = pressed.trailing_zeros();
+        *pressed &= !(1 << col);
+        keys.set(new);
+        Some((row as u8, col as u8))
+    });
+    let Some((row, col)) = released else {
+        return false;
+    };
+    // The same number as the last frame, so that rktk does not count a frame as missing.
+    let id = PEER_FRAME_ID.load(Ordering::Relaxed);
+    postcard::to_slice_cobs(&(id, SlaveToMaster::Released(row, col)), buf).is_ok()
+}
+
+fn set_key(keys: &SharedKeySet, row: u8, col: u8, set: bool) {
+    if row as usize >= ROWS || col >= 16 {
+        return;
+    }
+    keys.lock(|keys| {
+        let mut new = keys.get();
+        let bit = 1 << col;
+        new[row as usize] = if set {
+            new[row as usize] | bit
+        } else {
+            new[row as usize] & !bit
+        };
+        keys.set(new);
+    });
+}
+
+fn is_set(keys: &SharedKeySet, row: u8, col: u8) -> bool {
+    row < ROWS as u8 && col < 16 && keys.lock(Cell::get)[row as usize] & (1 << col) != 0
+}
+
+/// Decodes an rktk frame of the slave into its number and message.
+fn decode(frame: &[u8]) -> Option<(usize, SlaveToMaster)> {
+    let mut buf = [0; MAX_FRAME_SIZE];
+    let len = frame.len().min(MAX_FRAME_SIZE);
+    buf[..len].copy_from_slice(&frame[..len]);
+    postcard::from_bytes_cobs(&mut buf[..len]).ok()
+}
+
+/// Position and state of the key of a key event.
+fn key_event(message: SlaveToMaster) -> Option<(u8, u8, bool)> {
+    match message {
+        SlaveToMaster::Pressed(row, col) => Some((row, col, true)),
+        SlaveToMaster::Released(row, col) => Some((row, col, false)),
+        _ => None,
+    }
+}
+
+/// Applies `remaps` to a key event of the master. A remapped press is released at the same
+/// position, even if the link came back in between.
+pub(crate) fn remap(remaps: &[Remap], event: &mut KeyChangeEvent) {
+    let position = (event.row, event.col);
+    let Some(index) = remaps
+        .iter()
+        .take(MAX_REMAPS)
+        .position(|(from, _)| *from == position)
+    else {
+        return;
+    };
+    let bit = 1 << index;
+    let remapped = REMAPPED.lock(|remapped| {
+        let active = remapped.get();
+        let remap = if event.pressed {
+            is_lost()
+        } else {
+            active & bit != 0
+        };
+        remapped.set(if remap && event.pressed {
+            active | bit
+        } else {
+            active & !bit
+        });
+        remap
+    });
+    if remapped {
+        (event.row, event.col) = remaps[index].1;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Mutex;
+
+    use super::*;
+
+    /// Serializes the tests which lose the link.
+    static LINK: Mutex<()> = Mutex::new(());
+
+    fn frame(id: usize, message: SlaveToMaster, buf: &mut [u8; MAX_FRAME_SIZE]) -> &[u8] {
+        postcard::to_slice_cobs(&(id, message), buf).unwrap()
+    }
+
+    #[test]
+    fn keys_of_a_lost_slave_are_released() {
+        let _link = LINK.lock().unwrap();
+        let mut buf = [0; MAX_FRAME_SIZE];
+        peer_frame_received(frame(4, SlaveToMaster::Pressed(1, 2), &mut buf));
+        peer_frame_received(frame(5, SlaveToMaster::Pressed(3, 0), &mut buf));
+        peer_frame_received(frame(6, SlaveToMaster::Released(3, 0), &mut buf));
+        peer_frame_received(frame(7, SlaveToMaster::Mouse { x: 1, y: 1 }, &mut buf));
+        assert!(!take_peer_release(&mut buf));
+
+        LOST.store(true, Ordering::Relaxed);
+        assert!(take_peer_release(&mut buf));
+        let released = postcard::from_bytes_cobs::<(usize, SlaveToMaster)>(&mut buf).unwrap();
+        assert!(matches!(released, (7, SlaveToMaster::Released(1, 2))));
+        assert!(!take_peer_release(&mut buf));
+        LOST.store(false, Ordering::Relaxed);
+    }
+
+    #[test]
+    fn changes_are_confirmed_once_the_last_one_is_sent() {
+        let mut buf = [0; MAX_FRAME_SIZE];
+        key_reported(2, 5, true);
+        key_reported(2, 5, false);
+        assert_ne!(unconfirmed()[2] & 1 << 5, 0);
+
+        frame_confirmed(frame(0, SlaveToMaster::Pressed(2, 5), &mut buf));
+        assert_ne!(unconfirmed()[2] & 1 << 5, 0);
+        frame_confirmed(frame(1, SlaveToMaster::Released(2, 5), &mut buf));
+        assert_eq!(unconfirmed()[2] & 1 << 5, 0);
+    }
+
+    #[test]
+    fn positions_outside_of_the_half_are_ignored() {
+        key_reported(ROWS as u8, 0, true);
+        key_reported(0, 16, true);
+        assert!(!is_set(&PRESSED, ROWS as u8, 0));
+        assert!(!is_set(&PRESSED, 0, 16));
+    }
+
+    fn remapped(remaps: &[Remap], row: u8, col: u8, pressed: bool) -> (u8, u8) {
+        let mut event = KeyChangeEvent { row, col, pressed };
+        remap(remaps, &mut event);
+        (event.row, event.col)
+    }
+
+    #[test]
+    fn keys_are_remapped_while_lost() {
+        let _link = LINK.lock().unwrap();
+        let remaps = [((4, 5), (4, 7)), ((4, 6), (4, 8))];
+        assert_eq!(remapped(&remaps, 4, 5, true), (4, 5));
+        assert_eq!(remapped(&remaps, 4, 5, false), (4, 5));
+
+        LOST.store(true, Ordering::Relaxed);
+        assert_eq!(remapped(&remaps, 4, 5, true), (4, 7));
+        assert_eq!(remapped(&remaps, 4, 6, true), (4, 8));
+        assert_eq!(remapped(&remaps, 4, 6, false), (4, 8));
+        assert_eq!(remapped(&remaps, 3, 5, true), (3, 5));
+
+        // Released where it was pressed, although the link is back.
+        LOST.store(false, Ordering::Relaxed);
+        assert_eq!(remapped(&remaps, 4, 5, false), (4, 7));
+        assert_eq!(remapped(&remaps, 4, 5, true), (4, 5));
+        assert_eq!(remapped(&remaps, 4, 5, false), (4, 5));
+    }
+
+    #[test]
+    fn remaps_beyond_the_tracked_ones_are_ignored() {
+        let _link = LINK.lock().unwrap();
+        let mut remaps = [((0, 0), (0, 1)); MAX_REMAPS + 1];
+        remaps[MAX_REMAPS] = ((2, 2), (2, 3));
+
+        LOST.store(true, Ordering::Relaxed);
+        assert_eq!(remapped(&remaps, 2, 2, true), (2, 2));
+        LOST.store(false, Ordering::Relaxed);
+        assert_eq!(remapped(&remaps, 2, 2, false), (2, 2));
+    }
+
+    #[test]
+    fn the_layer_key_is_held_while_the_layer_is() {
+        let _link = LINK.lock().unwrap();
+        assert_eq!(layer_key_change(), None);
+        HOLD_LAYER.store(true, Ordering::Relaxed);
+        assert_eq!(layer_key_change(), Some(true));
+        assert_eq!(layer_key_change(), None);
+        HOLD_LAYER.store(false, Ordering::Relaxed);
+        assert_eq!(layer_key_change(), Some(false));
+        assert_eq!(layer_key_change(), None);
+    }
+}
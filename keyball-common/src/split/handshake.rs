@@ -0,0 +1,297 @@
+//! Handshake which tells each half what firmware the other half runs.
+//!
+//! Whenever the link comes up, each half sends a [`KeyballMessage::Hello`](super::KeyballMessage)
+//! with its [`Profile`] and asks for the one of the other half. Halves of different boards, such as
+//! an RP2040 left half and an nRF52840 right half, work together as long as the protocol version and
+//! the features in [`MUST_MATCH`] are the same. Otherwise the mismatch is shown on the display.
+//!
+//! The encoding of the hello message must stay the same across protocol versions, so that
+//! mismatching halves can still tell each other apart. It is also sent outside of the
+//! [`reliable`](super::reliable) framing, so that halves which differ in [`feature::RELIABLE_LINK`]
+//! can read it. As such a frame may get lost, the hello is repeated until the profile of the other
+//! half arrived. If it does not arrive within [`TIMEOUT`], e.g. because the other half runs a
+//! firmware without the handshake, that is shown as [`Mismatch::NoHandshake`].
+
+use core::cell::Cell;
+
+use embassy_sync::blocking_mutex::{raw::CriticalSectionRawMutex, Mutex};
+use embassy_time::{Duration, Instant};
+
+use crate::display;
+
+/// Version of the messages exchanged between the halves. Bump it whenever their encoding changes.
+pub const PROTOCOL_VERSION: u8 = 1;
+
+pub const ENCODED_LEN: usize = 4;
+
+/// Time after the link came up within which the profile of the other half has to arrive.
+pub const TIMEOUT: Duration = Duration::from_secs(2);
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Board {
+    Rp2040,
+    Nrf52840,
+    /// A board added by a later version.
+    Other(u8),
+}
+
+impl Board {
+    fn to_u8(self) -> u8 {
+        match self {
+            Board::Rp2040 => 0,
+            Board::Nrf52840 => 1,
+            Board::Other(id) => id,
+        }
+    }
+
+    fn from_u8(value: u8) -> Self {
+        match value {
+            0 => Board::Rp2040,
+            1 => Board::Nrf52840,
+            id => Board::Other(id),
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Board::Rp2040 => "RP2040",
+            Board::Nrf52840 => "nRF52840",
+            Board::Other(_) => "?",
+        }
+    }
+}
+
+/// Features of a build, as bits of [`Profile::features`].
+pub mod feature {
+    pub const USB: u16 = 1 << 0;
+    pub const BLE: u16 = 1 << 1;
+    /// The split link runs over BLE.
+    pub const BLE_SPLIT: u16 = 1 << 2;
+    /// The wired split link uses [`reliable`](crate::split::reliable) framing.
+    pub const RELIABLE_LINK: u16 = 1 << 3;
+    pub const HAND_STRAP: u16 = 1 << 4;
+}
+
+/// Features which change how the halves talk to each other.
+pub const MUST_MATCH: u16 = feature::BLE_SPLIT | feature::RELIABLE_LINK;
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct Profile {
+    pub protocol: u8,
+    pub board: Board,
+    pub features: u16,
+}
+
+impl Profile {
+    pub const fn new(board: Board, features: u16) -> Self {
+        Self {
+            protocol: PROTOCOL_VERSION,
+            board,
+            features,
+        }
+    }
+
+    pub fn encode(&self) -> [u8; ENCODED_LEN] {
+        let [lo, hi] = self.features.to_le_bytes();
+        [self.protocol, self.board.to_u8(), lo, hi]
+    }
+
+    pub fn decode(buf: &[u8]) -> Option<Self> {
+        let [protocol, board, lo, hi, ..] = *buf else {
+            return None;
+        };
+        Some(Self {
+            protocol,
+            board: Board::from_u8(board),
+            features: u16::from_le_bytes([lo, hi]),
+        })
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Mismatch {
+    Protocol {
+        own: u8,
+        peer: u8,
+    },
+    /// Features of [`MUST_MATCH`] which only one half has.
+    Features(u16),
+    /// The other half did not send its profile within [`TIMEOUT`].
+    NoHandshake,
+}
+
+/// Checks whether two halves can work together.
+pub fn check(own: &Profile, peer: &Profile) -> Option<Mismatch> {
+    if own.protocol != peer.protocol {
+        return Some(Mismatch::Protocol {
+            own: own.protocol,
+            peer: peer.protocol,
+        });
+    }
+    let differing = (own.features ^ peer.features) & MUST_MATCH;
+    (differing != 0).then_some(Mismatch::Features(differing))
+}
+
+static LOCAL: Mutex<CriticalSectionRawMutex, Cell<Option<Profile>>> = Mutex::new(Cell::new(None));
+static PEER: Mutex<CriticalSectionRawMutex, Cell<Option<Profile>>> = Mutex::new(Cell::new(None));
+/// When the link came up, while the profile of the other half is awaited.
+static WAITING_SINCE: Mutex<CriticalSectionRawMutex, Cell<Option<Instant>>> =
+    Mutex::new(Cell::new(None));
+
+/// Sets the profile of this half. Halves without one do not take part in the handshake.
+pub fn set_local(profile: Profile) {
+    LOCAL.lock(|local| local.set(Some(profile)));
+}
+
+pub fn local() -> Option<Profile> {
+    LOCAL.lock(|local| local.get())
+}
+
+/// Profile of the other half, once it was received.
+pub fn peer() -> Option<Profile> {
+    PEER.lock(|peer| peer.get())
+}
+
+/// Forgets the other half, whose profile is awaited from `now` on.
+pub(crate) fn link_up(now: Instant) {
+    PEER.lock(|p| p.set(None));
+    WAITING_SINCE.lock(|w| w.set(local().map(|_| now)));
+    display::update(|s| s.split_mismatch = None);
+}
+
+/// Forgets the other half, which may be replaced before the link comes back.
+pub(crate) fn link_down() {
+    PEER.lock(|p| p.set(None));
+    WAITING_SINCE.lock(|w| w.set(None));
+    display::update(|s| s.split_mismatch = None);
+}
+
+/// Checks whether the profile of the other half is still awaited. Returns whether the hello has to
+/// be sent again.
+pub(crate) fn poll(now: Instant) -> bool {
+    let Some(since) = WAITING_SINCE.lock(|w| w.get()) else {
+        return false;
+    };
+    if now < since + TIMEOUT {
+        return true;
+    }
+    WAITING_SINCE.lock(|w| w.set(None));
+    display::update(|s| s.split_mismatch = Some(Mismatch::NoHandshake));
+    false
+}
+
+/// Handles the profile of the other half.
+pub(crate) fn received(peer: Profile) {
+    PEER.lock(|p| p.set(Some(peer)));
+    WAITING_SINCE.lock(|w| w.set(None));
+    let mismatch = local().and_then(|own| check(&own, &peer));
+    display::update(|s| s.split_mismatch = mismatch);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const RP2040: Profile = Profile::new(Board::Rp2040, feature::USB | feature::RELIABLE_LINK);
+    const NRF52840: Profile = Profile::new(
+        Board::Nrf52840,
+        feature::BLE | feature::RELIABLE_LINK | feature::HAND_STRAP,
+    );
+
+    #[test]
+    fn profiles_round_trip() {
+        for profile in [RP2040, NRF52840, Profile::new(Board::Other(7), 0xffff)] {
+            assert_eq!(Profile::decode(&profile.encode()), Some(profile));
+        }
+        assert_eq!(Profile::decode(&RP2040.encode()[..ENCODED_LEN - 1]), None);
+    }
+
+    #[test]
+    fn unknown_boards_are_kept() {
+        let profile = Profile::decode(&[PROTOCOL_VERSION, 0x42, 0, 0]).unwrap();
+        assert_eq!(profile.board, Board::Other(0x42));
+        assert_eq!(profile.board.name(), "?");
+        assert_eq!(profile.encode()[1], 0x42);
+        assert_eq!(
+            check(
+                &RP2040,
+                &Profile {
+                    board: Board::Other(0x42),
+                    ..RP2040
+                }
+            ),
+            None
+        );
+    }
+
+    #[test]
+    fn different_boards_and_features_work_together() {
+        assert_eq!(check(&RP2040, &NRF52840), None);
+        assert_eq!(check(&NRF52840, &RP2040), None);
+    }
+
+    #[test]
+    fn protocol_versions_must_match() {
+        let newer = Profile {
+            protocol: PROTOCOL_VERSION + 1,
+            ..RP2040
+        };
+        assert_eq!(
+            check(&RP2040, &newer),
+            Some(Mismatch::Protocol {
+                own: PROTOCOL_VERSION,
+                peer: PROTOCOL_VERSION + 1
+            })
+        );
+    }
+
+    #[test]
+    fn link_features_must_match() {
+        let ble = Profile::new(Board::Nrf52840, feature::BLE_SPLIT);
+        assert_eq!(
+            check(&RP2040, &ble),
+            Some(Mismatch::Features(
+                feature::BLE_SPLIT | feature::RELIABLE_LINK
+            ))
+        );
+    }
+
+    // The state is shared, so the cases run in one test.
+    #[test]
+    fn peer_is_awaited_and_forgotten() {
+        let mismatch = || {
+            let mut mismatch = None;
+            display::update(|s| mismatch = s.split_mismatch);
+            mismatch
+        };
+        set_local(RP2040);
+        let start = Instant::from_secs(10);
+
+        link_up(start);
+        assert!(poll(start));
+        received(NRF52840);
+        assert_eq!(peer(), Some(NRF52840));
+        assert!(!poll(start + TIMEOUT));
+        assert_eq!(mismatch(), None);
+
+        link_down();
+        assert_eq!(peer(), None);
+        assert!(!poll(start + TIMEOUT));
+
+        link_up(start);
+        received(Profile::new(Board::Rp2040, feature::BLE_SPLIT));
+        assert!(matches!(mismatch(), Some(Mismatch::Features(_))));
+        link_down();
+        assert_eq!(mismatch(), None);
+
+        link_up(start);
+        assert!(poll(start + TIMEOUT - Duration::from_millis(1)));
+        assert!(!poll(start + TIMEOUT));
+        assert_eq!(mismatch(), Some(Mismatch::NoHandshake));
+        assert!(!poll(start + TIMEOUT * 2));
+
+        // A late answer still counts.
+        received(NRF52840);
+        assert_eq!(mismatch(), None);
+    }
+}
@@ -0,0 +1,134 @@
+//! Health of the split link, counted by [`KeyballSplitDriver`](super::KeyballSplitDriver).
+//!
+//! Each half counts its own side of the link. The master pings the slave every
+//! [`HEARTBEAT_INTERVAL`] and the slave answers, so that both halves notice when no frame arrives
+//! for [`LINK_TIMEOUT`].
+
+use core::cell::Cell;
+
+use embassy_sync::blocking_mutex::{raw::CriticalSectionRawMutex, Mutex};
+use embassy_time::{Duration, Instant};
+
+pub const HEARTBEAT_INTERVAL: Duration = Duration::from_millis(250);
+pub const LINK_TIMEOUT: Duration = Duration::from_secs(1);
+
+pub const ENCODED_LEN: usize = 25;
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Default)]
+pub struct LinkStats {
+    pub sent: u32,
+    pub received: u32,
+    /// Frames which could not be received or decoded.
+    pub framing_errors: u32,
+    /// Frames whose checksum did not match. Only counted by transports which carry one.
+    pub crc_failures: u32,
+    /// Times the link went down because nothing arrived for [`LINK_TIMEOUT`].
+    pub timeouts: u32,
+    /// Times the link came back after a timeout.
+    pub reconnects: u32,
+    pub up: bool,
+}
+
+impl LinkStats {
+    const fn new() -> Self {
+        Self {
+            sent: 0,
+            received: 0,
+            framing_errors: 0,
+            crc_failures: 0,
+            timeouts: 0,
+            reconnects: 0,
+            up: false,
+        }
+    }
+
+    /// Counters as little endian `u32` in the order of the fields, followed by `up`.
+    pub fn encode(&self) -> [u8; ENCODED_LEN] {
+        let mut buf = [0; ENCODED_LEN];
+        let counters = [
+            self.sent,
+            self.received,
+            self.framing_errors,
+            self.crc_failures,
+            self.timeouts,
+            self.reconnects,
+        ];
+        for (chunk, counter) in buf.chunks_mut(4).zip(counters) {
+            chunk.copy_from_slice(&counter.to_le_bytes());
+        }
+        buf[ENCODED_LEN - 1] = self.up as u8;
+        buf
+    }
+}
+
+#[derive(Clone, Copy)]
+struct State {
+    stats: LinkStats,
+    last_received: Instant,
+}
+
+static STATE: Mutex<CriticalSectionRawMutex, Cell<State>> = Mutex::new(Cell::new(State {
+    stats: LinkStats::new(),
+    last_received: Instant::from_ticks(0),
+}));
+
+fn modify(f: impl FnOnce(&mut State)) {
+    STATE.lock(|state| {
+        let mut s = state.get();
+        f(&mut s);
+        state.set(s);
+    });
+}
+
+pub fn stats() -> LinkStats {
+    STATE.lock(|state| state.get().stats)
+}
+
+/// Whether a frame arrived within [`LINK_TIMEOUT`].
+pub fn is_up() -> bool {
+    stats().up
+}
+
+pub(crate) fn frame_sent() {
+    modify(|s| s.stats.sent = s.stats.sent.wrapping_add(1));
+}
+
+/// Counts a received frame. Returns whether the link came up with it.
+pub(crate) fn frame_received() -> bool {
+    let mut came_up = false;
+    modify(|s| {
+        s.stats.received = s.stats.received.wrapping_add(1);
+        s.last_received = Instant::now();
+        if !s.stats.up {
+            s.stats.up = true;
+            came_up = true;
+            // The first frame after boot is not a reconnect.
+            if s.stats.timeouts > 0 {
+                s.stats.reconnects = s.stats.reconnects.wrapping_add(1);
+            }
+        }
+    });
+    came_up
+}
+
+pub(crate) fn framing_error() {
+    modify(|s| s.stats.framing_errors = s.stats.framing_errors.wrapping_add(1));
+}
+
+/// Counts a frame whose checksum did not match.
+pub fn crc_failure() {
+    modify(|s| s.stats.crc_failures = s.stats.crc_failures.wrapping_add(1));
+}
+
+/// Marks the link down if nothing arrived for [`LINK_TIMEOUT`]. Returns whether it went down.
+pub(crate) fn check_timeout() -> bool {
+    let mut went_down = false;
+    modify(|s| {
+        if s.stats.up && s.last_received.elapsed() >= LINK_TIMEOUT {
+            s.stats.up = false;
+            s.stats.timeouts = s.stats.timeouts.wrapping_add(1);
+            went_down = true;
+        }
+    });
+    went_down
+}
@@ -0,0 +1,226 @@
+//! Split transport over a packet link, such as a BLE connection.
+//!
+//! rktk talks to a [`LinkSplitDriver`], which only exchanges frames with a [`Link`] through
+//! channels. The transport on the other side of the channels moves the frames to the other half and
+//! keeps reconnecting whenever the link drops. Frames sent while the link is down are kept in the
+//! channel, so rktk waits instead of losing key releases.
+//!
+//! [`loopback`] connects two links directly, which runs the split logic without any radio.
+
+use embassy_futures::join::join;
+use embassy_sync::{blocking_mutex::raw::RawMutex, channel::Channel, signal::Signal};
+use rktk::drivers::interface::split::SplitDriver;
+
+use super::MAX_FRAME_SIZE;
+
+pub type Frame = heapless::Vec<u8, MAX_FRAME_SIZE>;
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Role {
+    /// The master half, which connects to the other half.
+    Central,
+    /// The slave half, which waits for the master to connect.
+    Peripheral,
+}
+
+/// Channels between rktk and a transport.
+pub struct Link<M: RawMutex, const N: usize> {
+    outgoing: Channel<M, Frame, N>,
+    incoming: Channel<M, Frame, N>,
+    role: Signal<M, Role>,
+}
+
+impl<M: RawMutex, const N: usize> Default for Link<M, N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<M: RawMutex, const N: usize> Link<M, N> {
+    pub const fn new() -> Self {
+        Self {
+            outgoing: Channel::new(),
+            incoming: Channel::new(),
+            role: Signal::new(),
+        }
+    }
+
+    /// Split driver for rktk.
+    pub fn split_driver(&self) -> LinkSplitDriver<'_, M, N> {
+        LinkSplitDriver {
+            link: self,
+            role: None,
+        }
+    }
+
+    /// Fixes the role for transports which do not leave it to rktk.
+    pub fn set_role(&self, role: Role) {
+        self.role.signal(role);
+    }
+
+    /// Waits until rktk has decided whether this half is master. Transports call this once before
+    /// connecting.
+    pub async fn role(&self) -> Role {
+        self.role.wait().await
+    }
+
+    /// Waits for the next frame to send to the other half.
+    pub async fn next_outgoing(&self) -> Frame {
+        self.outgoing.receive().await
+    }
+
+    /// Passes a frame received from the other half to rktk. Returns `false` if the frame was
+    /// dropped because rktk does not keep up or it is too large. The transport must then drop the
+    /// connection, so that both halves see the link go down instead of missing a key release.
+    #[must_use]
+    pub fn received(&self, data: &[u8]) -> bool {
+        Frame::from_slice(data).is_ok_and(|frame| self.incoming.try_send(frame).is_ok())
+    }
+}
+
+#[derive(Debug)]
+pub enum LinkSplitError {
+    FrameTooLarge,
+}
+
+pub struct LinkSplitDriver<'a, M: RawMutex, const N: usize> {
+    link: &'a Link<M, N>,
+    role: Option<Role>,
+}
+
+impl<M: RawMutex, const N: usize> LinkSplitDriver<'_, M, N> {
+    fn announce_role(&mut self, is_master: bool) {
+        if self.role.is_none() {
+            let role = if is_master {
+                Role::Central
+            } else {
+                Role::Peripheral
+            };
+            self.role = Some(role);
+            self.link.role.signal(role);
+        }
+    }
+}
+
+impl<M: RawMutex, const N: usize> SplitDriver for LinkSplitDriver<'_, M, N> {
+    type Error = LinkSplitError;
+
+    async fn init(&mut self) -> Result<(), Self::Error> {
+        Ok(())
+    }
+
+    async fn wait_recv(&mut self, buf: &mut [u8], is_master: bool) -> Result<(), Self::Error> {
+        self.announce_role(is_master);
+        let frame = self.link.incoming.receive().await;
+        let len = buf.len().min(frame.len());
+        buf[..len].copy_from_slice(&frame[..len]);
+        Ok(())
+    }
+
+    async fn send(&mut self, buf: &[u8], is_master: bool) -> Result<(), Self::Error> {
+        self.announce_role(is_master);
+        let frame = Frame::from_slice(buf).map_err(|_| LinkSplitError::FrameTooLarge)?;
+        self.link.outgoing.send(frame).await;
+        Ok(())
+    }
+}
+
+/// Transport which connects two links of the same device directly.
+pub async fn loopback<M: RawMutex, const N: usize>(a: &Link<M, N>, b: &Link<M, N>) -> ! {
+    let (never, _) = join(forward(a, b), forward(b, a)).await;
+    never
+}
+
+async fn forward<M: RawMutex, const N: usize>(from: &Link<M, N>, to: &Link<M, N>) -> ! {
+    loop {
+        let frame = from.next_outgoing().await;
+        to.incoming.send(frame).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use embassy_futures::{
+        block_on,
+        select::{select, Either},
+    };
+    use embassy_sync::blocking_mutex::raw::NoopRawMutex;
+
+    use super::*;
+
+    type TestLink = Link<NoopRawMutex, 4>;
+
+    /// Runs `f` while `a` and `b` are connected by [`loopback`].
+    fn connected<R>(a: &TestLink, b: &TestLink, f: impl core::future::Future<Output = R>) -> R {
+        match block_on(select(loopback(a, b), f)) {
+            Either::First(never) => never,
+            Either::Second(result) => result,
+        }
+    }
+
+    #[test]
+    fn frames_cross_in_both_directions() {
+        let (a, b) = (TestLink::new(), TestLink::new());
+        let (mut master, mut slave) = (a.split_driver(), b.split_driver());
+        connected(&a, &b, async {
+            let mut buf = [0; 3];
+            master.send(&[1, 2, 3], true).await.unwrap();
+            slave.wait_recv(&mut buf, false).await.unwrap();
+            assert_eq!(buf, [1, 2, 3]);
+
+            slave.send(&[4, 5, 6], false).await.unwrap();
+            master.wait_recv(&mut buf, true).await.unwrap();
+            assert_eq!(buf, [4, 5, 6]);
+        });
+    }
+
+    #[test]
+    fn role_follows_rktk() {
+        let (a, b) = (TestLink::new(), TestLink::new());
+        let (mut master, mut slave) = (a.split_driver(), b.split_driver());
+        connected(&a, &b, async {
+            master.send(&[0], true).await.unwrap();
+            slave.wait_recv(&mut [0], false).await.unwrap();
+            // The role is announced once, whatever rktk passes later.
+            master.send(&[0], false).await.unwrap();
+            assert_eq!(a.role().await, Role::Central);
+            assert_eq!(b.role().await, Role::Peripheral);
+        });
+    }
+
+    #[test]
+    fn frames_wait_while_the_link_is_down() {
+        let (a, b) = (TestLink::new(), TestLink::new());
+        let (mut master, mut slave) = (a.split_driver(), b.split_driver());
+        block_on(async {
+            for i in 0..4 {
+                master.send(&[i], true).await.unwrap();
+            }
+        });
+        connected(&a, &b, async {
+            for i in 0..4 {
+                let mut buf = [0];
+                slave.wait_recv(&mut buf, false).await.unwrap();
+                assert_eq!(buf, [i]);
+            }
+        });
+    }
+
+    #[test]
+    fn too_large_frames_are_rejected() {
+        let a = TestLink::new();
+        let mut driver = a.split_driver();
+        let result = block_on(driver.send(&[0; MAX_FRAME_SIZE + 1], true));
+        assert!(matches!(result, Err(LinkSplitError::FrameTooLarge)));
+        assert!(!a.received(&[0; MAX_FRAME_SIZE + 1]));
+    }
+
+    #[test]
+    fn received_reports_frames_rktk_has_no_room_for() {
+        let a = TestLink::new();
+        for i in 0..4 {
+            assert!(a.received(&[i]));
+        }
+        assert!(!a.received(&[4]));
+    }
+}
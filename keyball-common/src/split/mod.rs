@@ -0,0 +1,384 @@
+//! Split driver wrapper which carries Keyball specific messages next to the ones of rktk.
+//!
+//! Every frame gets a one byte tag. Frames tagged with [`TAG_RKTK`] are passed through to rktk
+//! unchanged, frames tagged with [`TAG_KEYBALL`] are decoded as [`KeyballMessage`] and handled here.
+//! The frames passing through are counted in [`health`], and whenever the link comes up the halves
+//! exchange their [`handshake`] profiles. While it is down, each half runs on its own as described
+//! in [`degraded`].
+
+use core::pin::pin;
+
+use embassy_futures::select::{select3, Either3};
+use embassy_sync::{blocking_mutex::raw::CriticalSectionRawMutex, channel::Channel};
+use embassy_time::{Instant, Timer};
+use rktk::drivers::interface::split::SplitDriver;
+
+pub mod degraded;
+pub mod handshake;
+pub mod health;
+pub mod link;
+pub mod reliable;
+
+use crate::{
+    backlight, bootloader,
+    display::{self, Page},
+    host_leds::{self, HostLeds},
+    matrix_tester, power, settings,
+    watchdog::{self, Task},
+};
+
+pub const MAX_FRAME_SIZE: usize = 64;
+
+const TAG_RKTK: u8 = 0;
+const TAG_KEYBALL: u8 = 1;
+
+const MSG_KEY_PRESSED: u8 = 0;
+const MSG_HOST_LEDS: u8 = 1;
+const MSG_ENTER_BOOTLOADER: u8 = 2;
+const MSG_PING: u8 = 3;
+const MSG_PONG: u8 = 4;
+const MSG_DISPLAY_PAGE: u8 = 5;
+/// Must not change between protocol versions, see [`handshake`].
+const MSG_HELLO: u8 = 6;
+const MSG_LAYER: u8 = 7;
+const MSG_SETTINGS: u8 = 8;
+const MSG_MATRIX_KEY: u8 = 9;
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum KeyballMessage {
+    /// Sent by the master for every key press of either half, so that reactive backlight effects of
+    /// the slave can follow them.
+    KeyPressed { row: u8, col: u8 },
+    /// Keyboard LED state reported by the host to the half connected to it.
+    HostLeds(u8),
+    /// The bootloader key was confirmed on the other half.
+    EnterBootloader,
+    /// Sent by the master every [`health::HEARTBEAT_INTERVAL`].
+    Ping,
+    /// Answer of the slave to [`KeyballMessage::Ping`].
+    Pong,
+    /// Page shown on the display of the other half.
+    DisplayPage(Page),
+    /// Profile of the sending half. Answered with the profile of the receiving half if `reply` is
+    /// set.
+    Hello {
+        profile: handshake::Profile,
+        reply: bool,
+    },
+    /// Highest active layer of the master.
+    Layer(u8),
+    /// Runtime settings of the sending half, see [`settings`].
+    Settings(settings::Revision),
+    /// Key change of the sending half while the [`matrix_tester`] is active.
+    MatrixKey {
+        row: u8,
+        col: u8,
+        pressed: bool,
+        chatter: bool,
+    },
+}
+
+impl KeyballMessage {
+    /// Encodes the message into `buf` and returns the number of bytes written.
+    pub fn encode(&self, buf: &mut [u8]) -> usize {
+        match self {
+            KeyballMessage::KeyPressed { row, col } => {
+                buf[..3].copy_from_slice(&[MSG_KEY_PRESSED, *row, *col]);
+                3
+            }
+            KeyballMessage::HostLeds(leds) => {
+                buf[..2].copy_from_slice(&[MSG_HOST_LEDS, *leds]);
+                2
+            }
+            KeyballMessage::EnterBootloader => {
+                buf[0] = MSG_ENTER_BOOTLOADER;
+                1
+            }
+            KeyballMessage::Ping => {
+                buf[0] = MSG_PING;
+                1
+            }
+            KeyballMessage::Pong => {
+                buf[0] = MSG_PONG;
+                1
+            }
+            KeyballMessage::DisplayPage(page) => {
+                buf[..2].copy_from_slice(&[MSG_DISPLAY_PAGE, page.to_u8()]);
+                2
+            }
+            KeyballMessage::Hello { profile, reply } => {
+                buf[..2].copy_from_slice(&[MSG_HELLO, *reply as u8]);
+                buf[2..2 + handshake::ENCODED_LEN].copy_from_slice(&profile.encode());
+                2 + handshake::ENCODED_LEN
+            }
+            KeyballMessage::Layer(layer) => {
+                buf[..2].copy_from_slice(&[MSG_LAYER, *layer]);
+                2
+            }
+            KeyballMessage::Settings(revision) => {
+                buf[0] = MSG_SETTINGS;
+                buf[1..1 + settings::ENCODED_LEN].copy_from_slice(&revision.encode());
+                1 + settings::ENCODED_LEN
+            }
+            KeyballMessage::MatrixKey {
+                row,
+                col,
+                pressed,
+                chatter,
+            } => {
+                let flags = *pressed as u8 | (*chatter as u8) << 1;
+                buf[..4].copy_from_slice(&[MSG_MATRIX_KEY, *row, *col, flags]);
+                4
+            }
+        }
+    }
+
+    pub fn decode(buf: &[u8]) -> Option<Self> {
+        match buf {
+            [MSG_KEY_PRESSED, row, col, ..] => Some(KeyballMessage::KeyPressed {
+                row: *row,
+                col: *col,
+            }),
+            [MSG_HOST_LEDS, leds, ..] => Some(KeyballMessage::HostLeds(*leds)),
+            [MSG_ENTER_BOOTLOADER, ..] => Some(KeyballMessage::EnterBootloader),
+            [MSG_PING, ..] => Some(KeyballMessage::Ping),
+            [MSG_PONG, ..] => Some(KeyballMessage::Pong),
+            [MSG_DISPLAY_PAGE, page, ..] => Page::from_u8(*page).map(KeyballMessage::DisplayPage),
+            [MSG_HELLO, reply, profile @ ..] => {
+                handshake::Profile::decode(profile).map(|profile| KeyballMessage::Hello {
+                    profile,
+                    reply: *reply != 0,
+                })
+            }
+            [MSG_LAYER, layer, ..] => Some(KeyballMessage::Layer(*layer)),
+            [MSG_SETTINGS, revision @ ..] => {
+                settings::Revision::decode(revision).map(KeyballMessage::Settings)
+            }
+            [MSG_MATRIX_KEY, row, col, flags, ..] => Some(KeyballMessage::MatrixKey {
+                row: *row,
+                col: *col,
+                pressed: flags & 1 != 0,
+                chatter: flags & 2 != 0,
+            }),
+            _ => None,
+        }
+    }
+
+    fn handle(self) {
+        match self {
+            KeyballMessage::KeyPressed { row, col } => backlight::key_pressed(row, col),
+            KeyballMessage::HostLeds(leds) => {
+                host_leds::apply(HostLeds(leds));
+            }
+            KeyballMessage::EnterBootloader => bootloader::requested_by_other_half(),
+            KeyballMessage::Ping => send_to_other_half(KeyballMessage::Pong),
+            KeyballMessage::Pong => {}
+            KeyballMessage::DisplayPage(page) => display::set_page(page),
+            KeyballMessage::Hello { profile, reply } => {
+                handshake::received(profile);
+                if reply {
+                    send_hello(false);
+                }
+            }
+            KeyballMessage::Layer(layer) => display::update(|s| s.layer = layer),
+            KeyballMessage::Settings(revision) => settings::received(revision),
+            KeyballMessage::MatrixKey {
+                row,
+                col,
+                pressed,
+                chatter,
+            } => matrix_tester::remote_key_changed(row, col, pressed, chatter),
+        }
+    }
+}
+
+static OUTGOING: Channel<CriticalSectionRawMutex, KeyballMessage, 8> = Channel::new();
+
+/// Queues a message for the other half. Messages are dropped if the queue is full.
+pub fn send_to_other_half(message: KeyballMessage) {
+    let _ = OUTGOING.try_send(message);
+}
+
+/// Whether `frame` carries a [`KeyballMessage::Hello`], which is sent outside of the
+/// [`reliable`] framing.
+pub(crate) fn is_hello(frame: &[u8]) -> bool {
+    matches!(frame, [TAG_KEYBALL, MSG_HELLO, ..])
+}
+
+fn send_hello(reply: bool) {
+    if let Some(profile) = handshake::local() {
+        send_to_other_half(KeyballMessage::Hello { profile, reply });
+    }
+}
+
+/// Drops errors of sends while the link is lost, so that rktk keeps running this half on its own.
+fn ignore_while_lost<E>(result: Result<(), E>) -> Result<(), E> {
+    if degraded::is_lost() {
+        Ok(())
+    } else {
+        result
+    }
+}
+
+/// What interrupted the receive loop of [`KeyballSplitDriver`].
+enum Event<E> {
+    Received(Result<(), E>),
+    Send(KeyballMessage),
+}
+
+#[derive(Debug)]
+pub enum KeyballSplitError<E> {
+    Inner(E),
+    FrameTooLarge,
+}
+
+pub struct KeyballSplitDriver<S: SplitDriver> {
+    inner: S,
+    buf: [u8; MAX_FRAME_SIZE],
+    next_heartbeat: Instant,
+}
+
+impl<S: SplitDriver> KeyballSplitDriver<S> {
+    pub fn new(inner: S) -> Self {
+        watchdog::watch(Task::Split);
+        Self {
+            inner,
+            buf: [0; MAX_FRAME_SIZE],
+            next_heartbeat: Instant::from_ticks(0),
+        }
+    }
+
+    async fn send_message(
+        &mut self,
+        message: KeyballMessage,
+        is_master: bool,
+    ) -> Result<(), KeyballSplitError<S::Error>> {
+        let mut data = [0; MAX_FRAME_SIZE - 1];
+        let len = message.encode(&mut data);
+        self.send_tagged(TAG_KEYBALL, &data[..len], is_master).await
+    }
+
+    async fn send_tagged(
+        &mut self,
+        tag: u8,
+        data: &[u8],
+        is_master: bool,
+    ) -> Result<(), KeyballSplitError<S::Error>> {
+        let len = data.len() + 1;
+        if len > MAX_FRAME_SIZE {
+            return Err(KeyballSplitError::FrameTooLarge);
+        }
+        self.buf[0] = tag;
+        self.buf[1..len].copy_from_slice(data);
+        self.inner
+            .send(&self.buf[..len], is_master)
+            .await
+            .map_err(KeyballSplitError::Inner)?;
+        health::frame_sent();
+        Ok(())
+    }
+}
+
+impl<S: SplitDriver> SplitDriver for KeyballSplitDriver<S> {
+    type Error = KeyballSplitError<S::Error>;
+
+    async fn init(&mut self) -> Result<(), Self::Error> {
+        self.inner.init().await.map_err(KeyballSplitError::Inner)
+    }
+
+    async fn wait_recv(&mut self, buf: &mut [u8], is_master: bool) -> Result<(), Self::Error> {
+        loop {
+            watchdog::check_in(Task::Split);
+            if is_master && degraded::take_peer_release(buf) {
+                return Ok(());
+            }
+            let event = {
+                // Kept across heartbeats, so that only sending cancels a frame coming in.
+                let mut receive = pin!(self.inner.wait_recv(&mut self.buf, is_master));
+                loop {
+                    match select3(
+                        receive.as_mut(),
+                        OUTGOING.receive(),
+                        Timer::at(self.next_heartbeat),
+                    )
+                    .await
+                    {
+                        Either3::First(result) => break Event::Received(result),
+                        // They would not arrive, and the state is synced again once the link is
+                        // back.
+                        Either3::Second(_) if degraded::is_lost() => {}
+                        Either3::Second(message) => break Event::Send(message),
+                        Either3::Third(()) => {
+                            watchdog::check_in(Task::Split);
+                            let now = Instant::now();
+                            self.next_heartbeat = now + health::HEARTBEAT_INTERVAL;
+                            if health::check_timeout() {
+                                handshake::link_down();
+                            }
+                            degraded::update(health::is_up(), is_master);
+                            if handshake::poll(now) {
+                                send_hello(true);
+                            }
+                            if is_master {
+                                break Event::Send(KeyballMessage::Ping);
+                            }
+                        }
+                    }
+                }
+            };
+
+            match event {
+                Event::Received(Ok(())) => {
+                    if health::frame_received() {
+                        handshake::link_up(Instant::now());
+                        send_hello(true);
+                        settings::link_up();
+                        degraded::update(true, is_master);
+                    }
+                }
+                Event::Received(Err(e)) => {
+                    health::framing_error();
+                    return Err(KeyballSplitError::Inner(e));
+                }
+                Event::Send(message) => {
+                    let result = self.send_message(message, is_master).await;
+                    ignore_while_lost(result)?;
+                    continue;
+                }
+            }
+
+            match self.buf[0] {
+                TAG_RKTK => {
+                    let len = buf.len().min(MAX_FRAME_SIZE - 1);
+                    buf[..len].copy_from_slice(&self.buf[1..=len]);
+                    if is_master {
+                        degraded::peer_frame_received(&buf[..len]);
+                    }
+                    return Ok(());
+                }
+                TAG_KEYBALL => match KeyballMessage::decode(&self.buf[1..]) {
+                    Some(message) => message.handle(),
+                    None => health::framing_error(),
+                },
+                _ => health::framing_error(),
+            }
+        }
+    }
+
+    async fn send(&mut self, buf: &[u8], is_master: bool) -> Result<(), Self::Error> {
+        watchdog::check_in(Task::Split);
+        // The slave only sends key and ball events of its own, which its hooks do not see.
+        if !is_master {
+            power::notify_activity();
+        }
+        let result = self.send_tagged(TAG_RKTK, buf, is_master).await;
+        if !is_master {
+            match result {
+                Ok(()) => degraded::frame_confirmed(buf),
+                Err(_) if !degraded::is_lost() => degraded::send_failed(),
+                Err(_) => {}
+            }
+        }
+        ignore_while_lost(result)
+    }
+}
@@ -0,0 +1,804 @@
+//! Reliable delivery over the one-wire split drivers, which lose frames whenever both halves talk
+//! at once or the line picks up noise.
+//!
+//! Every frame of rktk is wrapped into a data frame with a length, a session, a sequence number and
+//! a CRC16, and the other half answers with an ack frame. Frames without an ack are sent again up to
+//! [`MAX_ATTEMPTS`] times. Only one frame is in flight at a time, which suits the half-duplex line.
+//! Every boot starts a new session, numbered by a counter in retained RAM, so that the first frames
+//! after a reset are not taken for copies of the frames received before it.
+//!
+//! [`Protocol`] is the state machine without any I/O, and [`ReliableSplitDriver`] runs it on top of
+//! a [`SplitDriver`]. Frames of the [`handshake`](super::handshake) are passed through unwrapped and
+//! without an ack, so that a half without this framing can read them.
+//!
+//! rktk cancels `wait_recv` whenever it has a frame to send, and the split wrapper may cancel
+//! `send`. Neither loses a frame: a received payload is queued before its ack is sent, and a frame
+//! in flight stays in flight until it was acked or given up, whichever call of the driver comes
+//! next.
+//!
+//! | Bytes           | Content                                       |
+//! |-----------------|-----------------------------------------------|
+//! | 0               | Kind: data or ack                             |
+//! | 1               | Session of the sender of the data             |
+//! | 2               | Sequence number                               |
+//! | 3               | Payload length, 0 for acks                    |
+//! | 4..4+len        | Payload                                       |
+//! | 4+len..6+len    | CRC-16/CCITT-FALSE of the bytes before, LE    |
+
+use embassy_futures::select::{select, Either};
+use embassy_time::{Duration, Instant, Timer};
+use heapless::{Deque, Vec};
+use rktk::drivers::interface::split::SplitDriver;
+
+use super::{health, is_hello, MAX_FRAME_SIZE};
+use crate::retained::Mailbox;
+
+const KIND_DATA: u8 = 0xd1;
+const KIND_ACK: u8 = 0xac;
+
+const HEADER_LEN: usize = 4;
+const CRC_LEN: usize = 2;
+
+/// Size of the frames on the wire.
+pub const RAW_FRAME_SIZE: usize = MAX_FRAME_SIZE + HEADER_LEN + CRC_LEN;
+
+/// Transmissions of a frame, including the first one.
+pub const MAX_ATTEMPTS: u8 = 4;
+
+/// Time to wait for an ack. The halves use different times, so that frames which collided are not
+/// sent again at the same moment.
+const ACK_TIMEOUT_MASTER: Duration = Duration::from_millis(8);
+const ACK_TIMEOUT_SLAVE: Duration = Duration::from_millis(11);
+
+/// Payloads received while sending, which wait for the next `wait_recv`.
+const RECEIVE_QUEUE_SIZE: usize = 4;
+
+pub type RawFrame = Vec<u8, RAW_FRAME_SIZE>;
+
+#[link_section = ".uninit.keyball.reliable_session"]
+static SESSION: Mailbox = Mailbox::new();
+
+/// Returns the session of this boot, which differs from the session before a reset. Both halves
+/// lose their state on power-on, so the session may repeat then.
+fn next_session() -> u8 {
+    let session = SESSION
+        .take()
+        .map_or(0, |session| session as u8)
+        .wrapping_add(1);
+    SESSION.put(session as u32);
+    session
+}
+
+/// CRC-16/CCITT-FALSE.
+pub fn crc16(data: &[u8]) -> u16 {
+    let mut crc = 0xffff_u16;
+    for byte in data {
+        crc ^= (*byte as u16) << 8;
+        for _ in 0..8 {
+            crc = if crc & 0x8000 != 0 {
+                (crc << 1) ^ 0x1021
+            } else {
+                crc << 1
+            };
+        }
+    }
+    crc
+}
+
+fn encode(kind: u8, session: u8, seq: u8, payload: &[u8]) -> RawFrame {
+    let mut frame = RawFrame::new();
+    let _ = frame.extend_from_slice(&[kind, session, seq, payload.len() as u8]);
+    let _ = frame.extend_from_slice(payload);
+    let crc = crc16(&frame);
+    let _ = frame.extend_from_slice(&crc.to_le_bytes());
+    frame
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Frame<'a> {
+    Data {
+        session: u8,
+        seq: u8,
+        payload: &'a [u8],
+    },
+    /// Ack of the data frame with the same session and sequence number.
+    Ack { session: u8, seq: u8 },
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum DecodeError {
+    /// Unknown kind or a length which does not fit.
+    Malformed,
+    Crc,
+}
+
+/// Decodes a frame from the start of `buf`. Bytes after the frame are ignored.
+pub fn decode(buf: &[u8]) -> Result<Frame<'_>, DecodeError> {
+    let [kind, session, seq, len, ..] = *buf else {
+        return Err(DecodeError::Malformed);
+    };
+    let len = len as usize;
+    if len > MAX_FRAME_SIZE || buf.len() < HEADER_LEN + len + CRC_LEN {
+        return Err(DecodeError::Malformed);
+    }
+    let (body, rest) = buf.split_at(HEADER_LEN + len);
+    if crc16(body) != u16::from_le_bytes([rest[0], rest[1]]) {
+        return Err(DecodeError::Crc);
+    }
+    let payload = &body[HEADER_LEN..];
+    match kind {
+        KIND_DATA => Ok(Frame::Data {
+            session,
+            seq,
+            payload,
+        }),
+        KIND_ACK if len == 0 => Ok(Frame::Ack { session, seq }),
+        _ => Err(DecodeError::Malformed),
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum SendError {
+    /// A frame is still waiting for its ack.
+    Busy,
+    TooLarge,
+}
+
+/// Result of [`Protocol::receive`].
+#[derive(PartialEq, Eq, Debug)]
+pub enum Received<'a> {
+    /// A new payload. `ack` has to be transmitted.
+    Data {
+        payload: &'a [u8],
+        ack: RawFrame,
+    },
+    /// A payload which was already delivered, whose ack got lost. `ack` has to be transmitted.
+    Duplicate {
+        ack: RawFrame,
+    },
+    /// The frame in flight was acked.
+    Acked,
+    /// An ack of a frame which is not in flight.
+    Ignored,
+    Error(DecodeError),
+}
+
+/// What to do for the frame in flight, returned by [`Protocol::poll`].
+#[derive(PartialEq, Eq, Debug)]
+pub enum Poll<'a> {
+    /// Nothing is in flight.
+    Idle,
+    /// Wait for an ack until the deadline.
+    Waiting(Instant),
+    /// Transmit the frame again.
+    Retransmit(&'a [u8]),
+    /// The frame was sent [`MAX_ATTEMPTS`] times without an ack and was dropped.
+    GaveUp,
+}
+
+struct InFlight {
+    frame: RawFrame,
+    seq: u8,
+    attempts: u8,
+    timeout: Duration,
+    deadline: Instant,
+}
+
+/// Both directions of one half of the link, without any I/O.
+pub struct Protocol {
+    session: u8,
+    next_seq: u8,
+    in_flight: Option<InFlight>,
+    /// Session and sequence number of the last payload delivered.
+    last_received: Option<(u8, u8)>,
+}
+
+impl Protocol {
+    /// `session` has to differ from the session of the protocol this half ran before a reset.
+    pub const fn new(session: u8) -> Self {
+        Self {
+            session,
+            next_seq: 0,
+            in_flight: None,
+            last_received: None,
+        }
+    }
+
+    /// Starts sending `payload` and returns the frame to transmit. `timeout` is the time to wait for
+    /// the ack of each transmission.
+    pub fn send(
+        &mut self,
+        payload: &[u8],
+        now: Instant,
+        timeout: Duration,
+    ) -> Result<&[u8], SendError> {
+        if self.in_flight.is_some() {
+            return Err(SendError::Busy);
+        }
+        if payload.len() > MAX_FRAME_SIZE {
+            return Err(SendError::TooLarge);
+        }
+        let seq = self.next_seq;
+        self.next_seq = seq.wrapping_add(1);
+        let in_flight = self.in_flight.insert(InFlight {
+            frame: encode(KIND_DATA, self.session, seq, payload),
+            seq,
+            attempts: 1,
+            timeout,
+            deadline: now + timeout,
+        });
+        Ok(&in_flight.frame)
+    }
+
+    /// Handles a frame from the other half.
+    pub fn receive<'a>(&mut self, buf: &'a [u8]) -> Received<'a> {
+        match decode(buf) {
+            Err(e) => Received::Error(e),
+            Ok(Frame::Ack { session, seq }) => match &self.in_flight {
+                Some(in_flight) if session == self.session && in_flight.seq == seq => {
+                    self.in_flight = None;
+                    Received::Acked
+                }
+                _ => Received::Ignored,
+            },
+            Ok(Frame::Data {
+                session,
+                seq,
+                payload,
+            }) => {
+                let ack = encode(KIND_ACK, session, seq, &[]);
+                if self.last_received == Some((session, seq)) {
+                    return Received::Duplicate { ack };
+                }
+                self.last_received = Some((session, seq));
+                Received::Data { payload, ack }
+            }
+        }
+    }
+
+    /// Forgets the last payload delivered, so that it is delivered again if it arrives once more.
+    /// Used when the payload could not be taken.
+    pub fn reject_last(&mut self) {
+        self.last_received = None;
+    }
+
+    /// Whether a frame is waiting for its ack.
+    pub fn is_busy(&self) -> bool {
+        self.in_flight.is_some()
+    }
+
+    /// Checks the frame in flight.
+    pub fn poll(&mut self, now: Instant) -> Poll<'_> {
+        match &self.in_flight {
+            None => return Poll::Idle,
+            Some(in_flight) if now < in_flight.deadline => {
+                return Poll::Waiting(in_flight.deadline)
+            }
+            Some(in_flight) if in_flight.attempts >= MAX_ATTEMPTS => {
+                self.in_flight = None;
+                return Poll::GaveUp;
+            }
+            Some(_) => {}
+        }
+        let Some(in_flight) = &mut self.in_flight else {
+            return Poll::Idle;
+        };
+        in_flight.attempts += 1;
+        in_flight.deadline = now + in_flight.timeout;
+        Poll::Retransmit(&in_flight.frame)
+    }
+}
+
+#[derive(Debug)]
+pub enum ReliableSplitError<E> {
+    Inner(E),
+    FrameTooLarge,
+    /// The other half did not ack the frame.
+    NoAck,
+}
+
+/// Split driver which runs [`Protocol`] on top of a one-wire split driver.
+pub struct ReliableSplitDriver<S: SplitDriver> {
+    inner: S,
+    protocol: Protocol,
+    buf: [u8; RAW_FRAME_SIZE],
+    received: Deque<Vec<u8, MAX_FRAME_SIZE>, RECEIVE_QUEUE_SIZE>,
+}
+
+impl<S: SplitDriver> ReliableSplitDriver<S> {
+    pub fn new(inner: S) -> Self {
+        Self {
+            inner,
+            protocol: Protocol::new(next_session()),
+            buf: [0; RAW_FRAME_SIZE],
+            received: Deque::new(),
+        }
+    }
+
+    /// Takes one step of the frame in flight, if any, while receiving: transmits it again once its
+    /// ack is overdue, and returns [`ReliableSplitError::NoAck`] if it was given up.
+    async fn step(&mut self, is_master: bool) -> Result<(), ReliableSplitError<S::Error>> {
+        match self.protocol.poll(Instant::now()) {
+            Poll::Idle => self.receive(is_master).await,
+            Poll::Waiting(deadline) => {
+                match select(self.receive(is_master), Timer::at(deadline)).await {
+                    Either::First(result) => result,
+                    Either::Second(()) => Ok(()),
+                }
+            }
+            Poll::Retransmit(frame) => self
+                .inner
+                .send(frame, is_master)
+                .await
+                .map_err(ReliableSplitError::Inner),
+            Poll::GaveUp => Err(ReliableSplitError::NoAck),
+        }
+    }
+
+    /// Receives one frame and handles it.
+    async fn receive(&mut self, is_master: bool) -> Result<(), ReliableSplitError<S::Error>> {
+        self.inner
+            .wait_recv(&mut self.buf, is_master)
+            .await
+            .map_err(ReliableSplitError::Inner)?;
+        let ack = match self.protocol.receive(&self.buf) {
+            Received::Data { payload, ack } => {
+                let payload = Vec::from_slice(payload).unwrap_or_default();
+                if self.received.push_back(payload).is_err() {
+                    // Leave it to the other half to send it again.
+                    self.protocol.reject_last();
+                    return Ok(());
+                }
+                ack
+            }
+            Received::Duplicate { ack } => ack,
+            Received::Acked | Received::Ignored => return Ok(()),
+            Received::Error(_) if is_hello(&self.buf) => {
+                let payload = Vec::from_slice(&self.buf[..MAX_FRAME_SIZE]).unwrap_or_default();
+                let _ = self.received.push_back(payload);
+                return Ok(());
+            }
+            Received::Error(DecodeError::Crc) => {
+                health::crc_failure();
+                return Ok(());
+            }
+            Received::Error(DecodeError::Malformed) => {
+                health::framing_error();
+                return Ok(());
+            }
+        };
+        self.inner
+            .send(&ack, is_master)
+            .await
+            .map_err(ReliableSplitError::Inner)
+    }
+}
+
+impl<S: SplitDriver> SplitDriver for ReliableSplitDriver<S> {
+    type Error = ReliableSplitError<S::Error>;
+
+    async fn init(&mut self) -> Result<(), Self::Error> {
+        self.inner.init().await.map_err(ReliableSplitError::Inner)
+    }
+
+    async fn wait_recv(&mut self, buf: &mut [u8], is_master: bool) -> Result<(), Self::Error> {
+        loop {
+            if let Some(payload) = self.received.pop_front() {
+                let len = buf.len().min(payload.len());
+                buf[..len].copy_from_slice(&payload[..len]);
+                return Ok(());
+            }
+            match self.step(is_master).await {
+                // The send of the frame was cancelled, so nobody waits for the error.
+                Err(ReliableSplitError::NoAck) => {}
+                result => result?,
+            }
+        }
+    }
+
+    async fn send(&mut self, buf: &[u8], is_master: bool) -> Result<(), Self::Error> {
+        let timeout = if is_master {
+            ACK_TIMEOUT_MASTER
+        } else {
+            ACK_TIMEOUT_SLAVE
+        };
+        if is_hello(buf) {
+            return self
+                .inner
+                .send(buf, is_master)
+                .await
+                .map_err(ReliableSplitError::Inner);
+        }
+        // A previous send may have been cancelled while waiting for its ack. It is finished first,
+        // so that the frames stay in order.
+        while self.protocol.is_busy() {
+            match self.step(is_master).await {
+                Err(ReliableSplitError::NoAck) => {}
+                result => result?,
+            }
+        }
+        let frame = self
+            .protocol
+            .send(buf, Instant::now(), timeout)
+            .map_err(|_| ReliableSplitError::FrameTooLarge)?;
+        self.inner
+            .send(frame, is_master)
+            .await
+            .map_err(ReliableSplitError::Inner)?;
+        while self.protocol.is_busy() {
+            self.step(is_master).await?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use embassy_futures::{
+        block_on,
+        join::join,
+        select::{select, Either},
+    };
+    use embassy_sync::blocking_mutex::raw::NoopRawMutex;
+
+    use super::*;
+    use crate::split::{
+        link::{loopback, Link},
+        MSG_HELLO, TAG_KEYBALL,
+    };
+
+    type TestLink = Link<NoopRawMutex, 8>;
+
+    /// Runs `f` while `a` and `b` are connected by [`loopback`].
+    fn connected<R>(a: &TestLink, b: &TestLink, f: impl core::future::Future<Output = R>) -> R {
+        match block_on(select(loopback(a, b), f)) {
+            Either::First(never) => never,
+            Either::Second(result) => result,
+        }
+    }
+
+    const TIMEOUT: Duration = Duration::from_millis(10);
+
+    fn at(ms: u64) -> Instant {
+        Instant::from_millis(ms)
+    }
+
+    /// Sends `payload` from `sender` and returns the frame on the wire.
+    fn send(sender: &mut Protocol, payload: &[u8], now: Instant) -> RawFrame {
+        RawFrame::from_slice(sender.send(payload, now, TIMEOUT).unwrap()).unwrap()
+    }
+
+    /// Sends `payload` from `sender` to `receiver` and back with the ack, without any loss.
+    fn deliver(sender: &mut Protocol, receiver: &mut Protocol, payload: &[u8]) {
+        let frame = send(sender, payload, at(0));
+        let Received::Data { payload: got, ack } = receiver.receive(&frame) else {
+            panic!("not delivered");
+        };
+        assert_eq!(got, payload);
+        assert_eq!(sender.receive(&ack), Received::Acked);
+    }
+
+    #[test]
+    fn frames_are_delivered_and_acked() {
+        let (mut a, mut b) = (Protocol::new(1), Protocol::new(1));
+        deliver(&mut a, &mut b, &[1, 2, 3]);
+        assert_eq!(a.poll(at(100)), Poll::Idle);
+        deliver(&mut b, &mut a, &[4]);
+        deliver(&mut a, &mut b, &[]);
+    }
+
+    #[test]
+    fn lost_frames_are_sent_again() {
+        let (mut a, mut b) = (Protocol::new(1), Protocol::new(1));
+        let frame = send(&mut a, &[1], at(0));
+        assert_eq!(a.poll(at(9)), Poll::Waiting(at(10)));
+        assert_eq!(a.poll(at(10)), Poll::Retransmit(&frame));
+        assert_eq!(a.poll(at(19)), Poll::Waiting(at(20)));
+
+        let Received::Data { payload, ack } = b.receive(&frame) else {
+            panic!("not delivered");
+        };
+        assert_eq!(payload, [1]);
+        assert_eq!(a.receive(&ack), Received::Acked);
+    }
+
+    #[test]
+    fn lost_acks_deliver_the_payload_once() {
+        let (mut a, mut b) = (Protocol::new(1), Protocol::new(1));
+        let frame = send(&mut a, &[1], at(0));
+        assert!(matches!(b.receive(&frame), Received::Data { .. }));
+
+        let Poll::Retransmit(again) = a.poll(at(10)) else {
+            panic!("not sent again");
+        };
+        let Received::Duplicate { ack } = b.receive(again) else {
+            panic!("delivered twice");
+        };
+        assert_eq!(a.receive(&ack), Received::Acked);
+        assert_eq!(a.receive(&ack), Received::Ignored);
+        deliver(&mut a, &mut b, &[2]);
+    }
+
+    #[test]
+    fn corrupted_frames_are_rejected() {
+        let (mut a, mut b) = (Protocol::new(1), Protocol::new(1));
+        let frame = send(&mut a, &[1, 2, 3], at(0));
+        for i in 0..frame.len() {
+            let mut corrupted = frame.clone();
+            corrupted[i] ^= 0x10;
+            assert!(matches!(b.receive(&corrupted), Received::Error(_)));
+        }
+        let mut corrupted = frame.clone();
+        corrupted[HEADER_LEN + 1] ^= 0xff;
+        assert_eq!(b.receive(&corrupted), Received::Error(DecodeError::Crc));
+        assert_eq!(
+            b.receive(&frame[..frame.len() - 1]),
+            Received::Error(DecodeError::Malformed)
+        );
+        assert!(matches!(b.receive(&frame), Received::Data { .. }));
+    }
+
+    #[test]
+    fn frames_are_given_up_after_max_attempts() {
+        let mut a = Protocol::new(1);
+        let frame = send(&mut a, &[1], at(0));
+        assert_eq!(a.send(&[2], at(0), TIMEOUT), Err(SendError::Busy));
+        for attempt in 1..MAX_ATTEMPTS as u64 {
+            assert_eq!(a.poll(at(attempt * 10)), Poll::Retransmit(&frame));
+        }
+        assert_eq!(a.poll(at(MAX_ATTEMPTS as u64 * 10)), Poll::GaveUp);
+        assert_eq!(a.poll(at(100)), Poll::Idle);
+        assert!(a.send(&[2], at(100), TIMEOUT).is_ok());
+    }
+
+    #[test]
+    fn too_large_payloads_are_rejected() {
+        let mut a = Protocol::new(1);
+        let payload = [0; MAX_FRAME_SIZE + 1];
+        assert_eq!(a.send(&payload, at(0), TIMEOUT), Err(SendError::TooLarge));
+        assert_eq!(a.poll(at(0)), Poll::Idle);
+    }
+
+    #[test]
+    fn sequence_numbers_wrap() {
+        let (mut a, mut b) = (Protocol::new(1), Protocol::new(1));
+        for i in 0..600_u32 {
+            deliver(&mut a, &mut b, &i.to_le_bytes());
+        }
+    }
+
+    #[test]
+    fn frames_of_a_new_session_are_delivered_after_a_reset() {
+        let (mut a, mut b) = (Protocol::new(1), Protocol::new(1));
+        deliver(&mut a, &mut b, &[1]);
+
+        // Reset right after the first frame, which the new session repeats.
+        let mut a = Protocol::new(2);
+        deliver(&mut a, &mut b, &[1]);
+
+        // Once the sequence number wrapped, the last frame has the number of the first frame of a
+        // new session.
+        for _ in 1..256 {
+            deliver(&mut a, &mut b, &[2]);
+        }
+        let mut a = Protocol::new(3);
+        deliver(&mut a, &mut b, &[2]);
+    }
+
+    #[test]
+    fn acks_of_another_session_are_ignored() {
+        let (mut a, mut b) = (Protocol::new(1), Protocol::new(1));
+        let frame = send(&mut a, &[1], at(0));
+        let Received::Data { ack, .. } = b.receive(&frame) else {
+            panic!("not delivered");
+        };
+        let mut a = Protocol::new(2);
+        send(&mut a, &[2], at(0));
+        assert_eq!(a.receive(&ack), Received::Ignored);
+    }
+
+    /// xorshift32, so that the faults of a seed can be replayed.
+    struct Rng(u32);
+
+    impl Rng {
+        fn next(&mut self) -> u32 {
+            self.0 ^= self.0 << 13;
+            self.0 ^= self.0 >> 17;
+            self.0 ^= self.0 << 5;
+            self.0
+        }
+
+        fn chance(&mut self, percent: u32) -> bool {
+            self.next() % 100 < percent
+        }
+    }
+
+    /// One direction of a line which drops, duplicates and corrupts frames.
+    fn transmit(rng: &mut Rng, line: &mut std::collections::VecDeque<RawFrame>, frame: &[u8]) {
+        if rng.chance(20) {
+            return;
+        }
+        let mut frame = RawFrame::from_slice(frame).unwrap();
+        if rng.chance(10) {
+            let bit = rng.next() as usize % (frame.len() * 8);
+            frame[bit / 8] ^= 1 << (bit % 8);
+        }
+        if rng.chance(10) {
+            line.push_back(frame.clone());
+        }
+        line.push_back(frame);
+    }
+
+    /// One half of [`lossy_lines_deliver_in_order_and_once`].
+    struct Half {
+        protocol: Protocol,
+        to_send: core::ops::Range<u16>,
+        /// Payload in flight and its transmissions.
+        sending: Option<(u16, u8)>,
+        given_up: std::vec::Vec<u16>,
+        delivered: std::vec::Vec<u16>,
+    }
+
+    impl Half {
+        fn new(session: u8, count: u16) -> Self {
+            Self {
+                protocol: Protocol::new(session),
+                to_send: 0..count,
+                sending: None,
+                given_up: std::vec::Vec::new(),
+                delivered: std::vec::Vec::new(),
+            }
+        }
+
+        /// Sends or retransmits on `line` and handles the frames on `incoming`.
+        fn step(
+            &mut self,
+            rng: &mut Rng,
+            now: Instant,
+            incoming: &mut std::collections::VecDeque<RawFrame>,
+            line: &mut std::collections::VecDeque<RawFrame>,
+        ) {
+            while let Some(frame) = incoming.pop_front() {
+                match self.protocol.receive(&frame) {
+                    Received::Data { payload, ack } => {
+                        self.delivered
+                            .push(u16::from_le_bytes([payload[0], payload[1]]));
+                        transmit(rng, line, &ack);
+                    }
+                    Received::Duplicate { ack } => transmit(rng, line, &ack),
+                    Received::Acked => self.sending = None,
+                    Received::Ignored | Received::Error(_) => {}
+                }
+            }
+            match self.protocol.poll(now) {
+                Poll::Idle => {
+                    assert_eq!(self.sending, None);
+                    if let Some(value) = self.to_send.next() {
+                        let frame = self.protocol.send(&value.to_le_bytes(), now, TIMEOUT);
+                        transmit(rng, line, frame.unwrap());
+                        self.sending = Some((value, 1));
+                    }
+                }
+                Poll::Waiting(_) => {}
+                Poll::Retransmit(frame) => {
+                    let (_, attempts) = self.sending.as_mut().unwrap();
+                    *attempts += 1;
+                    assert!(*attempts <= MAX_ATTEMPTS);
+                    transmit(rng, line, frame);
+                }
+                Poll::GaveUp => {
+                    let (value, attempts) = self.sending.take().unwrap();
+                    assert_eq!(attempts, MAX_ATTEMPTS);
+                    self.given_up.push(value);
+                }
+            }
+        }
+
+        /// Checks what the other half delivered of the payloads of this half.
+        fn check_delivered_by(&self, other: &Half, count: u16) {
+            assert!(other.delivered.windows(2).all(|pair| pair[0] < pair[1]));
+            for value in 0..count {
+                // A payload given up may still have arrived, if only its acks were lost.
+                assert!(other.delivered.contains(&value) || self.given_up.contains(&value));
+            }
+        }
+    }
+
+    #[test]
+    fn lossy_lines_deliver_in_order_and_once() {
+        const COUNT: u16 = 300;
+        let mut given_up = 0;
+        for seed in 1..=20 {
+            let mut rng = Rng(seed);
+            let (mut a, mut b) = (Half::new(1, COUNT), Half::new(1, COUNT));
+            let (mut a_to_b, mut b_to_a) = Default::default();
+            let mut now = 0;
+            while !(a.to_send.is_empty() && b.to_send.is_empty())
+                || a.sending.is_some()
+                || b.sending.is_some()
+            {
+                a.step(&mut rng, at(now), &mut b_to_a, &mut a_to_b);
+                b.step(&mut rng, at(now), &mut a_to_b, &mut b_to_a);
+                now += 1;
+            }
+            a.check_delivered_by(&b, COUNT);
+            b.check_delivered_by(&a, COUNT);
+            given_up += a.given_up.len() + b.given_up.len();
+        }
+        // The faults are frequent enough to give up on some payloads.
+        assert!(given_up > 0);
+    }
+
+    #[test]
+    fn rejected_payloads_are_delivered_again() {
+        let (mut a, mut b) = (Protocol::new(1), Protocol::new(1));
+        let frame = send(&mut a, &[1], at(0));
+        assert!(matches!(b.receive(&frame), Received::Data { .. }));
+        b.reject_last();
+        assert!(matches!(b.receive(&frame), Received::Data { .. }));
+    }
+
+    #[test]
+    fn cancelled_sends_are_finished_in_order() {
+        let (a, b) = (TestLink::new(), TestLink::new());
+        let mut sender = ReliableSplitDriver::new(a.split_driver());
+        let mut receiver = ReliableSplitDriver::new(b.split_driver());
+        connected(&a, &b, async {
+            // Cancelled while waiting for the ack.
+            let cancelled = select(sender.send(&[1], true), core::future::ready(())).await;
+            assert!(matches!(cancelled, Either::Second(())));
+
+            let receive = async {
+                let mut first = [0; 1];
+                let mut second = [0; 1];
+                receiver.wait_recv(&mut first, false).await.unwrap();
+                receiver.wait_recv(&mut second, false).await.unwrap();
+                (first, second)
+            };
+            let (sent, received) = join(sender.send(&[2], true), receive).await;
+            sent.unwrap();
+            assert_eq!(received, ([1], [2]));
+        });
+    }
+
+    #[test]
+    fn hellos_pass_through_unframed() {
+        let (a, b) = (TestLink::new(), TestLink::new());
+        let (mut raw, mut driver) = (a.split_driver(), ReliableSplitDriver::new(b.split_driver()));
+        let hello = [TAG_KEYBALL, MSG_HELLO, 1, 2, 3, 4, 5];
+        connected(&a, &b, async {
+            raw.send(&hello, true).await.unwrap();
+            let mut buf = [0; 7];
+            driver.wait_recv(&mut buf, false).await.unwrap();
+            assert_eq!(buf, hello);
+
+            // No ack is awaited.
+            driver.send(&hello, false).await.unwrap();
+            raw.wait_recv(&mut buf, true).await.unwrap();
+            assert_eq!(buf, hello);
+        });
+    }
+
+    #[test]
+    fn corrupted_frames_are_counted_and_dropped() {
+        let (a, b) = (TestLink::new(), TestLink::new());
+        let (mut raw, mut driver) = (a.split_driver(), ReliableSplitDriver::new(b.split_driver()));
+        connected(&a, &b, async {
+            let before = health::stats().crc_failures;
+            let mut corrupted = encode(KIND_DATA, 1, 0, &[1, 2, 3]);
+            corrupted[HEADER_LEN] ^= 0xff;
+            raw.send(&corrupted, true).await.unwrap();
+            let frame = encode(KIND_DATA, 1, 0, &[4, 5, 6]);
+            raw.send(&frame, true).await.unwrap();
+
+            let mut buf = [0; 3];
+            driver.wait_recv(&mut buf, false).await.unwrap();
+            assert_eq!(buf, [4, 5, 6]);
+            assert_eq!(health::stats().crc_failures, before + 1);
+
+            let mut ack = [0; RAW_FRAME_SIZE];
+            raw.wait_recv(&mut ack, true).await.unwrap();
+            assert_eq!(decode(&ack), Ok(Frame::Ack { session: 1, seq: 0 }));
+        });
+    }
+}
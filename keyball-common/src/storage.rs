@@ -0,0 +1,41 @@
+//! Keys of the values Keyball keeps in the rktk storage.
+//!
+//! Keys of rktk itself are small numbers, so Keyball keys have "KB" in their upper bytes to stay
+//! out of their way.
+
+use rktk::drivers::interface::storage::StorageDriver;
+
+const fn key(id: u16) -> u64 {
+    0x4b42_0000_0000_0000 | id as u64
+}
+
+pub const HAND: u64 = key(0);
+pub const BOND_SLOTS: u64 = key(1);
+pub const RESET_REASON: u64 = key(2);
+pub const SETTINGS: u64 = key(3);
+
+/// Slot of the crash log ring buffer.
+pub const fn crash_log(slot: u8) -> u64 {
+    key(0x100 | slot as u16)
+}
+
+/// Bond with a peer of the BLE split link.
+pub const fn split_bond(slot: u8) -> u64 {
+    key(0x200 | slot as u16)
+}
+
+/// Storage driver which passes everything on to a borrowed one, so that rktk and Keyball tasks can
+/// share the storage.
+pub struct Shared<'a, S: StorageDriver>(pub &'a S);
+
+impl<S: StorageDriver> StorageDriver for Shared<'_, S> {
+    type Error = S::Error;
+
+    async fn read<const N: usize>(&self, key: u64, buf: &mut [u8; N]) -> Result<(), Self::Error> {
+        self.0.read::<N>(key, buf).await
+    }
+
+    async fn write<const N: usize>(&self, key: u64, buf: &[u8; N]) -> Result<(), Self::Error> {
+        self.0.write::<N>(key, buf).await
+    }
+}
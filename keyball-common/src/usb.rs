@@ -0,0 +1,216 @@
+//! USB driver wrapper which picks up the keyboard LED output report sent by the host.
+//!
+//! The HID classes are set up inside rktk and do not expose output reports, so this wraps the
+//! embassy USB driver and watches OUT traffic instead. The host delivers the report either as a
+//! `SET_REPORT(Output)` control request to the keyboard interface or on its interrupt OUT endpoint.
+//! rktk creates the keyboard class before the others, so it has interface [`KEYBOARD_INTERFACE`]
+//! and the first interrupt OUT endpoint.
+//!
+//! Vendor requests to the device which carry the commands of [`host`](crate::host) are answered here
+//! as well and never reach embassy-usb. Other requests are passed on.
+
+use embassy_usb_driver::{
+    ControlPipe, Driver, Endpoint, EndpointAllocError, EndpointError, EndpointInfo, EndpointOut,
+    EndpointType,
+};
+
+use embassy_time::{Duration, Timer};
+
+use crate::{
+    host::{self, Response, MAX_RESPONSE_SIZE},
+    host_leds::{self, HostLeds},
+};
+
+const REQUEST_TYPE_CLASS_INTERFACE_OUT: u8 = 0x21;
+const HID_REQ_SET_REPORT: u8 = 0x09;
+const HID_REPORT_TYPE_OUTPUT: u8 = 0x02;
+
+/// Interface of the keyboard HID class.
+pub const KEYBOARD_INTERFACE: u16 = 0;
+
+const REQUEST_TYPE_MASK: u8 = 0x7f;
+const REQUEST_TYPE_VENDOR_DEVICE: u8 = 0x40;
+const REQUEST_TYPE_DEVICE_TO_HOST: u8 = 0x80;
+
+/// Time for the status stage to complete before a command resets the half.
+const RESET_DELAY: Duration = Duration::from_millis(10);
+
+pub struct LedReportDriver<D> {
+    inner: D,
+    /// Whether the OUT endpoint of the keyboard has been allocated.
+    keyboard_out_allocated: bool,
+}
+
+impl<D> LedReportDriver<D> {
+    pub fn new(inner: D) -> Self {
+        Self {
+            inner,
+            keyboard_out_allocated: false,
+        }
+    }
+}
+
+impl<'a, D: Driver<'a>> Driver<'a> for LedReportDriver<D> {
+    type EndpointOut = LedReportEndpointOut<D::EndpointOut>;
+    type EndpointIn = D::EndpointIn;
+    type ControlPipe = LedReportControlPipe<D::ControlPipe>;
+    type Bus = D::Bus;
+
+    fn alloc_endpoint_out(
+        &mut self,
+        ep_type: EndpointType,
+        max_packet_size: u16,
+        interval_ms: u8,
+    ) -> Result<Self::EndpointOut, EndpointAllocError> {
+        let inner = self
+            .inner
+            .alloc_endpoint_out(ep_type, max_packet_size, interval_ms)?;
+        let keyboard = ep_type == EndpointType::Interrupt && !self.keyboard_out_allocated;
+        self.keyboard_out_allocated |= keyboard;
+        Ok(LedReportEndpointOut {
+            inner,
+            watch: keyboard,
+        })
+    }
+
+    fn alloc_endpoint_in(
+        &mut self,
+        ep_type: EndpointType,
+        max_packet_size: u16,
+        interval_ms: u8,
+    ) -> Result<Self::EndpointIn, EndpointAllocError> {
+        self.inner
+            .alloc_endpoint_in(ep_type, max_packet_size, interval_ms)
+    }
+
+    fn start(self, control_max_packet_size: u16) -> (Self::Bus, Self::ControlPipe) {
+        let (bus, control) = self.inner.start(control_max_packet_size);
+        (
+            bus,
+            LedReportControlPipe {
+                inner: control,
+                set_report_pending: false,
+            },
+        )
+    }
+}
+
+pub struct LedReportEndpointOut<E> {
+    inner: E,
+    watch: bool,
+}
+
+impl<E: EndpointOut> Endpoint for LedReportEndpointOut<E> {
+    fn info(&self) -> &EndpointInfo {
+        self.inner.info()
+    }
+
+    async fn wait_enabled(&mut self) {
+        self.inner.wait_enabled().await
+    }
+}
+
+impl<E: EndpointOut> EndpointOut for LedReportEndpointOut<E> {
+    async fn read(&mut self, buf: &mut [u8]) -> Result<usize, EndpointError> {
+        let len = self.inner.read(buf).await?;
+        if self.watch && len == 1 {
+            host_leds::report(HostLeds(buf[0]));
+        }
+        Ok(len)
+    }
+}
+
+pub struct LedReportControlPipe<C> {
+    inner: C,
+    set_report_pending: bool,
+}
+
+impl<C: ControlPipe> LedReportControlPipe<C> {
+    async fn handle_vendor_request(&mut self, req: [u8; 8]) {
+        let value = u16::from_le_bytes([req[2], req[3]]);
+        let length = u16::from_le_bytes([req[6], req[7]]) as usize;
+        let mut buf = [0; MAX_RESPONSE_SIZE];
+        match host::handle(req[1], value, &mut buf) {
+            Response::Data(len) => {
+                let data = &buf[..len.min(length)];
+                let chunk_size = self.inner.max_packet_size();
+                let mut sent = 0;
+                loop {
+                    let end = (sent + chunk_size).min(data.len());
+                    let chunk = &data[sent..end];
+                    // A short packet ends the transfer. If the data is shorter than requested and
+                    // ends on a full packet, an empty packet follows.
+                    let last = end == data.len() && (chunk.len() < chunk_size || end == length);
+                    let result = self.inner.data_in(chunk, sent == 0, last).await;
+                    if result.is_err() || last {
+                        return;
+                    }
+                    sent = end;
+                }
+            }
+            Response::Reset => {
+                self.inner.accept().await;
+                Timer::after(RESET_DELAY).await;
+                cortex_m::peripheral::SCB::sys_reset();
+            }
+            Response::Reject => self.inner.reject().await,
+        }
+    }
+}
+
+impl<C: ControlPipe> ControlPipe for LedReportControlPipe<C> {
+    fn max_packet_size(&self) -> usize {
+        self.inner.max_packet_size()
+    }
+
+    async fn setup(&mut self) -> [u8; 8] {
+        loop {
+            let req = self.inner.setup().await;
+            if req[0] & REQUEST_TYPE_MASK == REQUEST_TYPE_VENDOR_DEVICE
+                && host::is_command(req[1], req[0] & REQUEST_TYPE_DEVICE_TO_HOST != 0)
+            {
+                self.handle_vendor_request(req).await;
+                continue;
+            }
+            let index = u16::from_le_bytes([req[4], req[5]]);
+            let length = u16::from_le_bytes([req[6], req[7]]);
+            // The keyboard report has no report ID.
+            self.set_report_pending = req[0] == REQUEST_TYPE_CLASS_INTERFACE_OUT
+                && req[1] == HID_REQ_SET_REPORT
+                && req[2] == 0
+                && req[3] == HID_REPORT_TYPE_OUTPUT
+                && index == KEYBOARD_INTERFACE
+                && length == 1;
+            return req;
+        }
+    }
+
+    async fn data_out(
+        &mut self,
+        buf: &mut [u8],
+        first: bool,
+        last: bool,
+    ) -> Result<usize, EndpointError> {
+        let len = self.inner.data_out(buf, first, last).await?;
+        if core::mem::take(&mut self.set_report_pending) && len == 1 {
+            host_leds::report(HostLeds(buf[0]));
+        }
+        Ok(len)
+    }
+
+    async fn data_in(&mut self, data: &[u8], first: bool, last: bool) -> Result<(), EndpointError> {
+        self.inner.data_in(data, first, last).await
+    }
+
+    async fn accept(&mut self) {
+        self.inner.accept().await
+    }
+
+    async fn reject(&mut self) {
+        self.inner.reject().await
+    }
+
+    async fn accept_set_address(&mut self, addr: u8) {
+        self.inner.accept_set_address(addr).await
+    }
+}
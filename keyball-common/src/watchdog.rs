@@ -0,0 +1,65 @@
+//! Feeding of the hardware watchdog.
+//!
+//! The MCU crates pass the start of the watchdog with [`TIMEOUT`] and its feeding to [`run`], which
+//! starts it once every critical task checked in, so that a slow start of rktk does not reset the
+//! half. The watchdog is then only fed while every critical task keeps calling [`check_in`], so that
+//! a task which hangs, e.g. on the split link, resets the half instead of leaving it dead. Tasks
+//! which not every build runs are only waited for once [`watch`] was called for them.
+
+use core::sync::atomic::{AtomicBool, Ordering};
+
+use embassy_time::{Duration, Timer};
+
+pub const TIMEOUT: Duration = Duration::from_secs(4);
+
+/// How often the check-ins are looked at. A task has to check in at least this often.
+const CHECK_INTERVAL: Duration = Duration::from_secs(1);
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Task {
+    /// The main loop of rktk, which scans the keys.
+    Keyscan,
+    /// The split loop of rktk, which receives and sends the frames of the other half.
+    Split,
+}
+
+const TASK_COUNT: usize = 2;
+
+static ALIVE: [AtomicBool; TASK_COUNT] = [const { AtomicBool::new(false) }; TASK_COUNT];
+static WATCHED: [AtomicBool; TASK_COUNT] = [AtomicBool::new(true), AtomicBool::new(false)];
+
+/// Makes the watchdog wait for check-ins of `task`. Only needed for tasks other than
+/// [`Task::Keyscan`].
+pub fn watch(task: Task) {
+    ALIVE[task as usize].store(true, Ordering::Relaxed);
+    WATCHED[task as usize].store(true, Ordering::Relaxed);
+}
+
+/// Tells that `task` is still running.
+pub fn check_in(task: Task) {
+    ALIVE[task as usize].store(true, Ordering::Relaxed);
+}
+
+/// Starts the watchdog with `start` once all tasks checked in, and then calls `feed` with it as long
+/// as they keep checking in.
+pub async fn run<W>(start: impl FnOnce() -> W, mut feed: impl FnMut(&mut W)) -> ! {
+    let mut start = Some(start);
+    let mut watchdog = None;
+    loop {
+        Timer::after(CHECK_INTERVAL).await;
+        let all_alive = ALIVE.iter().zip(&WATCHED).all(|(alive, watched)| {
+            alive.load(Ordering::Relaxed) || !watched.load(Ordering::Relaxed)
+        });
+        if !all_alive {
+            continue;
+        }
+        for alive in &ALIVE {
+            alive.store(false, Ordering::Relaxed);
+        }
+        if let Some(start) = start.take() {
+            watchdog = Some(start());
+        } else if let Some(watchdog) = &mut watchdog {
+            feed(watchdog);
+        }
+    }
+}
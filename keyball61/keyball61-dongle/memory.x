@@ -0,0 +1,19 @@
+/* Layout for nRF52840 dongles with the Adafruit nRF52 bootloader and SoftDevice S140 v6, same as nice!nano */
+
+__softdevice_end = 0x00026000;
+__storage_start = 0x000EC000;
+__storage_end = 0x000F4000;
+__bootloader_start = 0x000F4000;
+
+MEMORY
+{
+  /* Application only. Storage and bootloader regions are above. */
+  FLASH : ORIGIN = 0x00026000, LENGTH = 792K
+  /* RAM MAX: 256K (0x40000) */
+  RAM : ORIGIN = 0x20008000, LENGTH = 200K
+}
+
+ASSERT(ORIGIN(FLASH) >= __softdevice_end, "application overlaps the SoftDevice");
+ASSERT(ORIGIN(FLASH) + LENGTH(FLASH) <= __storage_start, "application overlaps the storage region");
+ASSERT(__storage_start % 4096 == 0 && __storage_end % 4096 == 0, "storage region is not page aligned");
+ASSERT(__storage_end <= __bootloader_start, "storage region overlaps the bootloader");
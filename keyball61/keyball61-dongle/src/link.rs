@@ -0,0 +1,108 @@
+//! BLE links to both halves.
+//!
+//! The dongle is the central of two connections to halves built with the `dongle` feature of
+//! keyball61-nrf52840. It pairs with the first two halves it finds and connects only to them from
+//! then on. The keys of a half are released when its connection drops.
+
+use core::cell::Cell;
+
+use embassy_sync::{
+    blocking_mutex::{raw::CriticalSectionRawMutex, Mutex as BlockingMutex},
+    mutex::Mutex,
+};
+use embassy_time::{Duration, Timer};
+use nrf_softdevice::{
+    ble::{central, gatt_client, Address},
+    Softdevice,
+};
+
+use keyball61_nrf_common::{
+    split_bond::{wait_encrypted, SplitBonder},
+    split_service::{
+        advertises_split_service, SplitServiceClient, SplitServiceClientEvent, ATT_MTU,
+    },
+};
+use keyball_common::dongle::{self, DongleEvent, HALF_COUNT};
+
+/// Bonds with both halves.
+pub static BONDER: SplitBonder<HALF_COUNT> = SplitBonder::new();
+
+const RETRY_INTERVAL: Duration = Duration::from_millis(100);
+
+/// The SoftDevice scans for one connection at a time.
+static SCAN: Mutex<CriticalSectionRawMutex, ()> = Mutex::new(());
+
+/// Addresses of the connected halves, so that the other task does not connect to the same one.
+static CONNECTED: BlockingMutex<CriticalSectionRawMutex, Cell<[Option<[u8; 6]>; HALF_COUNT]>> =
+    BlockingMutex::new(Cell::new([None; HALF_COUNT]));
+
+/// Keeps a connection to one half. Spawned once for each half.
+#[embassy_executor::task(pool_size = HALF_COUNT)]
+pub async fn task(sd: &'static Softdevice, half: usize) -> ! {
+    loop {
+        run(sd, half).await;
+        dongle::half_lost(half);
+        CONNECTED.lock(|c| {
+            let mut connected = c.get();
+            connected[half] = None;
+            c.set(connected);
+        });
+        Timer::after(RETRY_INTERVAL).await;
+    }
+}
+
+async fn run(sd: &'static Softdevice, half: usize) {
+    let conn = {
+        let _scan = SCAN.lock().await;
+
+        let scan_config = central::ScanConfig::default();
+        let Ok(address) = central::scan(sd, &scan_config, |report| {
+            let data = unsafe {
+                core::slice::from_raw_parts(report.data.p_data, report.data.len as usize)
+            };
+            let address = Address::from_raw(report.peer_addr);
+            let taken = CONNECTED.lock(|c| c.get().contains(&Some(address.bytes())));
+            (!taken && BONDER.accepts(&address) && advertises_split_service(data))
+                .then_some(address)
+        })
+        .await
+        else {
+            return;
+        };
+
+        let whitelist = [&address];
+        let mut config = central::ConnectConfig::default();
+        config.scan_config.whitelist = Some(&whitelist);
+        config.att_mtu = Some(ATT_MTU);
+        let Ok(conn) = central::connect_with_security(sd, &config, &BONDER).await else {
+            return;
+        };
+        CONNECTED.lock(|c| {
+            let mut connected = c.get();
+            connected[half] = Some(address.bytes());
+            c.set(connected);
+        });
+        conn
+    };
+
+    if !BONDER.secure(&conn) || !wait_encrypted(&conn).await {
+        let _ = conn.disconnect();
+        return;
+    }
+
+    let Ok(client) = gatt_client::discover::<SplitServiceClient>(&conn).await else {
+        return;
+    };
+    if client.to_central_cccd_write(true).await.is_err() {
+        return;
+    }
+
+    gatt_client::run(&conn, &client, |event| match event {
+        SplitServiceClientEvent::ToCentralNotification(data) => {
+            if let Some(event) = DongleEvent::decode(&data) {
+                dongle::received(half, event);
+            }
+        }
+    })
+    .await;
+}
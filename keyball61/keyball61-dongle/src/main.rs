@@ -0,0 +1,125 @@
+#![no_std]
+#![no_main]
+
+use core::panic::PanicInfo;
+
+use embassy_executor::Spawner;
+use embassy_nrf::{
+    bind_interrupts,
+    interrupt::{self, InterruptExt, Priority},
+    peripherals::USBD,
+    usb::vbus_detect::SoftwareVbusDetect,
+};
+use once_cell::sync::OnceCell;
+
+use rktk::{drivers::Drivers, none_driver};
+use rktk_drivers_common::{
+    panic_utils,
+    usb::{CommonUsbDriverBuilder, UsbOpts},
+};
+use rktk_drivers_nrf::{softdevice::flash::get_flash, system::NrfSystemDriver};
+
+use keyball61_nrf_common::{enter_bootloader, read_reset_reason, start_watchdog, FicrDeviceId};
+use keyball_common::{
+    dongle::{RemoteKeyscan, RemoteMouse},
+    hardware_id,
+    usb::LedReportDriver,
+    *,
+};
+
+mod link;
+
+bind_interrupts!(pub struct Irqs {
+    USBD => embassy_nrf::usb::InterruptHandler<USBD>;
+});
+
+include!(concat!(env!("OUT_DIR"), "/usb_identity.rs"));
+
+// The dongle is always plugged in.
+static SOFTWARE_VBUS: OnceCell<SoftwareVbusDetect> = OnceCell::new();
+
+#[embassy_executor::task]
+async fn softdevice_task(sd: &'static nrf_softdevice::Softdevice) -> ! {
+    sd.run().await
+}
+
+#[embassy_executor::main]
+async fn main(spawner: Spawner) {
+    let mut config = embassy_nrf::config::Config::default();
+    config.gpiote_interrupt_priority = Priority::P2;
+    config.time_interrupt_priority = Priority::P2;
+    let p = embassy_nrf::init(config);
+    reset_reason::set_from_hardware(read_reset_reason());
+
+    interrupt::USBD.set_priority(Priority::P2);
+
+    let sd = rktk_drivers_nrf::softdevice::init_sd("keyball61-dongle");
+    spawner.must_spawn(softdevice_task(sd));
+
+    embassy_time::Timer::after_millis(50).await;
+
+    let (flash, cache) = get_flash(sd);
+    let storage = rktk_drivers_nrf::softdevice::flash::create_storage_driver(flash, &cache);
+    crash_log::load(&storage).await;
+    reset_reason::record(&storage).await;
+    link::BONDER.load(&storage).await;
+    if bond::take_clear_all() {
+        link::BONDER.forget();
+    }
+    // Only once the bonds are known, so that no other device is paired with instead.
+    for half in 0..dongle::HALF_COUNT {
+        spawner.must_spawn(link::task(sd, half));
+    }
+
+    let usb = {
+        let vbus = SOFTWARE_VBUS.get_or_init(|| SoftwareVbusDetect::new(true, true));
+        let driver = LedReportDriver::new(embassy_nrf::usb::Driver::new(p.USBD, Irqs, vbus));
+        let opts = UsbOpts {
+            config: usb_config(&USB_IDENTITY, hardware_id::serial_number(&mut FicrDeviceId)),
+            // 1 kHz, which the halves can not offer over BLE.
+            mouse_poll_interval: 1,
+            kb_poll_interval: 1,
+            driver,
+        };
+        CommonUsbDriverBuilder::new(opts)
+    };
+
+    // Both halves send their input here, so the dongle has no matrix, ball, split or output of its
+    // own. Debouncing is done by the halves.
+    let drivers = Drivers {
+        keyscan: RemoteKeyscan,
+        system: NrfSystemDriver::new(None),
+        mouse_builder: Some(RemoteMouse),
+        usb_builder: Some(usb),
+        display_builder: none_driver!(DisplayBuilder),
+        split: none_driver!(Split),
+        rgb: none_driver!(Rgb),
+        storage: Some(storage::Shared(&storage)),
+        ble_builder: none_driver!(BleBuilder),
+        debounce: none_driver!(Debounce),
+        encoder: none_driver!(Encoder),
+    };
+
+    embassy_futures::join::join4(
+        rktk::task::start(drivers, keymap::KEYMAP, hooks::create_hooks()),
+        bootloader::run(enter_bootloader),
+        watchdog::run(
+            || start_watchdog(p.WDT),
+            |watchdog| {
+                if let Some(handle) = watchdog {
+                    handle.pet();
+                }
+            },
+        ),
+        link::BONDER.run(&storage),
+    )
+    .await;
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    cortex_m::interrupt::disable();
+    crash_log::record_panic(info);
+    panic_utils::save_panic_info(info);
+    cortex_m::peripheral::SCB::sys_reset()
+}
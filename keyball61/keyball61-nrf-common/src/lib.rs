@@ -0,0 +1,11 @@
+//! Parts shared by the nRF52840 builds of Keyball61, which are the halves and the dongle.
+
+#![no_std]
+
+#[cfg(feature = "softdevice")]
+pub mod split_bond;
+#[cfg(feature = "softdevice")]
+pub mod split_service;
+mod system;
+
+pub use system::*;
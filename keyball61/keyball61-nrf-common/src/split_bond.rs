@@ -0,0 +1,249 @@
+//! Bonds of the BLE split link.
+//!
+//! The devices pair with Just Works the first time they meet and encrypt the link with the stored
+//! key from then on. A [`SplitBonder`] which is full pairs with no other device, so a keyboard
+//! nearby which advertises the split service is not taken for a half. The central finds the bond of
+//! a peer by its address. A half has only one peer, so its bond is used whatever the address, as
+//! the address of the other half changes with its bond slot.
+//!
+//! [`BT_CLRA`](keyball_common::keycode::BT_CLRA) forgets the bonds, so that a half can be replaced.
+//! Halves of the dongle run no keymap, so they pair with any central instead and replace their bond.
+
+use core::cell::Cell;
+
+use embassy_sync::{
+    blocking_mutex::{raw::CriticalSectionRawMutex, Mutex},
+    signal::Signal,
+};
+use embassy_time::{with_timeout, Duration, Timer};
+use nrf_softdevice::ble::{
+    security::{IoCapabilities, SecurityHandler},
+    Address, Connection, EncryptionInfo, IdentityKey, MasterId, SecurityMode,
+};
+use rktk::drivers::interface::storage::StorageDriver;
+
+use keyball_common::storage;
+
+/// Time the devices have to encrypt the connection before it is dropped.
+const SECURITY_TIMEOUT: Duration = Duration::from_secs(5);
+
+const ENCODED_LEN: usize = 35;
+
+#[derive(Clone, Copy)]
+struct Bond {
+    peer: Address,
+    master_id: MasterId,
+    key: EncryptionInfo,
+}
+
+impl Bond {
+    fn encode(bond: Option<Bond>) -> [u8; ENCODED_LEN] {
+        let mut buf = [0; ENCODED_LEN];
+        if let Some(bond) = bond {
+            buf[0] = 1;
+            buf[1] = bond.peer.flags;
+            buf[2..8].copy_from_slice(&bond.peer.bytes);
+            buf[8..10].copy_from_slice(&bond.master_id.ediv.to_le_bytes());
+            buf[10..18].copy_from_slice(&bond.master_id.rand);
+            buf[18..34].copy_from_slice(&bond.key.ltk);
+            buf[34] = bond.key.flags;
+        }
+        buf
+    }
+
+    fn decode(buf: &[u8; ENCODED_LEN]) -> Option<Bond> {
+        if buf[0] != 1 {
+            return None;
+        }
+        let mut bytes = [0; 6];
+        bytes.copy_from_slice(&buf[2..8]);
+        let mut rand = [0; 8];
+        rand.copy_from_slice(&buf[10..18]);
+        let mut ltk = [0; 16];
+        ltk.copy_from_slice(&buf[18..34]);
+        Some(Bond {
+            peer: Address {
+                flags: buf[1],
+                bytes,
+            },
+            master_id: MasterId {
+                ediv: u16::from_le_bytes([buf[8], buf[9]]),
+                rand,
+            },
+            key: EncryptionInfo {
+                ltk,
+                flags: buf[34],
+            },
+        })
+    }
+}
+
+/// Security handler of the split link, which keeps bonds with up to `N` peers.
+pub struct SplitBonder<const N: usize> {
+    bonds: Mutex<CriticalSectionRawMutex, Cell<[Option<Bond>; N]>>,
+    changed: Signal<CriticalSectionRawMutex, ()>,
+    /// Whether a new bond replaces the first one when full.
+    replacing: bool,
+}
+
+impl<const N: usize> Default for SplitBonder<N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<const N: usize> SplitBonder<N> {
+    pub const fn new() -> Self {
+        Self {
+            bonds: Mutex::new(Cell::new([None; N])),
+            changed: Signal::new(),
+            replacing: false,
+        }
+    }
+
+    /// Bonder which pairs with any device, replacing the first bond when full.
+    pub const fn replacing() -> Self {
+        Self {
+            bonds: Mutex::new(Cell::new([None; N])),
+            changed: Signal::new(),
+            replacing: true,
+        }
+    }
+
+    fn bonds(&self) -> [Option<Bond>; N] {
+        self.bonds.lock(|bonds| bonds.get())
+    }
+
+    fn is_full(&self) -> bool {
+        self.bonds().iter().all(Option::is_some)
+    }
+
+    /// Bond of the peer at `address`.
+    fn bond_of(&self, address: &Address) -> Option<Bond> {
+        let bonds = self.bonds();
+        let bond = bonds.iter().flatten().find(|bond| bond.peer == *address);
+        match bond {
+            Some(bond) => Some(*bond),
+            None if N == 1 => bonds[0],
+            None => None,
+        }
+    }
+
+    /// Whether the central may connect to the device at `address`.
+    pub fn accepts(&self, address: &Address) -> bool {
+        !self.is_full() || self.bond_of(address).is_some()
+    }
+
+    /// Starts encrypting the link from the central, with the bond of the peer if there is one and
+    /// by pairing otherwise. Returns `false` if the peer must not be paired with.
+    pub fn secure(&self, conn: &Connection) -> bool {
+        match self.bond_of(&conn.peer_address()) {
+            Some(bond) => conn.encrypt(&bond.master_id, &bond.key).is_ok(),
+            None if !self.is_full() => conn.request_security().is_ok(),
+            None => false,
+        }
+    }
+
+    /// Forgets all bonds. Must be called before the split link starts.
+    pub fn forget(&self) {
+        self.bonds.lock(|bonds| bonds.set([None; N]));
+        self.changed.signal(());
+    }
+
+    /// Loads the stored bonds. Must be called before the split link starts.
+    pub async fn load<S: StorageDriver>(&self, storage: &S) {
+        let mut bonds = [None; N];
+        for (i, bond) in bonds.iter_mut().enumerate() {
+            let mut buf = [0; ENCODED_LEN];
+            if storage
+                .read::<ENCODED_LEN>(storage::split_bond(i as u8), &mut buf)
+                .await
+                .is_ok()
+            {
+                *bond = Bond::decode(&buf);
+            }
+        }
+        self.bonds.lock(|b| b.set(bonds));
+    }
+
+    /// Writes changed bonds to storage.
+    pub async fn run<S: StorageDriver>(&self, storage: &S) -> ! {
+        loop {
+            self.changed.wait().await;
+            for (i, bond) in self.bonds().into_iter().enumerate() {
+                let _ = storage
+                    .write::<ENCODED_LEN>(storage::split_bond(i as u8), &Bond::encode(bond))
+                    .await;
+            }
+        }
+    }
+}
+
+impl<const N: usize> SecurityHandler for SplitBonder<N> {
+    fn io_capabilities(&self) -> IoCapabilities {
+        IoCapabilities::None
+    }
+
+    fn can_bond(&self, _conn: &Connection) -> bool {
+        self.replacing || !self.is_full()
+    }
+
+    fn on_bonded(
+        &self,
+        _conn: &Connection,
+        master_id: MasterId,
+        key: EncryptionInfo,
+        peer_id: IdentityKey,
+    ) {
+        let bond = Bond {
+            peer: peer_id.addr,
+            master_id,
+            key,
+        };
+        self.bonds.lock(|bonds| {
+            let mut new = bonds.get();
+            let slot = new
+                .iter()
+                .position(|b| b.is_some_and(|b| b.peer == bond.peer))
+                .or_else(|| new.iter().position(Option::is_none))
+                .or(self.replacing.then_some(0));
+            if let Some(slot) = slot {
+                new[slot] = Some(bond);
+                bonds.set(new);
+            }
+        });
+        self.changed.signal(());
+    }
+
+    fn get_key(&self, _conn: &Connection, master_id: MasterId) -> Option<EncryptionInfo> {
+        self.bonds()
+            .iter()
+            .flatten()
+            .find(|bond| bond.master_id == master_id)
+            .map(|bond| bond.key)
+    }
+}
+
+pub fn is_encrypted(conn: &Connection) -> bool {
+    !matches!(
+        conn.security_mode(),
+        SecurityMode::NoAccess | SecurityMode::Open
+    )
+}
+
+/// Waits until the connection is encrypted. Returns `false` if it was not within
+/// [`SECURITY_TIMEOUT`] or dropped.
+pub async fn wait_encrypted(conn: &Connection) -> bool {
+    let encrypted = async {
+        while !is_encrypted(conn) {
+            if conn.handle().is_none() {
+                return false;
+            }
+            Timer::after(Duration::from_millis(10)).await;
+        }
+        true
+    };
+    with_timeout(SECURITY_TIMEOUT, encrypted)
+        .await
+        .unwrap_or(false)
+}
@@ -0,0 +1,64 @@
+//! GATT service of the BLE split link, which the halves and the dongle use.
+//!
+//! The peripheral notifies frames on `to_central`, and the central writes frames to
+//! `to_peripheral`. A frame always fits into one notification or write.
+
+use heapless::Vec;
+
+use keyball_common::split::MAX_FRAME_SIZE;
+
+/// ATT MTU which fits a whole frame into one notification or write.
+pub const ATT_MTU: u16 = MAX_FRAME_SIZE as u16 + 3;
+
+/// 4b420001-7370-6c69-7400-6b657962616c, little endian.
+const SERVICE_UUID: [u8; 16] = [
+    0x6c, 0x61, 0x62, 0x79, 0x65, 0x6b, 0x00, 0x74, 0x69, 0x6c, 0x70, 0x73, 0x01, 0x00, 0x42, 0x4b,
+];
+
+/// Advertising data of the peripheral.
+#[rustfmt::skip]
+pub const ADV_DATA: [u8; 21] = [
+    0x02, 0x01, 0x06, // Flags: LE General Discoverable, BR/EDR not supported
+    0x11, AD_TYPE_COMPLETE_UUID128, // Complete list of 128-bit service UUIDs
+    0x6c, 0x61, 0x62, 0x79, 0x65, 0x6b, 0x00, 0x74, 0x69, 0x6c, 0x70, 0x73, 0x01, 0x00, 0x42, 0x4b,
+];
+
+const AD_TYPE_COMPLETE_UUID128: u8 = 0x07;
+
+#[nrf_softdevice::gatt_service(uuid = "4b420001-7370-6c69-7400-6b657962616c")]
+pub struct SplitService {
+    #[characteristic(uuid = "4b420002-7370-6c69-7400-6b657962616c", notify)]
+    to_central: Vec<u8, MAX_FRAME_SIZE>,
+    #[characteristic(uuid = "4b420003-7370-6c69-7400-6b657962616c", write_without_response)]
+    to_peripheral: Vec<u8, MAX_FRAME_SIZE>,
+}
+
+#[nrf_softdevice::gatt_server]
+pub struct SplitServer {
+    pub split: SplitService,
+}
+
+#[nrf_softdevice::gatt_client(uuid = "4b420001-7370-6c69-7400-6b657962616c")]
+pub struct SplitServiceClient {
+    #[characteristic(uuid = "4b420002-7370-6c69-7400-6b657962616c", notify)]
+    to_central: Vec<u8, MAX_FRAME_SIZE>,
+    #[characteristic(uuid = "4b420003-7370-6c69-7400-6b657962616c", write_without_response)]
+    to_peripheral: Vec<u8, MAX_FRAME_SIZE>,
+}
+
+/// Whether the advertising data `data` lists the split service.
+pub fn advertises_split_service(mut data: &[u8]) -> bool {
+    while let [len, rest @ ..] = data {
+        let len = *len as usize;
+        if len == 0 || rest.len() < len {
+            return false;
+        }
+        let (field, next) = rest.split_at(len);
+        if field[0] == AD_TYPE_COMPLETE_UUID128 && field[1..].chunks(16).any(|u| u == SERVICE_UUID)
+        {
+            return true;
+        }
+        data = next;
+    }
+    false
+}
@@ -0,0 +1,76 @@
+use embassy_nrf::{
+    peripherals::WDT,
+    wdt::{self, Watchdog, WatchdogHandle},
+};
+
+use keyball_common::{hardware_id::HardwareId, reset_reason::ResetReason, watchdog};
+
+/// 64-bit device ID from FICR.
+pub struct FicrDeviceId;
+
+impl HardwareId for FicrDeviceId {
+    fn hardware_id(&mut self) -> [u8; 8] {
+        let ficr = unsafe { &*embassy_nrf::pac::FICR::ptr() };
+        let high = ficr.deviceid[1].read().bits() as u64;
+        let low = ficr.deviceid[0].read().bits() as u64;
+        (high << 32 | low).to_be_bytes()
+    }
+}
+
+/// Starts the watchdog with [`watchdog::TIMEOUT`]. It keeps running across soft resets and can
+/// then only be taken over with the same configuration, so this fails after a firmware update which
+/// changed it. The watchdog resets the chip soon after in that case, and starts over on the next
+/// boot.
+pub fn start_watchdog(wdt: WDT) -> Option<WatchdogHandle> {
+    let mut config = wdt::Config::default();
+    config.timeout_ticks = 32768 * watchdog::TIMEOUT.as_secs() as u32;
+    config.run_during_sleep = true;
+    config.run_during_debug_halt = false;
+    Watchdog::try_new::<1>(wdt, config)
+        .ok()
+        .map(|(_, [handle])| handle)
+}
+
+/// Reads and clears RESETREAS. Must be called before the SoftDevice is enabled, which protects the
+/// POWER peripheral. Power-on and brown-out leave no flag and cannot be told apart.
+pub fn read_reset_reason() -> ResetReason {
+    let power = unsafe { &*embassy_nrf::pac::POWER::ptr() };
+    let reas = power.resetreas.read();
+    let reason = if reas.dog().is_detected() {
+        ResetReason::Watchdog
+    } else if reas.lockup().is_detected() {
+        ResetReason::Lockup
+    } else if reas.sreq().is_detected() {
+        ResetReason::Software
+    } else if reas.resetpin().is_detected() {
+        ResetReason::Pin
+    } else if reas.off().is_detected() {
+        ResetReason::WakeUp
+    } else if reas.bits() == 0 {
+        ResetReason::PowerOn
+    } else {
+        ResetReason::Unknown
+    };
+    // The flags are kept until cleared, even across further resets.
+    power.resetreas.write(|w| unsafe { w.bits(reas.bits()) });
+    reason
+}
+
+/// GPREGRET value which makes the Adafruit nRF52 bootloader stay in UF2 mode after a reset.
+const DFU_MAGIC_UF2_RESET: u8 = 0x57;
+
+/// Resets into the UF2 mode of the bootloader.
+pub fn enter_bootloader() -> ! {
+    #[cfg(feature = "softdevice")]
+    unsafe {
+        nrf_softdevice::raw::sd_power_gpregret_set(0, DFU_MAGIC_UF2_RESET as u32);
+    }
+    #[cfg(not(feature = "softdevice"))]
+    {
+        let power = unsafe { &*embassy_nrf::pac::POWER::ptr() };
+        power
+            .gpregret
+            .write(|w| unsafe { w.gpregret().bits(DFU_MAGIC_UF2_RESET) });
+    }
+    cortex_m::peripheral::SCB::sys_reset()
+}
@@ -13,14 +13,30 @@ use std::fs::File;
 use std::io::Write;
 use std::path::PathBuf;
 
+#[path = "../usb_identity.rs"]
+mod usb_identity;
+
 fn main() {
     // Put `memory.x` in our output directory and ensure it's
     // on the linker search path.
+    // Each supported board has its own memory layout, which also checks at link time that storage
+    // does not overlap the bootloader.
+    let ble_micro_pro = env::var_os("CARGO_FEATURE_BLE_MICRO_PRO").is_some();
+    let no_softdevice = env::var_os("CARGO_FEATURE_NO_SOFTDEVICE").is_some();
+    let memory_x: Option<&[u8]> = match (ble_micro_pro, no_softdevice) {
+        (false, false) => Some(include_bytes!("memory.x")),
+        (true, false) => Some(include_bytes!("memory-ble-micro-pro.x")),
+        (false, true) => Some(include_bytes!("memory-no-softdevice.x")),
+        // Rejected with a compile error by main.rs.
+        (true, true) => None,
+    };
     let out = &PathBuf::from(env::var_os("OUT_DIR").unwrap());
-    File::create(out.join("memory.x"))
-        .unwrap()
-        .write_all(include_bytes!("memory.x"))
-        .unwrap();
+    if let Some(memory_x) = memory_x {
+        File::create(out.join("memory.x"))
+            .unwrap()
+            .write_all(memory_x)
+            .unwrap();
+    }
     println!("cargo:rustc-link-search={}", out.display());
 
     // By default, Cargo will re-run a build script whenever
@@ -28,6 +44,10 @@ fn main() {
     // here, we ensure the build script is only re-run when
     // `memory.x` is changed.
     println!("cargo:rerun-if-changed=memory.x");
+    println!("cargo:rerun-if-changed=memory-ble-micro-pro.x");
+    println!("cargo:rerun-if-changed=memory-no-softdevice.x");
+
+    usb_identity::write(out);
 
     println!("cargo:rustc-link-arg-bins=--nmagic");
     println!("cargo:rustc-link-arg-bins=-Tlink.x");
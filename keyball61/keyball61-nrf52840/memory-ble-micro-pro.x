@@ -0,0 +1,26 @@
+/* Layout for BLE Micro Pro (SoftDevice S140 v6) */
+/* The storage region is where rktk-drivers-nrf keeps the storage (0xA0000 to 0xA3000) and the BLE */
+/* bonds (0xAA000 to 0xAC000), and where Keyball keeps the bonds of each bond slot (0xAC000 to */
+/* 0xB0000). main.rs checks at boot that they stay inside it. */
+/* 0xE0000 to the bootloader is left untouched, as the stock BLE Micro Pro firmware keeps its files */
+/* there. */
+
+__softdevice_end = 0x00026000;
+__storage_start = 0x000A0000;
+__storage_end = 0x000B0000;
+__stock_files_start = 0x000E0000;
+__bootloader_start = 0x000F4000;
+
+MEMORY
+{
+  /* Application only. Storage and bootloader regions are above. */
+  FLASH : ORIGIN = 0x00026000, LENGTH = 488K
+  /* RAM MAX: 256K (0x40000) */
+  RAM : ORIGIN = 0x20008000, LENGTH = 200K
+}
+
+ASSERT(ORIGIN(FLASH) >= __softdevice_end, "application overlaps the SoftDevice");
+ASSERT(ORIGIN(FLASH) + LENGTH(FLASH) <= __storage_start, "application overlaps the storage region");
+ASSERT(__storage_start % 4096 == 0 && __storage_end % 4096 == 0, "storage region is not page aligned");
+ASSERT(__storage_end <= __stock_files_start, "storage region overlaps the files of the stock firmware");
+ASSERT(__storage_end <= __bootloader_start, "storage region overlaps the bootloader");
@@ -0,0 +1,19 @@
+/* Layout for nice!nano without the SoftDevice (Adafruit nRF52 bootloader, MBR only) */
+
+__mbr_end = 0x00001000;
+__storage_start = 0x000EC000;
+__storage_end = 0x000F4000;
+__bootloader_start = 0x000F4000;
+
+MEMORY
+{
+  /* Application only. Storage and bootloader regions are above. */
+  FLASH : ORIGIN = 0x00001000, LENGTH = 940K
+  /* No RAM is reserved for the SoftDevice. */
+  RAM : ORIGIN = 0x20000000, LENGTH = 256K
+}
+
+ASSERT(ORIGIN(FLASH) >= __mbr_end, "application overlaps the MBR");
+ASSERT(ORIGIN(FLASH) + LENGTH(FLASH) <= __storage_start, "application overlaps the storage region");
+ASSERT(__storage_start % 4096 == 0 && __storage_end % 4096 == 0, "storage region is not page aligned");
+ASSERT(__storage_end <= __bootloader_start, "storage region overlaps the bootloader");
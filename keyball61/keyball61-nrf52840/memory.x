@@ -1,7 +1,22 @@
+/* Layout for nice!nano (Adafruit nRF52 bootloader, SoftDevice S140 v6) */
+/* The storage region is where rktk-drivers-nrf keeps the storage (0xA0000 to 0xA3000) and the BLE */
+/* bonds (0xAA000 to 0xAC000), and where Keyball keeps the bonds of each bond slot (0xAC000 to */
+/* 0xB0000). main.rs checks at boot that they stay inside it. */
+
+__softdevice_end = 0x00026000;
+__storage_start = 0x000A0000;
+__storage_end = 0x000B0000;
+__bootloader_start = 0x000F4000;
+
 MEMORY
 {
-  /* for softdevice v6 */
-  FLASH : ORIGIN = 0x00026000, LENGTH = 828K
+  /* Application only. Storage and bootloader regions are above. */
+  FLASH : ORIGIN = 0x00026000, LENGTH = 488K
   /* RAM MAX: 256K (0x40000) */
   RAM : ORIGIN = 0x20008000, LENGTH = 200K
 }
+
+ASSERT(ORIGIN(FLASH) >= __softdevice_end, "application overlaps the SoftDevice");
+ASSERT(ORIGIN(FLASH) + LENGTH(FLASH) <= __storage_start, "application overlaps the storage region");
+ASSERT(__storage_start % 4096 == 0 && __storage_end % 4096 == 0, "storage region is not page aligned");
+ASSERT(__storage_end <= __bootloader_start, "storage region overlaps the bootloader");
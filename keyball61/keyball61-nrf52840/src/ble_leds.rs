@@ -0,0 +1,64 @@
+//! Keyboard LED output report of the BLE HID service.
+//!
+//! The HID service of rktk accepts writes to its output report but does not pass them on. The
+//! SoftDevice keeps the written value in its attribute table though, so the report is looked up
+//! there by its Report Reference descriptor and polled.
+
+use embassy_time::{Duration, Timer};
+use nrf_softdevice::{ble::gatt_server, raw, Softdevice};
+
+use keyball_common::host_leds::{self, HostLeds};
+
+const POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+const UUID_REPORT: u16 = 0x2a4d;
+const UUID_REPORT_REFERENCE: u16 = 0x2908;
+const REPORT_TYPE_OUTPUT: u8 = 0x02;
+
+/// Value handle of the output report of the HID service, if there is one.
+fn find_output_report(sd: &Softdevice) -> Option<u16> {
+    let mut report = None;
+    for handle in 1..=u16::MAX {
+        let mut uuid = raw::ble_uuid_t { uuid: 0, type_: 0 };
+        let err = unsafe { raw::sd_ble_gatts_attr_get(handle, &mut uuid, core::ptr::null_mut()) };
+        if err != raw::NRF_SUCCESS {
+            // Handles are assigned in order, so the first missing one ends the table.
+            return None;
+        }
+        if uuid.type_ != raw::BLE_UUID_TYPE_BLE as u8 {
+            continue;
+        }
+        match uuid.uuid {
+            UUID_REPORT => report = Some(handle),
+            // The Report Reference of a report follows its value, as [report id, report type].
+            UUID_REPORT_REFERENCE => {
+                let mut reference = [0; 2];
+                let output = gatt_server::get_value(sd, handle, &mut reference)
+                    .is_ok_and(|len| len == 2 && reference[1] == REPORT_TYPE_OUTPUT);
+                if output {
+                    return report;
+                }
+            }
+            _ => {}
+        }
+    }
+    None
+}
+
+/// Reports changes of the LED output report to [`host_leds`]. Must be spawned after the HID service
+/// has been registered.
+#[embassy_executor::task]
+pub async fn task(sd: &'static Softdevice) -> ! {
+    let Some(handle) = find_output_report(sd) else {
+        core::future::pending().await
+    };
+    let mut last = 0;
+    loop {
+        Timer::after(POLL_INTERVAL).await;
+        let mut value = [0; 1];
+        if matches!(gatt_server::get_value(sd, handle, &mut value), Ok(1)) && value[0] != last {
+            last = value[0];
+            host_leds::report(HostLeds(last));
+        }
+    }
+}
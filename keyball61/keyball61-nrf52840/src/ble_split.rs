@@ -0,0 +1,189 @@
+//! Split transport over BLE.
+//!
+//! The master half is the central and connects to the slave, which advertises the split service.
+//! Frames from the master are written to one characteristic, frames from the slave are notified
+//! on the other. Both halves start over whenever the connection drops.
+//!
+//! With the `dongle` feature, the same transport carries the input of this half to the dongle,
+//! which is the central for both halves.
+//!
+//! The connection is encrypted with the bond of [`BONDER`], and no frame is exchanged before.
+//! Frames are never dropped while the connection is up: sending is retried, and a frame which rktk
+//! has no room for drops the connection, so that both halves handle it as a lost link.
+
+use embassy_futures::select::select3;
+use embassy_sync::{
+    blocking_mutex::raw::{CriticalSectionRawMutex, NoopRawMutex},
+    signal::Signal,
+};
+use embassy_time::{Duration, Timer};
+use nrf_softdevice::{
+    ble::{
+        central, gatt_client, gatt_server, gatt_server::NotifyValueError, peripheral, Address,
+        Connection,
+    },
+    Softdevice,
+};
+
+use keyball61_nrf_common::{
+    split_bond::{is_encrypted, wait_encrypted, SplitBonder},
+    split_service::*,
+};
+use keyball_common::split::link::{Link, Role};
+#[cfg(feature = "dongle")]
+use rktk::drivers::interface::split::SplitDriver as _;
+
+/// Frames waiting for the connection in each direction.
+const QUEUE_SIZE: usize = 16;
+
+pub static LINK: Link<CriticalSectionRawMutex, QUEUE_SIZE> = Link::new();
+
+/// Bond with the other half, or with the dongle.
+#[cfg(not(feature = "dongle"))]
+pub static BONDER: SplitBonder<1> = SplitBonder::new();
+#[cfg(feature = "dongle")]
+pub static BONDER: SplitBonder<1> = SplitBonder::replacing();
+
+/// Pause before connecting again after the connection dropped or could not be established.
+const RETRY_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Pause before sending a frame again which the SoftDevice had no buffer for.
+const SEND_RETRY_INTERVAL: Duration = Duration::from_millis(5);
+
+/// Registers the split service. Must be called before the SoftDevice starts running.
+pub fn init(sd: &mut Softdevice) -> SplitServer {
+    SplitServer::new(sd).unwrap()
+}
+
+#[embassy_executor::task]
+pub async fn task(sd: &'static Softdevice, server: SplitServer) -> ! {
+    let role = LINK.role().await;
+    loop {
+        match role {
+            Role::Central => run_central(sd).await,
+            Role::Peripheral => run_peripheral(sd, &server).await,
+        }
+        Timer::after(RETRY_INTERVAL).await;
+    }
+}
+
+/// Sends the input of this half to the dongle. Also makes this half the peripheral of the link.
+#[cfg(feature = "dongle")]
+#[embassy_executor::task]
+pub async fn forward_to_dongle() -> ! {
+    // Advertise right away instead of waiting for the first event.
+    LINK.set_role(Role::Peripheral);
+    let mut driver = LINK.split_driver();
+    loop {
+        let (buf, len) = keyball_common::dongle::next_event().await.encode();
+        let _ = driver.send(&buf[..len], false).await;
+    }
+}
+
+/// Connects to the slave and exchanges frames until the connection drops.
+async fn run_central(sd: &'static Softdevice) {
+    let scan_config = central::ScanConfig::default();
+    let Ok(address) = central::scan(sd, &scan_config, |report| {
+        let data =
+            unsafe { core::slice::from_raw_parts(report.data.p_data, report.data.len as usize) };
+        advertises_split_service(data).then(|| Address::from_raw(report.peer_addr))
+    })
+    .await
+    else {
+        return;
+    };
+
+    let whitelist = [&address];
+    let mut config = central::ConnectConfig::default();
+    config.scan_config.whitelist = Some(&whitelist);
+    config.att_mtu = Some(ATT_MTU);
+    let Ok(conn) = central::connect_with_security(sd, &config, &BONDER).await else {
+        return;
+    };
+    if !BONDER.secure(&conn) || !wait_encrypted(&conn).await {
+        let _ = conn.disconnect();
+        return;
+    }
+
+    let Ok(client) = gatt_client::discover::<SplitServiceClient>(&conn).await else {
+        return;
+    };
+    if client.to_central_cccd_write(true).await.is_err() {
+        return;
+    }
+
+    let overflow = Signal::<NoopRawMutex, ()>::new();
+    select3(
+        gatt_client::run(&conn, &client, |event| match event {
+            SplitServiceClientEvent::ToCentralNotification(data) => {
+                if !LINK.received(&data) {
+                    overflow.signal(());
+                }
+            }
+        }),
+        async {
+            loop {
+                let frame = LINK.next_outgoing().await;
+                loop {
+                    match client.to_peripheral_write_without_response(&frame).await {
+                        Ok(()) => break,
+                        Err(gatt_client::WriteError::Disconnected) => return,
+                        Err(_) => Timer::after(SEND_RETRY_INTERVAL).await,
+                    }
+                }
+            }
+        },
+        overflow.wait(),
+    )
+    .await;
+    let _ = conn.disconnect();
+}
+
+/// Advertises the split service, and exchanges frames with the master once it connected until the
+/// connection drops.
+async fn run_peripheral(sd: &'static Softdevice, server: &SplitServer) {
+    let adv = peripheral::ConnectableAdvertisement::ScannableUndirected {
+        adv_data: &ADV_DATA,
+        scan_data: &[],
+    };
+    let mut config = peripheral::Config::default();
+    config.att_mtu = Some(ATT_MTU);
+    let Ok(conn) = peripheral::advertise_pairable(sd, adv, &config, &BONDER).await else {
+        return;
+    };
+
+    let overflow = Signal::<NoopRawMutex, ()>::new();
+    select3(
+        gatt_server::run(&conn, server, |event| match event {
+            SplitServerEvent::Split(SplitServiceEvent::ToPeripheralWrite(data)) => {
+                // Writes before the connection is encrypted do not come from the other half.
+                if is_encrypted(&conn) && !LINK.received(&data) {
+                    overflow.signal(());
+                }
+            }
+            SplitServerEvent::Split(SplitServiceEvent::ToCentralCccdWrite { .. }) => {}
+        }),
+        async {
+            if wait_encrypted(&conn).await {
+                notify_loop(&conn, server).await;
+            }
+        },
+        overflow.wait(),
+    )
+    .await;
+    let _ = conn.disconnect();
+}
+
+/// Sends frames until the connection drops.
+async fn notify_loop(conn: &Connection, server: &SplitServer) {
+    loop {
+        let frame = LINK.next_outgoing().await;
+        loop {
+            match server.split.to_central_notify(conn, &frame) {
+                Ok(()) => break,
+                Err(NotifyValueError::Disconnected) => return,
+                Err(NotifyValueError::Raw(_)) => Timer::after(SEND_RETRY_INTERVAL).await,
+            }
+        }
+    }
+}
@@ -0,0 +1,104 @@
+//! Bonds of each BLE bond slot.
+//!
+//! rktk-drivers-nrf keeps the bonds of the BLE stack as a single serialized map in its own flash
+//! range. Copies of that map are kept per slot in the range after it, and moved in and out as is,
+//! without being decoded.
+
+use core::ops::Range;
+
+use rktk_drivers_common::storage::flash_sequential_map::sequential_storage::{
+    self, cache::NoCache, map,
+};
+use rktk_drivers_nrf::softdevice::flash::SharedFlash;
+
+use keyball_common::bond::{BondStore, SLOT_COUNT};
+
+/// Where rktk-drivers-nrf keeps its bonds.
+const STACK_BONDS: Range<u32> = 0xa_a000..0xa_c000;
+/// Key of the bond map in [`STACK_BONDS`].
+const STACK_KEY: u8 = 0;
+/// Where the copies of each slot are kept. Must be reserved by `memory.x`.
+pub const SLOT_BONDS: Range<u32> = 0xa_c000..0xb_0000;
+/// Large enough for the bond map of rktk-drivers-nrf, which holds up to 8 peers.
+const MAX_BONDS_LEN: usize = 1024;
+
+pub struct FlashBondStore(pub &'static SharedFlash);
+
+impl BondStore for FlashBondStore {
+    async fn save(&mut self, slot: u8) {
+        let mut flash = self.0.lock().await;
+        let mut buf = [0; MAX_BONDS_LEN];
+        let bonds = match map::fetch_item::<u8, &[u8], _>(
+            &mut *flash,
+            STACK_BONDS,
+            &mut NoCache::new(),
+            &mut buf,
+            &STACK_KEY,
+        )
+        .await
+        {
+            Ok(Some(bonds)) => bonds,
+            _ => &[],
+        };
+        let _ = map::store_item::<u8, &[u8], _>(
+            &mut *flash,
+            SLOT_BONDS,
+            &mut NoCache::new(),
+            &mut [0; MAX_BONDS_LEN],
+            &slot,
+            &bonds,
+        )
+        .await;
+    }
+
+    async fn restore(&mut self, slot: u8) {
+        let mut flash = self.0.lock().await;
+        let mut buf = [0; MAX_BONDS_LEN];
+        let bonds = match map::fetch_item::<u8, &[u8], _>(
+            &mut *flash,
+            SLOT_BONDS,
+            &mut NoCache::new(),
+            &mut buf,
+            &slot,
+        )
+        .await
+        {
+            Ok(Some(bonds)) => bonds,
+            _ => &[],
+        };
+        let _ = sequential_storage::erase_all(&mut *flash, STACK_BONDS).await;
+        if !bonds.is_empty() {
+            let _ = map::store_item::<u8, &[u8], _>(
+                &mut *flash,
+                STACK_BONDS,
+                &mut NoCache::new(),
+                &mut [0; MAX_BONDS_LEN],
+                &STACK_KEY,
+                &bonds,
+            )
+            .await;
+        }
+    }
+
+    async fn clear(&mut self, slot: u8) {
+        let mut flash = self.0.lock().await;
+        let _ = sequential_storage::erase_all(&mut *flash, STACK_BONDS).await;
+        let _ = map::store_item::<u8, &[u8], _>(
+            &mut *flash,
+            SLOT_BONDS,
+            &mut NoCache::new(),
+            &mut [0; MAX_BONDS_LEN],
+            &slot,
+            &&[][..],
+        )
+        .await;
+    }
+
+    async fn clear_all(&mut self) {
+        for slot in 0..SLOT_COUNT {
+            self.clear(slot).await;
+        }
+        #[cfg(feature = "ble-split")]
+        crate::ble_split::BONDER.forget();
+    }
+}
@@ -6,34 +6,58 @@ use core::panic::PanicInfo;
 use embassy_embedded_hal::shared_bus::asynch::spi::SpiDevice;
 use embassy_executor::Spawner;
 use embassy_nrf::{
-    gpio::{Output, Pin},
+    gpio::{AnyPin, Output},
     interrupt::{self, InterruptExt, Priority},
-    peripherals::SPI2,
-    ppi::Group,
+    peripherals::{SAADC, SPI2},
+    saadc::{self, Saadc},
     spim::Spim,
     twim::Twim,
-    usb::vbus_detect::SoftwareVbusDetect,
 };
 use embassy_sync::{blocking_mutex::raw::NoopRawMutex, mutex::Mutex};
-use once_cell::sync::OnceCell;
 
-use rktk::{drivers::Drivers, hooks::create_empty_hooks, none_driver};
+#[cfg(not(feature = "hand-strap"))]
+use rktk::drivers::interface::keyscan::Hand;
+use rktk::{drivers::Drivers, none_driver};
 use rktk_drivers_common::{
-    debounce::EagerDebounceDriver,
     display::ssd1306::Ssd1306DisplayBuilder,
     keyscan::{duplex_matrix::DuplexMatrixScanner, HandDetector},
     mouse::pmw3360::Pmw3360Builder,
     panic_utils,
 };
 use rktk_drivers_nrf::{
-    keyscan::flex_pin::NrfFlexPin, rgb::ws2812_pwm::Ws2812Pwm, softdevice::flash::get_flash,
-    split::uart_half_duplex::UartHalfDuplexSplitDriver, system::NrfSystemDriver,
+    keyscan::flex_pin::NrfFlexPin, rgb::ws2812_pwm::Ws2812Pwm, system::NrfSystemDriver,
 };
 
-use keyball_common::*;
+use keyball61_nrf_common::{enter_bootloader, read_reset_reason, start_watchdog, FicrDeviceId};
+use keyball_common::{
+    battery::BatterySensor,
+    debounce::DebouncedKeyscan,
+    hardware_id,
+    pin_map::{ControllerPins, KeyballPins, ProMicroPin, KEYBALL61_NRF},
+    split::handshake::{self, feature, Board, Profile},
+    usb::LedReportDriver,
+    *,
+};
 
+#[cfg(feature = "softdevice")]
 use nrf_softdevice as _;
 
+#[cfg(all(feature = "softdevice", feature = "no-softdevice"))]
+compile_error!("The softdevice and no-softdevice features are exclusive.");
+#[cfg(not(any(feature = "softdevice", feature = "no-softdevice")))]
+compile_error!("Either the softdevice or the no-softdevice feature is required.");
+#[cfg(all(feature = "ble-micro-pro", feature = "no-softdevice"))]
+compile_error!("The BLE Micro Pro has no memory layout without the SoftDevice yet.");
+
+#[cfg(feature = "ble")]
+mod ble_leds;
+#[cfg(feature = "ble-split")]
+mod ble_split;
+#[cfg(feature = "ble")]
+mod bond_store;
+mod sleep;
+mod vbus;
+
 #[cfg(feature = "ble")]
 mod ble {
     pub use rktk_drivers_nrf::softdevice::ble::init_ble_server;
@@ -53,28 +77,99 @@ bind_interrupts!(pub struct Irqs {
     SPIM2_SPIS2_SPI2 => embassy_nrf::spim::InterruptHandler<SPI2>;
     SPIM0_SPIS0_TWIM0_TWIS0_SPI0_TWI0 => embassy_nrf::twim::InterruptHandler<embassy_nrf::peripherals::TWISPI0>;
     UARTE0_UART0 => embassy_nrf::buffered_uarte::InterruptHandler<embassy_nrf::peripherals::UARTE0>;
+    SAADC => embassy_nrf::saadc::InterruptHandler;
 });
 
-static SOFTWARE_VBUS: OnceCell<SoftwareVbusDetect> = OnceCell::new();
+include!(concat!(env!("OUT_DIR"), "/usb_identity.rs"));
+
+#[cfg(not(feature = "ble-micro-pro"))]
+const PINS: ControllerPins = keyball_common::pin_map::NICE_NANO;
+#[cfg(feature = "ble-micro-pro")]
+const PINS: ControllerPins = keyball_common::pin_map::BLE_MICRO_PRO;
+
+/// Signals of the Keyball61 and the Pro Micro pins they are wired to.
+const KEYBALL: KeyballPins = KEYBALL61_NRF;
+
+// Ball CS and LED data on the pins of the original nice!nano build, P0.06 and P0.09.
+#[cfg(not(feature = "ble-micro-pro"))]
+const _: () = {
+    assert!(PINS.resolve(KEYBALL.ball_cs) == 6);
+    assert!(PINS.resolve(KEYBALL.rgb) == 9);
+};
+
+// The same signals on the BLE Micro Pro, whose TX is P0.13.
+#[cfg(feature = "ble-micro-pro")]
+const _: () = {
+    assert!(PINS.resolve(KEYBALL.ball_cs) == 13);
+    assert!(PINS.resolve(KEYBALL.rgb) == 9);
+};
+
+/// Takes the GPIO wired to `pin`.
+///
+/// Every pin of the map must be taken only once, and not through the peripheral singletons too.
+fn take_pin(pin: ProMicroPin) -> AnyPin {
+    unsafe { AnyPin::steal(PINS.resolve(pin)) }
+}
+
+/// Measures the battery on VDDH, which the battery is connected to in high voltage mode.
+struct VddhBatterySensor(Saadc<'static, 1>);
+
+impl VddhBatterySensor {
+    fn new(saadc: SAADC) -> Self {
+        let mut config = saadc::Config::default();
+        config.resolution = saadc::Resolution::_12BIT;
+        let channel = saadc::ChannelConfig::single_ended(saadc::VddhDiv5Input);
+        Self(Saadc::new(saadc, Irqs, config, [channel]))
+    }
+}
+
+impl BatterySensor for VddhBatterySensor {
+    async fn read_millivolts(&mut self) -> u16 {
+        let mut buf = [0; 1];
+        self.0.sample(&mut buf).await;
+        // Full scale is 3.6V with the internal reference and gain 1/6, and the input is VDDH/5.
+        (buf[0].max(0) as u32 * 3600 * 5 / 4096) as u16
+    }
+}
+
+#[cfg(feature = "softdevice")]
+#[embassy_executor::task]
+async fn softdevice_task(sd: &'static nrf_softdevice::Softdevice) -> ! {
+    sd.run_with_callback(vbus::handle_soc_event).await
+}
+
+/// Storage region reserved by `memory.x`.
+fn storage_range() -> core::ops::Range<u32> {
+    extern "C" {
+        static __storage_start: u8;
+        static __storage_end: u8;
+    }
+    unsafe {
+        core::ptr::addr_of!(__storage_start) as u32..core::ptr::addr_of!(__storage_end) as u32
+    }
+}
 
 #[embassy_executor::main]
-async fn main(_spawner: Spawner) {
+async fn main(spawner: Spawner) {
     let mut config = embassy_nrf::config::Config::default();
     config.gpiote_interrupt_priority = Priority::P2;
     config.time_interrupt_priority = Priority::P2;
     let p = embassy_nrf::init(config);
+    reset_reason::set_from_hardware(read_reset_reason());
+    handshake::set_local(split_profile());
 
     interrupt::USBD.set_priority(Priority::P2);
     interrupt::SPIM2_SPIS2_SPI2.set_priority(Priority::P2);
     interrupt::SPIM0_SPIS0_TWIM0_TWIS0_SPI0_TWI0.set_priority(Priority::P2);
     interrupt::UARTE1.set_priority(Priority::P2);
+    interrupt::SAADC.set_priority(Priority::P2);
 
     let display = Ssd1306DisplayBuilder::new(
         Twim::new(
             p.TWISPI0,
             Irqs,
-            p.P0_17,
-            p.P0_20,
+            take_pin(KEYBALL.oled_sda),
+            take_pin(KEYBALL.oled_scl),
             rktk_drivers_nrf::display::ssd1306::recommended_i2c_config(),
         ),
         ssd1306::size::DisplaySize128x32,
@@ -87,52 +182,49 @@ async fn main(_spawner: Spawner) {
     let spi = Mutex::<NoopRawMutex, _>::new(Spim::new(
         p.SPI2,
         Irqs,
-        p.P1_13,
-        p.P1_11,
-        p.P0_10,
+        take_pin(KEYBALL.ball_sck),
+        take_pin(KEYBALL.ball_miso),
+        take_pin(KEYBALL.ball_mosi),
         rktk_drivers_nrf::mouse::pmw3360::recommended_spi_config(),
     ));
     let ball_spi_device = SpiDevice::new(
         &spi,
         Output::new(
-            p.P0_06,
+            take_pin(KEYBALL.ball_cs),
             embassy_nrf::gpio::Level::High,
             embassy_nrf::gpio::OutputDrive::Standard,
         ),
     );
     let ball = Pmw3360Builder::new(ball_spi_device);
 
-    let keyscan = DuplexMatrixScanner::<_, 5, 4, 7, 5>::new(
-        [
-            NrfFlexPin::new(p.P0_22), // ROW0
-            NrfFlexPin::new(p.P0_24), // ROW1
-            NrfFlexPin::new(p.P1_00), // ROW2
-            NrfFlexPin::new(p.P0_11), // ROW3
-            NrfFlexPin::new(p.P1_04), // ROW4
-        ],
-        [
-            NrfFlexPin::new(p.P0_31), // COL0
-            NrfFlexPin::new(p.P0_29), // COL1
-            NrfFlexPin::new(p.P0_02), // COL2
-            NrfFlexPin::new(p.P1_15), // COL3
-        ],
-        HandDetector::Constant(rktk::drivers::interface::keyscan::Hand::Left),
-        false,
-        translate_key_position,
-    );
-
-    let split = UartHalfDuplexSplitDriver::new(
-        p.P0_08.degrade(),
-        p.UARTE0,
-        Irqs,
-        p.TIMER1,
-        p.PPI_CH0,
-        p.PPI_CH1,
-        p.PPI_GROUP0.degrade(),
-    );
+    #[cfg(not(feature = "ble-split"))]
+    let split = {
+        use embassy_nrf::ppi::Group;
+        use rktk_drivers_nrf::split::uart_half_duplex::UartHalfDuplexSplitDriver;
+
+        Some(split::KeyballSplitDriver::new(
+            split::reliable::ReliableSplitDriver::new(UartHalfDuplexSplitDriver::new(
+                take_pin(KEYBALL.split),
+                p.UARTE0,
+                Irqs,
+                p.TIMER1,
+                p.PPI_CH0,
+                p.PPI_CH1,
+                p.PPI_GROUP0.degrade(),
+            )),
+        ))
+    };
+    #[cfg(all(feature = "ble-split", not(feature = "dongle")))]
+    let split = Some(split::KeyballSplitDriver::new(
+        ble_split::LINK.split_driver(),
+    ));
+    // The link carries the input of this half to the dongle instead.
+    #[cfg(feature = "dongle")]
+    let split = none_driver!(Split);
 
-    let rgb = Ws2812Pwm::new(p.PWM0, p.P0_09);
+    let rgb = Ws2812Pwm::new(p.PWM0, take_pin(KEYBALL.rgb));
 
+    #[cfg(feature = "softdevice")]
     let sd = rktk_drivers_nrf::softdevice::init_sd("keyball61");
 
     #[cfg(feature = "ble")]
@@ -141,20 +233,114 @@ async fn main(_spawner: Spawner) {
         rktk_drivers_nrf::softdevice::ble::DeviceInformation {
             manufacturer_name: Some("nazo6"),
             model_number: Some("100"),
-            serial_number: Some("100"),
+            serial_number: Some(hardware_id::serial_number(&mut FicrDeviceId)),
             ..Default::default()
         },
     )
     .await;
 
-    rktk_drivers_nrf::softdevice::start_softdevice(sd).await;
+    #[cfg(feature = "ble-split")]
+    let split_server = ble_split::init(sd);
+
+    #[cfg(feature = "ble")]
+    let battery_reporter = move |percent: u8| {
+        let _ = server.bas.battery_level_set(&percent);
+    };
+    #[cfg(not(feature = "ble"))]
+    let battery_reporter = |_percent: u8| {};
+
+    // Run here instead of through rktk so that the USB power events reach the VBUS detection.
+    #[cfg(feature = "softdevice")]
+    spawner.must_spawn(softdevice_task(sd));
+    #[cfg(feature = "ble")]
+    spawner.must_spawn(ble_leds::task(sd));
+    #[cfg(not(feature = "softdevice"))]
+    let _ = spawner;
+    #[cfg(feature = "ble-split")]
+    spawner.must_spawn(ble_split::task(sd, split_server));
+    #[cfg(feature = "dongle")]
+    {
+        dongle::enable_forwarding();
+        spawner.must_spawn(ble_split::forward_to_dongle());
+    }
 
     embassy_time::Timer::after_millis(50).await;
 
     // let rand = rktk_drivers_nrf52::softdevice::rand::SdRand::new(sd);
 
-    let (flash, cache) = get_flash(sd);
+    #[cfg(feature = "softdevice")]
+    let (flash, cache) = rktk_drivers_nrf::softdevice::flash::get_flash(sd);
+    #[cfg(feature = "softdevice")]
     let storage = rktk_drivers_nrf::softdevice::flash::create_storage_driver(flash, &cache);
+    // rktk-drivers-nrf picks the range itself, so the region reserved by memory.x only protects
+    // the bootloader if the driver really stays inside it.
+    #[cfg(feature = "softdevice")]
+    assert!(
+        storage_range().start <= storage.flash_range.start
+            && storage.flash_range.end <= storage_range().end,
+        "storage outside of the region reserved by memory.x"
+    );
+    #[cfg(not(feature = "softdevice"))]
+    let storage =
+        rktk_drivers_common::storage::flash_sequential_map::FlashSequentialMapStorage::new(
+            embassy_embedded_hal::adapter::BlockingAsync::new(embassy_nrf::nvmc::Nvmc::new(p.NVMC)),
+            storage_range(),
+        );
+
+    crash_log::load(&storage).await;
+    reset_reason::record(&storage).await;
+    settings::load(&storage).await;
+
+    #[cfg(feature = "hand-strap")]
+    let hand = {
+        let strap = embassy_nrf::gpio::Input::new(
+            take_pin(KEYBALL.hand_strap),
+            embassy_nrf::gpio::Pull::Up,
+        );
+        handedness::read_strap(|| strap.is_low())
+    };
+    #[cfg(not(feature = "hand-strap"))]
+    let hand = handedness::load(&storage).await.unwrap_or(Hand::Left);
+
+    #[allow(unused_mut)]
+    let mut keyscan = DebouncedKeyscan::new(
+        DuplexMatrixScanner::<_, 5, 4, 7, 5>::new(
+            KEYBALL.rows.map(|pin| NrfFlexPin::new(take_pin(pin))),
+            KEYBALL.cols.map(|pin| NrfFlexPin::new(take_pin(pin))),
+            HandDetector::Constant(hand),
+            false,
+            translate_key_position,
+        ),
+        &DEBOUNCE,
+    );
+    #[cfg(not(feature = "hand-strap"))]
+    handedness::handle_boot_combo(&mut keyscan, &storage).await;
+
+    // Before the bond slots, which may forget it.
+    #[cfg(feature = "ble-split")]
+    ble_split::BONDER.load(&storage).await;
+    #[cfg(all(feature = "ble-split", not(feature = "ble")))]
+    if bond::take_clear_all() {
+        ble_split::BONDER.forget();
+    }
+
+    // The address of the active bond slot has to be set before BLE starts advertising.
+    #[cfg(feature = "ble")]
+    {
+        use nrf_softdevice::ble::{get_address, set_address, Address, AddressType};
+
+        assert!(
+            storage_range().start <= bond_store::SLOT_BONDS.start
+                && bond_store::SLOT_BONDS.end <= storage_range().end,
+            "bond copies outside of the region reserved by memory.x"
+        );
+        let slots = bond::load(&storage, &mut bond_store::FlashBondStore(flash)).await;
+        let base = get_address(sd).bytes();
+        set_address(
+            sd,
+            &Address::new(AddressType::RandomStatic, slots.address(base)),
+        );
+    }
 
     let ble_builder = {
         #[cfg(feature = "ble")]
@@ -169,14 +355,18 @@ async fn main(_spawner: Spawner) {
     let drivers = Drivers {
         keyscan,
         system: NrfSystemDriver::new(None),
-        mouse_builder: Some(ball),
+        mouse_builder: Some(settings::SettingsMouseBuilder(ball)),
         usb_builder: {
             #[cfg(feature = "usb")]
             let usb = {
-                let vbus = SOFTWARE_VBUS.get_or_init(|| SoftwareVbusDetect::new(true, true));
-                let driver = embassy_nrf::usb::Driver::new(p.USBD, Irqs, vbus);
+                let vbus = vbus::init();
+                let driver =
+                    LedReportDriver::new(embassy_nrf::usb::Driver::new(p.USBD, Irqs, vbus));
                 let opts = usb::UsbOpts {
-                    config: USB_CONFIG,
+                    config: usb_config(
+                        &USB_IDENTITY,
+                        hardware_id::serial_number(&mut FicrDeviceId),
+                    ),
                     mouse_poll_interval: 2,
                     kb_poll_interval: 5,
                     driver,
@@ -189,23 +379,67 @@ async fn main(_spawner: Spawner) {
 
             usb
         },
-        display_builder: Some(display),
-        split: Some(split),
-        rgb: Some(rgb),
-        storage: Some(storage),
+        display_builder: none_driver!(DisplayBuilder),
+        split,
+        rgb: none_driver!(Rgb),
+        storage: Some(storage::Shared(&storage)),
         ble_builder,
-        debounce: Some(EagerDebounceDriver::new(
-            embassy_time::Duration::from_millis(20),
-        )),
+        debounce: none_driver!(Debounce),
         encoder: none_driver!(Encoder),
     };
 
-    rktk::task::start(drivers, keymap::KEYMAP, create_empty_hooks()).await;
+    // Backlight and display are driven by keyball-common instead of rktk so that they can show
+    // Keyball specific state.
+    embassy_futures::join::join5(
+        rktk::task::start(drivers, keymap::KEYMAP, hooks::create_hooks()),
+        backlight::run(rgb),
+        display::run(display),
+        battery::run(VddhBatterySensor::new(p.SAADC), battery_reporter),
+        embassy_futures::join::join5(
+            sleep::run(&spi),
+            bootloader::run(enter_bootloader),
+            watchdog::run(
+                || start_watchdog(p.WDT),
+                |watchdog| {
+                    if let Some(handle) = watchdog {
+                        handle.pet();
+                    }
+                },
+            ),
+            settings::run(&storage),
+            async {
+                #[cfg(feature = "ble-split")]
+                ble_split::BONDER.run(&storage).await;
+            },
+        ),
+    )
+    .await;
+}
+
+/// Profile of this build for the split handshake.
+fn split_profile() -> Profile {
+    let mut features = 0;
+    if cfg!(feature = "usb") {
+        features |= feature::USB;
+    }
+    if cfg!(feature = "ble") {
+        features |= feature::BLE;
+    }
+    if cfg!(feature = "ble-split") {
+        features |= feature::BLE_SPLIT;
+    } else {
+        features |= feature::RELIABLE_LINK;
+    }
+    if cfg!(feature = "hand-strap") {
+        features |= feature::HAND_STRAP;
+    }
+    Profile::new(Board::Nrf52840, features)
 }
 
 #[panic_handler]
 fn panic(info: &PanicInfo) -> ! {
     cortex_m::interrupt::disable();
+    crash_log::record_panic(info);
     panic_utils::save_panic_info(info);
     cortex_m::peripheral::SCB::sys_reset()
 }
@@ -0,0 +1,105 @@
+//! System OFF after a period of inactivity. A key press wakes the board up, which resets it.
+
+use embassy_nrf::{
+    gpio::{Level, Output, OutputDrive},
+    pac,
+    peripherals::SPI2,
+    spim::Spim,
+};
+use embassy_sync::{blocking_mutex::raw::NoopRawMutex, mutex::Mutex};
+use embassy_time::Timer;
+
+use keyball_common::{backlight, display, power};
+
+use crate::{take_pin, vbus, KEYBALL, PINS};
+
+/// Shutdown register of the PMW3360 and the value which shuts it down.
+const PMW3360_SHUTDOWN: u8 = 0x3b;
+const PMW3360_SHUTDOWN_VALUE: u8 = 0xb6;
+
+/// Puts the board into System OFF once it has been idle for [`power::sleep_timeout`]. Never sleeps
+/// while USB power is present.
+///
+/// `spi` is the bus of the ball sensor. It is used once more to shut the sensor down.
+pub async fn run(spi: &Mutex<NoopRawMutex, Spim<'_, SPI2>>) -> ! {
+    power::wait_for_idle(vbus::present).await;
+
+    backlight::turn_off().await;
+    display::turn_off().await;
+    shutdown_ball(spi).await;
+    configure_wake_pins();
+
+    system_off();
+    // System OFF does not return. This is only reached in debug interface mode, where it is
+    // emulated.
+    loop {
+        cortex_m::asm::wfe();
+    }
+}
+
+#[cfg(feature = "softdevice")]
+fn system_off() {
+    unsafe {
+        nrf_softdevice::raw::sd_power_system_off();
+    }
+}
+
+#[cfg(not(feature = "softdevice"))]
+fn system_off() {
+    let power = unsafe { &*pac::POWER::ptr() };
+    power.systemoff.write(|w| w.systemoff().enter());
+}
+
+async fn shutdown_ball(spi: &Mutex<NoopRawMutex, Spim<'_, SPI2>>) {
+    let mut spi = spi.lock().await;
+    // The sensor driver owns the CS pin, but nothing else runs between this and System OFF.
+    let mut cs = Output::new(
+        take_pin(KEYBALL.ball_cs),
+        Level::High,
+        OutputDrive::Standard,
+    );
+    // EasyDMA can only read from RAM, so the command must not be promoted to a constant.
+    let command = [PMW3360_SHUTDOWN | 0x80, PMW3360_SHUTDOWN_VALUE];
+    cs.set_low();
+    let _ = spi.write(&command).await;
+    Timer::after_micros(20).await;
+    cs.set_high();
+    // Leave CS high in System OFF.
+    core::mem::forget(cs);
+}
+
+/// Configures the matrix so that a key press triggers the DETECT signal, which wakes the chip up
+/// from System OFF.
+///
+/// The duplex matrix has diodes of both directions, and one pin configuration can only see keys of
+/// one of them. Rows are driven low and columns sense low with pull-ups, so the keys whose diode
+/// points from the column to the row wake the board.
+fn configure_wake_pins() {
+    for row in KEYBALL.rows {
+        let (port, pin) = port_and_pin(PINS.resolve(row));
+        port.outclr.write(|w| unsafe { w.bits(1 << pin) });
+        port.pin_cnf[pin].write(|w| w.dir().output().input().disconnect());
+    }
+    for col in KEYBALL.cols {
+        let (port, pin) = port_and_pin(PINS.resolve(col));
+        port.pin_cnf[pin].write(|w| {
+            w.dir()
+                .input()
+                .input()
+                .connect()
+                .pull()
+                .pullup()
+                .sense()
+                .low()
+        });
+    }
+}
+
+fn port_and_pin(pin: u8) -> (&'static pac::p0::RegisterBlock, usize) {
+    let port = if pin < 32 {
+        unsafe { &*pac::P0::ptr() }
+    } else {
+        unsafe { &*pac::P1::ptr() }
+    };
+    (port, (pin % 32) as usize)
+}
@@ -0,0 +1,99 @@
+//! VBUS detection from the USB power events of the POWER peripheral.
+//!
+//! With the SoftDevice, POWER belongs to it, so the events reach the application as SoC events and
+//! the register is read through the SoftDevice API. Without it, embassy-nrf handles the events.
+
+#[cfg(not(feature = "softdevice"))]
+pub use hardware::*;
+#[cfg(feature = "softdevice")]
+pub use softdevice::*;
+
+/// VBUSDETECT bit of USBREGSTATUS.
+const USBREGSTATUS_VBUSDETECT: u32 = 1 << 0;
+
+#[cfg(feature = "softdevice")]
+mod softdevice {
+    use embassy_nrf::usb::vbus_detect::SoftwareVbusDetect;
+    use nrf_softdevice::{raw, SocEvent};
+    use once_cell::sync::OnceCell;
+
+    use keyball_common::output;
+
+    use super::USBREGSTATUS_VBUSDETECT;
+
+    /// OUTPUTRDY bit of USBREGSTATUS.
+    const USBREGSTATUS_OUTPUTRDY: u32 = 1 << 1;
+
+    static VBUS_DETECT: OnceCell<SoftwareVbusDetect> = OnceCell::new();
+
+    /// Enables the USB power events and returns the VBUS detector for the USB driver, initialized
+    /// with the current state. The SoftDevice must be enabled.
+    pub fn init() -> &'static SoftwareVbusDetect {
+        unsafe {
+            raw::sd_power_usbdetected_enable(1);
+            raw::sd_power_usbpwrrdy_enable(1);
+            raw::sd_power_usbremoved_enable(1);
+        }
+        VBUS_DETECT.get_or_init(|| {
+            let status = usbregstatus();
+            let detected = status & USBREGSTATUS_VBUSDETECT != 0;
+            output::set_vbus(detected);
+            SoftwareVbusDetect::new(detected, status & USBREGSTATUS_OUTPUTRDY != 0)
+        })
+    }
+
+    /// Passed to the SoftDevice as callback for SoC events.
+    pub fn handle_soc_event(event: SocEvent) {
+        let Some(vbus) = VBUS_DETECT.get() else {
+            return;
+        };
+        match event {
+            SocEvent::PowerUsbDetected => {
+                vbus.detected(true);
+                output::set_vbus(true);
+            }
+            SocEvent::PowerUsbPowerReady => vbus.ready(),
+            SocEvent::PowerUsbRemoved => {
+                vbus.detected(false);
+                output::set_vbus(false);
+            }
+            _ => {}
+        }
+    }
+
+    /// Whether VBUS is present right now.
+    pub fn present() -> bool {
+        usbregstatus() & USBREGSTATUS_VBUSDETECT != 0
+    }
+
+    fn usbregstatus() -> u32 {
+        let mut status = 0;
+        unsafe {
+            raw::sd_power_usbregstatus_get(&mut status);
+        }
+        status
+    }
+}
+
+#[cfg(not(feature = "softdevice"))]
+mod hardware {
+    use embassy_nrf::{bind_interrupts, pac, usb::vbus_detect::HardwareVbusDetect};
+
+    use super::USBREGSTATUS_VBUSDETECT;
+
+    bind_interrupts!(struct Irqs {
+        POWER_CLOCK => embassy_nrf::usb::vbus_detect::InterruptHandler;
+    });
+
+    /// Returns the VBUS detector for the USB driver. Reports always go to USB in this build, so
+    /// the output selection is not told about VBUS.
+    pub fn init() -> HardwareVbusDetect {
+        HardwareVbusDetect::new(Irqs)
+    }
+
+    /// Whether VBUS is present right now.
+    pub fn present() -> bool {
+        let power = unsafe { &*pac::POWER::ptr() };
+        power.usbregstatus.read().bits() & USBREGSTATUS_VBUSDETECT != 0
+    }
+}
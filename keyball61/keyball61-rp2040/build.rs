@@ -13,6 +13,9 @@ use std::fs::File;
 use std::io::Write;
 use std::path::PathBuf;
 
+#[path = "../usb_identity.rs"]
+mod usb_identity;
+
 fn main() {
     // Put `memory.x` in our output directory and ensure it's
     // on the linker search path.
@@ -29,6 +32,8 @@ fn main() {
     // `memory.x` is changed.
     println!("cargo:rerun-if-changed=memory.x");
 
+    usb_identity::write(out);
+
     println!("cargo:rustc-link-arg-bins=--nmagic");
     println!("cargo:rustc-link-arg-bins=-Tlink.x");
     println!("cargo:rustc-link-arg-bins=-Tlink-rp.x");
@@ -7,14 +7,16 @@ use embassy_embedded_hal::shared_bus::asynch::spi::SpiDevice;
 use embassy_executor::Spawner;
 use embassy_rp::{
     bind_interrupts,
-    gpio::Output,
+    flash::{Blocking, Flash},
+    gpio::{AnyPin, Input, Output, Pull},
     i2c::I2c,
-    peripherals::{I2C1, PIO0, PIO1, USB},
+    peripherals::{FLASH, I2C1, PIO0, PIO1, USB},
     pio::Pio,
+    watchdog::Watchdog,
 };
 
 use embassy_sync::{blocking_mutex::raw::NoopRawMutex, mutex::Mutex};
-use rktk::{drivers::Drivers, hooks::create_empty_hooks, none_driver};
+use rktk::{drivers::Drivers, none_driver};
 use rktk_drivers_common::{
     display::ssd1306::Ssd1306DisplayBuilder,
     keyscan::{duplex_matrix::DuplexMatrixScanner, HandDetector},
@@ -27,7 +29,19 @@ use rktk_drivers_rp::{
     split::pio_half_duplex::PioHalfDuplexSplitDriver,
 };
 
-use keyball_common::*;
+use keyball_common::{
+    debounce::DebouncedKeyscan,
+    hardware_id::{self, HardwareId},
+    pin_map::{ControllerPins, ProMicroPin, KEYBALL61, PRO_MICRO_RP2040},
+    reset_reason::ResetReason,
+    split::{
+        handshake::{self, feature, Board, Profile},
+        reliable::ReliableSplitDriver,
+        KeyballSplitDriver,
+    },
+    usb::LedReportDriver,
+    *,
+};
 
 bind_interrupts!(pub struct Irqs {
     USBCTRL_IRQ => embassy_rp::usb::InterruptHandler<USB>;
@@ -36,11 +50,70 @@ bind_interrupts!(pub struct Irqs {
     PIO1_IRQ_0 => embassy_rp::pio::InterruptHandler<PIO1>;
 });
 
+include!(concat!(env!("OUT_DIR"), "/usb_identity.rs"));
+
+const FLASH_SIZE: usize = 4 * 1024 * 1024;
+
+const PINS: ControllerPins = PRO_MICRO_RP2040;
+
+/// Two resets within this time enter BOOTSEL.
+const DOUBLE_RESET_WINDOW: embassy_time::Duration = double_reset::DEFAULT_WINDOW;
+
+// I2C, SPI and PIO need pins of concrete types, so all pins are taken as the peripheral singletons
+// written out below, and only checked against the pin map here.
+const _: () = {
+    assert!(wired_to(KEYBALL61.rows, [4, 5, 6, 7, 8]));
+    assert!(wired_to(KEYBALL61.cols, [29, 28, 27, 26]));
+    assert!(PINS.resolve(KEYBALL61.oled_sda) == 2);
+    assert!(PINS.resolve(KEYBALL61.oled_scl) == 3);
+    assert!(PINS.resolve(KEYBALL61.ball_sck) == 22);
+    assert!(PINS.resolve(KEYBALL61.ball_mosi) == 23);
+    assert!(PINS.resolve(KEYBALL61.ball_miso) == 20);
+    assert!(PINS.resolve(KEYBALL61.ball_cs) == 21);
+    assert!(PINS.resolve(KEYBALL61.split) == 1);
+    assert!(PINS.resolve(KEYBALL61.rgb) == 0);
+    assert!(PINS.resolve(KEYBALL61.hand_strap) == 9);
+};
+
+/// Whether `pins` are wired to the GPIOs `gpios`.
+const fn wired_to<const N: usize>(pins: [ProMicroPin; N], gpios: [u8; N]) -> bool {
+    let mut i = 0;
+    while i < N {
+        if PINS.resolve(pins[i]) != gpios[i] {
+            return false;
+        }
+        i += 1;
+    }
+    true
+}
+
+/// Unique ID of the external flash chip, as the RP2040 itself has none.
+struct FlashUniqueId<'a>(&'a mut FLASH);
+
+impl HardwareId for FlashUniqueId<'_> {
+    fn hardware_id(&mut self) -> [u8; 8] {
+        let mut flash = Flash::<_, Blocking, FLASH_SIZE>::new_blocking(&mut *self.0);
+        let mut id = [0; 8];
+        let _ = flash.blocking_unique_id(&mut id);
+        id
+    }
+}
+
 #[embassy_executor::main]
 async fn main(_spawner: Spawner) {
     let mut cfg = embassy_rp::config::Config::default();
     cfg.clocks.sys_clk.div_int = 2;
-    let p = embassy_rp::init(cfg);
+    let mut p = embassy_rp::init(cfg);
+    let reason = reset_reason::set_from_hardware(read_reset_reason());
+    let mut features = feature::USB | feature::RELIABLE_LINK;
+    if cfg!(feature = "hand-strap") {
+        features |= feature::HAND_STRAP;
+    }
+    handshake::set_local(Profile::new(Board::Rp2040, features));
+
+    if double_reset::detect(reason) {
+        embassy_rp::rom_data::reset_to_usb_boot(0, 0);
+    }
 
     let display = Ssd1306DisplayBuilder::new(
         I2c::new_async(
@@ -69,29 +142,45 @@ async fn main(_spawner: Spawner) {
     let ball_spi = SpiDevice::new(&spi, Output::new(p.PIN_21, embassy_rp::gpio::Level::High));
     let ball = Paw3395Builder::new(ball_spi, PAW3395_CONFIG);
 
-    let keyscan = DuplexMatrixScanner::<_, 5, 4, 5, 7>::new(
-        [
-            RpFlexPin::new(p.PIN_4),
-            RpFlexPin::new(p.PIN_5),
-            RpFlexPin::new(p.PIN_6),
-            RpFlexPin::new(p.PIN_7),
-            RpFlexPin::new(p.PIN_8),
-        ],
-        [
-            RpFlexPin::new(p.PIN_29),
-            RpFlexPin::new(p.PIN_28),
-            RpFlexPin::new(p.PIN_27),
-            RpFlexPin::new(p.PIN_26),
-        ],
-        HandDetector::ByKey(2, 6),
-        true,
-        translate_key_position,
+    #[cfg(feature = "hand-strap")]
+    let hand = {
+        let strap = Input::new(p.PIN_9, Pull::Up);
+        HandDetector::Constant(handedness::read_strap(|| strap.is_low()))
+    };
+    #[cfg(not(feature = "hand-strap"))]
+    let hand = HandDetector::ByKey(2, 6);
+
+    let keyscan = DebouncedKeyscan::new(
+        DuplexMatrixScanner::<_, 5, 4, 5, 7>::new(
+            [
+                AnyPin::from(p.PIN_4),
+                AnyPin::from(p.PIN_5),
+                AnyPin::from(p.PIN_6),
+                AnyPin::from(p.PIN_7),
+                AnyPin::from(p.PIN_8),
+            ]
+            .map(RpFlexPin::new),
+            [
+                AnyPin::from(p.PIN_29),
+                AnyPin::from(p.PIN_28),
+                AnyPin::from(p.PIN_27),
+                AnyPin::from(p.PIN_26),
+            ]
+            .map(RpFlexPin::new),
+            hand,
+            true,
+            translate_key_position,
+        ),
+        &DEBOUNCE,
     );
 
     let usb = {
-        let driver = embassy_rp::usb::Driver::new(p.USB, Irqs);
+        let driver = LedReportDriver::new(embassy_rp::usb::Driver::new(p.USB, Irqs));
         let usb_opts = UsbOpts {
-            config: USB_CONFIG,
+            config: usb_config(
+                &USB_IDENTITY,
+                hardware_id::serial_number(&mut FlashUniqueId(&mut p.FLASH)),
+            ),
             mouse_poll_interval: 5,
             kb_poll_interval: 5,
             driver,
@@ -109,28 +198,80 @@ async fn main(_spawner: Spawner) {
     // NOTE: needed for some macro thing. maybe this can be avoided.
     #[allow(clippy::needless_late_init)]
     let storage;
-    rktk_drivers_rp::init_storage!(storage, p.FLASH, p.DMA_CH3, { 4 * 1024 * 1024 });
+    rktk_drivers_rp::init_storage!(storage, p.FLASH, p.DMA_CH3, { FLASH_SIZE });
+    crash_log::load(&storage).await;
+    reset_reason::record(&storage).await;
+    settings::load(&storage).await;
 
     let drivers = Drivers {
         keyscan,
         system: rktk_drivers_rp::system::RpSystemDriver,
-        mouse_builder: Some(ball),
+        mouse_builder: Some(settings::SettingsMouseBuilder(ball)),
         usb_builder: Some(usb),
-        display_builder: Some(display),
-        split: Some(split),
-        rgb: Some(rgb),
+        display_builder: none_driver!(DisplayBuilder),
+        split: Some(KeyballSplitDriver::new(ReliableSplitDriver::new(split))),
+        rgb: none_driver!(Rgb),
         ble_builder: none_driver!(BleBuilder),
-        storage: Some(storage),
+        storage: Some(storage::Shared(&storage)),
         debounce: none_driver!(Debounce),
         encoder: none_driver!(Encoder),
     };
 
-    rktk::task::start(drivers, keymap::KEYMAP, create_empty_hooks()).await;
+    // Backlight and display are driven by keyball-common instead of rktk so that they can show
+    // Keyball specific state.
+    embassy_futures::join::join5(
+        rktk::task::start(drivers, keymap::KEYMAP, hooks::create_hooks()),
+        backlight::run(rgb),
+        display::run(display),
+        double_reset::disarm_after(DOUBLE_RESET_WINDOW),
+        embassy_futures::join::join3(
+            bootloader::run(enter_bootloader),
+            watchdog::run(
+                || {
+                    let mut wdt = Watchdog::new(p.WATCHDOG);
+                    wdt.pause_on_debug(true);
+                    wdt.start(watchdog::TIMEOUT);
+                    wdt
+                },
+                Watchdog::feed,
+            ),
+            settings::run(&storage),
+        ),
+    )
+    .await;
+}
+
+/// Brown-out resets the chip like power-on. Resets of the processors alone, such as
+/// `SCB::sys_reset`, leave the flags of the last chip reset in place.
+fn read_reset_reason() -> ResetReason {
+    let watchdog = embassy_rp::pac::WATCHDOG.reason().read();
+    let chip = embassy_rp::pac::VREG_AND_CHIP_RESET.chip_reset().read();
+    if watchdog.timer() {
+        ResetReason::Watchdog
+    } else if watchdog.force() {
+        ResetReason::Software
+    } else if chip.had_run() {
+        ResetReason::Pin
+    } else if chip.had_psm_restart() {
+        ResetReason::Software
+    } else if chip.had_por() {
+        ResetReason::PowerOn
+    } else {
+        ResetReason::Unknown
+    }
+}
+
+fn enter_bootloader() -> ! {
+    embassy_rp::rom_data::reset_to_usb_boot(0, 0);
+    loop {
+        cortex_m::asm::wfe();
+    }
 }
 
 #[panic_handler]
 fn panic(info: &PanicInfo) -> ! {
     cortex_m::interrupt::disable();
+    crash_log::record_panic(info);
     panic_utils::save_panic_info(info);
     cortex_m::peripheral::SCB::sys_reset()
 }
@@ -0,0 +1,54 @@
+//! Generates `usb_identity.rs` for the build scripts of the Keyball61 variants, which include this
+//! file with `#[path]`. They need `serde_json` as a build dependency.
+
+use std::env;
+use std::fs::File;
+use std::io::Write;
+use std::path::Path;
+
+/// Generates `USB_IDENTITY` from the `keyball.usb.<package name>` section of `rktk.json`. Falls back
+/// to `UsbIdentity::DEFAULT` if there is no such section.
+pub fn write(out: &Path) {
+    println!("cargo:rerun-if-env-changed=RKTK_CONFIG_PATH");
+
+    let package = env::var("CARGO_PKG_NAME").unwrap();
+    let config: Option<serde_json::Value> = env::var_os("RKTK_CONFIG_PATH").map(|path| {
+        println!("cargo:rerun-if-changed={}", path.to_string_lossy());
+        serde_json::from_str(&std::fs::read_to_string(path).unwrap()).unwrap()
+    });
+    let identity = config
+        .as_ref()
+        .and_then(|c| c.get("keyball")?.get("usb")?.get(&package));
+
+    let code = match identity {
+        Some(identity) => {
+            let field = |name: &str| {
+                identity[name]
+                    .as_str()
+                    .unwrap_or_else(|| panic!("keyball.usb.{package}.{name} must be a string"))
+            };
+            let id = |name: &str| {
+                u16::from_str_radix(field(name).trim_start_matches("0x"), 16)
+                    .unwrap_or_else(|_| panic!("keyball.usb.{package}.{name} must be a hex u16"))
+            };
+            format!(
+                "const USB_IDENTITY: keyball_common::UsbIdentity = keyball_common::UsbIdentity {{
+                    vid: {:#06x},
+                    pid: {:#06x},
+                    manufacturer: {:?},
+                    product: {:?},
+                }};",
+                id("vid"),
+                id("pid"),
+                field("manufacturer"),
+                field("product"),
+            )
+        }
+        None => "const USB_IDENTITY: keyball_common::UsbIdentity = keyball_common::UsbIdentity::DEFAULT;".to_string(),
+    };
+
+    File::create(out.join("usb_identity.rs"))
+        .unwrap()
+        .write_all(code.as_bytes())
+        .unwrap();
+}